@@ -1,13 +1,20 @@
 use crate::vm::chunk::Chunk;
 use crate::vm::value::Value;
+use crate::vm::function::{Function, Parameter};
+use crate::vm::enums::{Enum, Variant};
+use crate::vm::native;
 use crate::vm::op_codes::*;
 use crate::scanner::{Scanner, tokens::{*}};
 use crate::vm::value::number;
 use std::collections::HashMap;
+use std::cell::OnceCell;
 use std::ops::Index;
+use std::rc::Rc;
 
 #[macro_use]
 mod rules;
+mod peephole;
+mod registers;
 
 use self::rules::*;
 
@@ -33,10 +40,72 @@ struct Global {
 	constant: bool,
 	/// If the global has been initialized
 	initialized: bool,
+	/// If the global has been declared,
+	/// functions can use it before that
+	declared: bool,
+	/// The value of a constant with a constant initializer,
+	/// reading it is replaced by the value
+	value: Option<Value>,
+}
+
+/// Settings for the compiler, these are set with command line flags
+#[derive(Clone, Copy)]
+pub struct Options {
+	/// Reuse the call frame for calls in tail position
+	pub tail_calls: bool,
+	/// Evaluate operators on constants while compiling
+	pub fold: bool,
+	/// Clean up the code of every function when it is done
+	pub peephole: bool,
+	/// Use register ops that work on locals and temporaries directly where possible
+	pub registers: bool,
+}
+
+impl Default for Options {
+	fn default() -> Options {
+		Options {
+			tail_calls: true,
+			fold: true,
+			peephole: true,
+			registers: false,
+		}
+	}
+}
+
+/// A variable captured by a closure
+#[derive(PartialEq)]
+struct Upvalue {
+	/// If this captures a local of the enclosing function,
+	/// otherwise it captures an upvalue of the enclosing function
+	is_local: bool,
+	index: u16,
+}
+
+/// The variables of a destructuring pattern like `[a, b, ...rest]` or `{name, age}`
+struct Pattern {
+	targets: Vec<Token>,
+	/// If the last target collects the remaining items of a list
+	rest: bool,
+	map: bool,
+}
+
+/// The state belonging to the function that is being compiled.
+/// This is stored away while compiling a function nested inside of it.
+struct FunctionState {
+	chunk: Chunk,
+	locals: Vec<Local>,
+	upvalues: Vec<Upvalue>,
+	scope: u32,
+	labels: HashMap<String, usize>,
+	gotos: Vec<(String, Token, usize)>,
+	continues: Vec<usize>,
+	generator: bool,
+	tail_calls: Vec<usize>,
 }
 
 /// Holds the state of the compiler
 struct Compiler {
+	options: Options,
 	previous: Token,
 	current: Token,
 	scanner: Scanner,
@@ -49,6 +118,12 @@ struct Compiler {
 	locals: Vec<Local>,
 	globals: Vec<Global>,
 
+	/// Variables captured from enclosing functions
+	upvalues: Vec<Upvalue>,
+
+	/// The functions the current function is nested in
+	enclosing: Vec<FunctionState>,
+
 	/// Scope depth
 	scope: u32,
 
@@ -61,10 +136,24 @@ struct Compiler {
 
 	/// These are return pointers for the continue keyword
 	continues: Vec<usize>,
+
+	/// If the current function yields
+	generator: bool,
+
+	/// Calls in tail position, these become tail calls
+	/// when the function turns out not to be a generator
+	tail_calls: Vec<usize>,
+
+	/// Location of the last call that was compiled
+	last_call: Option<usize>,
+
+	/// Constants pushed by the last ops with the location of their op,
+	/// an operator on these can be evaluated while compiling
+	constant_ops: Vec<(usize, Value)>,
 }
 
 impl Compiler {
-	pub fn new(scanner: Scanner) -> Compiler {
+	pub fn new(scanner: Scanner, options: Options) -> Compiler {
 		let placeholder_token = Token {
 			ttype: TokenType::EOF,
 			start: 0,
@@ -73,6 +162,7 @@ impl Compiler {
 		};
 	
 		Compiler {
+			options,
 			scanner,
 			current: placeholder_token,
 			previous: placeholder_token,
@@ -82,24 +172,38 @@ impl Compiler {
 			can_assign: false,
 			locals: vec![],
 			globals: vec![],
+			upvalues: vec![],
+			enclosing: vec![],
 			scope: 0,
 			labels: HashMap::new(),
 			continues: vec![],
 			gotos: vec![],
+			generator: false,
+			tail_calls: vec![],
+			last_call: None,
+			constant_ops: vec![],
 		}
 	}
 }
 
-pub fn compile(source: String) -> Result<Chunk, ()> {
-	let mut compiler = Compiler::new(Scanner::new(source));
+pub fn compile(source: String, options: Options) -> Result<Chunk, ()> {
+	let mut compiler = Compiler::new(Scanner::new(source), options);
 	#[allow(unused_must_use)] {
 		compiler.start();
 	}
+	compiler.check_globals();
 
 	// Run another pass which resolves gotos
 	// regardless if the previous compilation failed
 	return match compiler.resolve_gotos() {
-		Ok(()) => Ok(compiler.chunk),
+		Ok(()) => {
+			let mut chunk = std::mem::replace(&mut compiler.chunk, Chunk::new());
+			compiler.finish_chunk(&mut chunk, 0, true);
+			chunk.globals = compiler.globals.iter()
+				.map(|global| compiler.lexeme(global.identifier).to_owned())
+				.collect();
+			Ok(chunk)
+		},
 		Err(()) => Err(())
 	}
 }
@@ -127,6 +231,19 @@ impl Compiler {
 	}
 
 	/// Fill all the goto instructions
+	/// Report the globals that functions use but the script never declares
+	fn check_globals(&mut self) {
+		let undeclared: Vec<Token> = self.globals.iter()
+			.filter(|global| !global.declared)
+			.map(|global| global.identifier)
+			.collect();
+		for identifier in undeclared {
+			// Each of them is a separate mistake
+			self.panic = false;
+			self.error_at(identifier, "cannot find variable");
+		}
+	}
+
 	fn resolve_gotos(&mut self) -> Result<(), ()> {
 		let mut errors = vec![];
 		let mut patches = vec![];
@@ -159,10 +276,97 @@ impl Compiler {
 			TokenType::VAR => self.var_decleration(false),
 			TokenType::CONST => self.var_decleration(true),
 			TokenType::LABEL => self.label(),
+			TokenType::ENUM => self.enum_decleration(),
+			TokenType::FUN => {
+				// Without a name this is an anonymous function in an expression statement
+				if self.peek_next() == TokenType::IDENTIFIER {
+					self.fun_decleration();
+				} else {
+					self.statement();
+				}
+			},
 			_ => self.statement()
 		}
 	}
 
+	fn fun_decleration(&mut self) {
+		//Advance over the fun token
+		self.advance();
+
+		let global_index = self.parse_variable("expected function name", false);
+		let name = self.lexeme(self.previous).to_owned();
+
+		// A function can refer to itself
+		match global_index {
+			Some(index) => self.globals[index].initialized = true,
+			None => self.locals.last_mut().unwrap().initialized = true,
+		}
+
+		self.consume(TokenType::LEFT_PAREN, "expected '(' after function name");
+		self.function(Some(name));
+
+		if let Some(index) = global_index {
+			self.define_global(index);
+		}
+	}
+
+	/// An enum like `enum Shape { Point, Circle(radius) }`,
+	/// the whole enum is a constant
+	fn enum_decleration(&mut self) {
+		//Advance over the enum token
+		self.advance();
+
+		let global_index = self.parse_variable("expected enum name", true);
+		let name = self.lexeme(self.previous).to_owned();
+		self.consume(TokenType::LEFT_BRACE, "expected '{' after enum name");
+
+		let mut variants: Vec<Variant> = vec![];
+		while self.current.ttype != TokenType::RIGHT_BRACE && self.current.ttype != TokenType::EOF {
+			self.consume(TokenType::IDENTIFIER, "expected variant name");
+			let variant = self.lexeme(self.previous).to_owned();
+			if variants.iter().any(|other| other.name == variant) {
+				self.error_at(self.previous, "duplicate variant name");
+			}
+
+			let mut fields = vec![];
+			if self.current.ttype == TokenType::LEFT_PAREN {
+				self.advance();
+				loop {
+					self.consume(TokenType::IDENTIFIER, "expected field name");
+					fields.push(self.lexeme(self.previous).to_owned());
+					if self.current.ttype != TokenType::COMMA {
+						break;
+					}
+					self.advance();
+				}
+				self.consume(TokenType::RIGHT_PAREN, "expected ')' after fields");
+			}
+			variants.push(Variant {
+				name: variant,
+				fields,
+			});
+
+			if self.current.ttype != TokenType::COMMA {
+				break;
+			}
+			self.advance();
+		}
+		self.consume(TokenType::RIGHT_BRACE, "expected '}' after variants");
+
+		let index = self.make_constant(Value::ENUM(Rc::new(Enum {
+			name,
+			variants,
+		})));
+		self.push_indexed(CONSTANT, index);
+
+		if let Some(index) = global_index {
+			self.define_global(index);
+			self.globals[index].initialized = true;
+		} else {
+			self.locals.last_mut().unwrap().initialized = true;
+		}
+	}
+
 	fn label(&mut self) {
 		self.advance();
 		self.consume(TokenType::IDENTIFIER, "expected identifier after 'label'");
@@ -179,9 +383,14 @@ impl Compiler {
 	fn var_decleration(&mut self, constant: bool) {
 		//Advance over the var token
 		self.advance();
+
+		if matches!(self.current.ttype, TokenType::LEFT_BRACKET | TokenType::LEFT_BRACE) {
+			return self.destructuring_decleration(constant);
+		}
 		
 		let global_index = self.parse_variable("expected variable name", constant);
 		
+		let start = self.chunk.code.len();
 		if self.current.ttype == TokenType::EQUAL {
 			self.advance();
 			self.expression();
@@ -191,11 +400,14 @@ impl Compiler {
 
 		self.consume(TokenType::SEMICOLON, "expected ';' after variable decleration");
 
-		if global_index.is_some() {
-			self.define_global(global_index.unwrap());
+		if let Some(index) = global_index {
+			if constant && self.options.fold {
+				self.globals[index].value = self.constant_expression(start);
+			}
+			self.define_global(index);
 			// After we have parsed the expression of this global
 			// we can say it's initialized
-			self.globals.last_mut().unwrap().initialized = true;
+			self.globals[index].initialized = true;
 		} else {
 			// After we have parsed the expression of a local
 			// we can say it's initialized
@@ -203,34 +415,117 @@ impl Compiler {
 		};
 	}
 
+	/// A decleration like `var [a, b] = list;` or `var {name, age} = map;`
+	fn destructuring_decleration(&mut self, constant: bool) {
+		let map = self.current.ttype == TokenType::LEFT_BRACE;
+		self.advance();
+		let pattern = self.pattern(map);
+
+		// Declared before the initializer, so it can't refer to them
+		let mut global_indexes = vec![];
+		for target in &pattern.targets {
+			if self.scope > 0 {
+				self.declare_variable(*target, constant);
+			} else {
+				global_indexes.push(self.set_global(*target, constant));
+			}
+		}
+
+		self.consume(TokenType::EQUAL, "expected '=' after destructuring pattern");
+		self.expression();
+		self.consume(TokenType::SEMICOLON, "expected ';' after variable decleration");
+		self.unpack(&pattern);
+
+		if self.scope > 0 {
+			let start = self.locals.len() - pattern.targets.len();
+			for local in &mut self.locals[start..] {
+				local.initialized = true;
+			}
+		} else {
+			// The last value is on top of the stack
+			for index in global_indexes.into_iter().rev() {
+				self.define_global(index);
+				self.globals[index].initialized = true;
+			}
+		}
+	}
+
+	/// Parse a destructuring pattern after the opening bracket or brace
+	fn pattern(&mut self, map: bool) -> Pattern {
+		let mut pattern = Pattern {
+			targets: vec![],
+			rest: false,
+			map,
+		};
+		loop {
+			if !map && self.current.ttype == TokenType::ELLIPSIS {
+				self.advance();
+				pattern.rest = true;
+			}
+			if pattern.targets.len() == u8::MAX as usize {
+				self.error_at(self.current, "can't destructure more than 255 variables");
+			}
+			self.consume(TokenType::IDENTIFIER, "expected variable name in destructuring pattern");
+			pattern.targets.push(self.previous);
+			if pattern.rest || self.current.ttype != TokenType::COMMA {
+				break;
+			}
+			self.advance();
+		}
+		if map {
+			self.consume(TokenType::RIGHT_BRACE, "expected '}' after destructuring pattern");
+		} else {
+			self.consume(TokenType::RIGHT_BRACKET, "expected ']' after destructuring pattern");
+		}
+		pattern.targets.truncate(u8::MAX as usize);
+		pattern
+	}
+
+	/// Replace the list or map on the stack with the values for a pattern
+	fn unpack(&mut self, pattern: &Pattern) {
+		let count = pattern.targets.len() as u8;
+		if pattern.map {
+			let keys: Vec<usize> = pattern.targets.iter().map(|target| {
+				let key = Value::from(self.lexeme(*target).to_owned());
+				self.make_constant(key)
+			}).collect();
+			self.push_names(&[UNPACKMAP, count], &keys);
+		} else {
+			self.push_bytes(&[UNPACKLIST, count - pattern.rest as u8, pattern.rest as u8]);
+		}
+	}
+
 	/// In case of a global, consumes the identifier and saves it as a string in the constants
 	/// then returns the index.
 	/// This will handle globals, locals functions, classes? and parameters
 	fn parse_variable(&mut self, errormsg: &str, constant: bool) -> Option<usize> {
 		self.consume(TokenType::IDENTIFIER, errormsg);
 		if self.scope > 0 {
-			self.declare_variable(constant);
+			self.declare_variable(self.previous, constant);
 			return None;
 		}
 		Some(self.set_global(self.previous, constant))
 	}
 
 	/// Save a local
-	fn declare_variable(&mut self, constant: bool) {
+	fn declare_variable(&mut self, identifier: Token, constant: bool) {
 		// Detect a double variable decleration
 		let mut error: Option<Token> = None;
 		for local in &self.locals {
-			if local.identifier == self.previous {
-				error = Some(self.previous);
+			if local.identifier == identifier {
+				error = Some(identifier);
 				break;
 			}
 		}
 		if let Some(error) = error {
 			self.error_at(error, "A variable with that identifier has already been declared in this scope");
 		}
+		if self.locals.len() > u16::MAX as usize {
+			self.error_at(identifier, "too many local variables in function");
+		}
 
 		let local = Local {
-			identifier: self.previous,
+			identifier,
 			depth: self.scope,
 			initialized: false,
 			constant,
@@ -242,14 +537,37 @@ impl Compiler {
 	/// Can also return an old global with that name
 	fn set_global(&mut self, identifier: Token, constant: bool) -> usize {
 		let lexeme = self.lexeme(identifier).to_string();
-		self.globals.iter().position(|global| self.lexeme(global.identifier) == lexeme).unwrap_or_else(|| {
-			self.globals.push(Global {
-				identifier,
-				constant,
-				initialized: false,
-			});
-			self.globals.len()-1
-		})
+		match self.globals.iter().position(|global| self.lexeme(global.identifier) == lexeme) {
+			// A function used it before, now it gets declared
+			Some(index) if !self.globals[index].declared => {
+				self.globals[index].declared = true;
+				self.globals[index].constant = constant;
+				index
+			},
+			Some(index) => {
+				// Reads of a constant may already have been replaced by its value
+				if self.globals[index].constant {
+					self.error_at(identifier, "can't redefine constant");
+				}
+				index
+			},
+			None => self.add_global(identifier, constant, true),
+		}
+	}
+
+	/// Saves a new global and returns the index
+	fn add_global(&mut self, identifier: Token, constant: bool, declared: bool) -> usize {
+		if self.globals.len() > u16::MAX as usize {
+			self.error_at(identifier, "too many global variables");
+		}
+		self.globals.push(Global {
+			identifier,
+			constant,
+			initialized: false,
+			declared,
+			value: None,
+		});
+		self.globals.len()-1
 	}
 
 	fn get_global(&mut self, identifier: Token, complain_const: bool) -> Option<usize> {
@@ -257,9 +575,11 @@ impl Compiler {
 		let mut index: Option<usize> = None;
 		let mut error: Option<&str> = None;
 		for (i, global) in self.globals.iter().enumerate() {
-			if self.lexeme(global.identifier) == lexeme {
-				// We are currently initializing this variable
-				if !global.initialized {
+			// The script runs in order, so it can only use globals declared before
+			if self.lexeme(global.identifier) == lexeme && (global.declared || !self.enclosing.is_empty()) {
+				// We are currently initializing this variable,
+				// unless we are in a function which runs later
+				if !global.initialized && self.enclosing.is_empty() {
 					error = Some("can't read global variable in it's own initializer.");
 				}
 
@@ -304,9 +624,52 @@ impl Compiler {
 		return index;
 	}
 
+	/// Find a local of an enclosing function and capture it.
+	/// The level is how deeply nested the function capturing it is.
+	fn resolve_upvalue(&mut self, identifier: Token, level: usize, complain_const: bool) -> Option<usize> {
+		if level == 0 {
+			return None;
+		}
+
+		let lexeme = self.lexeme(identifier).to_owned();
+		let enclosing = &self.enclosing[level-1];
+		let local = enclosing.locals.iter().rposition(|local| self.lexeme(local.identifier) == lexeme);
+
+		if let Some(index) = local {
+			if enclosing.locals[index].constant && complain_const {
+				self.error_at(identifier, "can't redefine constant");
+			}
+			return Some(self.add_upvalue(identifier, level, true, index));
+		}
+
+		// Capture it from the enclosing function which captures it in turn
+		let index = self.resolve_upvalue(identifier, level-1, complain_const)?;
+		Some(self.add_upvalue(identifier, level, false, index))
+	}
+
+	/// Add an upvalue to a function, or reuse one capturing the same variable
+	fn add_upvalue(&mut self, identifier: Token, level: usize, is_local: bool, index: usize) -> usize {
+		let upvalue = Upvalue {is_local, index: index as u16};
+		let upvalues = if level == self.enclosing.len() {
+			&mut self.upvalues
+		} else {
+			&mut self.enclosing[level].upvalues
+		};
+
+		if let Some(existing) = upvalues.iter().position(|existing| *existing == upvalue) {
+			return existing;
+		}
+
+		upvalues.push(upvalue);
+		let index = upvalues.len()-1;
+		if index > u8::MAX as usize {
+			self.error_at(identifier, "too many closure variables in function");
+		}
+		index
+	}
+
 	fn define_global(&mut self, global_index: usize) {
-		self.push_byte(DEFGLOBAL);
-		self.push_byte(global_index as u8);
+		self.push_indexed(DEFGLOBAL, global_index);
 	}
 
 	fn statement(&mut self) {
@@ -322,10 +685,37 @@ impl Compiler {
 			TokenType::WHILE => self.while_statement(),
 			TokenType::FOR => self.for_statement(),
 			TokenType::CONTINUE => self.continue_statement(),
+			TokenType::RETURN => self.return_statement(),
+			TokenType::MATCH => self.match_statement(),
 			_ => self.expression_statement()
 		}
 	}
 
+	fn return_statement(&mut self) {
+		if self.enclosing.is_empty() {
+			self.error_at(self.current, "can't return from top-level code");
+		}
+		self.advance();
+
+		if self.current.ttype == TokenType::SEMICOLON {
+			self.advance();
+			self.push_bytes(&[NIL, RETURN]);
+		} else {
+			self.expression();
+			self.consume(TokenType::SEMICOLON, "expected ';' after return value");
+			self.mark_tail_call();
+			self.push_byte(RETURN);
+		}
+	}
+
+	/// Remember the call that was just compiled if it is the value being returned
+	fn mark_tail_call(&mut self) {
+		let location = self.chunk.code.len() - 2;
+		if self.last_call == Some(location) {
+			self.tail_calls.push(location);
+		}
+	}
+
 	fn continue_statement(&mut self) {
 		// Error if we're not in a loop
 		if let Some(to) = self.continues.pop() {
@@ -426,12 +816,11 @@ impl Compiler {
 	fn goto(&mut self) {
 		self.advance();
 		self.consume(TokenType::IDENTIFIER, "expected a label to go to");
-		self.placeholder_jump(JUMP);
+		let location = self.placeholder_jump(JUMP);
 		self.gotos.push((
 			self.lexeme(self.previous).to_owned(),
 			self.previous,
-			// one accounting for len(), two for the arguments
-			self.chunk.code.len()-3
+			location
 		));
 		self.consume(TokenType::SEMICOLON, "expected ';' after goto statement");
 	}
@@ -446,50 +835,147 @@ impl Compiler {
 		self.statement();
 
 		// Always having a jump even if there's no ELSE
-		// may seem redundant but I think it's necessary
+		// may seem redundant but it's necessary
 		// for popping the condition.
-		// Adding a jump funcition that pops would solve that.
+		// The peephole pass turns this into a jump that pops.
 
 		let jump_over_else = self.placeholder_jump(JUMP);
 		self.patch_jump(jump_to_else);
+		self.push_byte(POP);
 		if self.current.ttype == TokenType::ELSE {
 			self.advance();
 			self.statement();
 		}
-		self.push_byte(POP);
 		self.patch_jump(jump_over_else);
 	}
 
+	/// A match like `match (shape) { Shape.Circle(r) => print r; _ => print "other"; }`,
+	/// the first arm with a matching pattern runs.
+	fn match_statement(&mut self) {
+		self.advance();
+		let keyword = self.previous;
+		self.consume(TokenType::LEFT_PAREN, "expected '(' after match");
+		self.expression();
+		self.consume(TokenType::RIGHT_PAREN, "expected ')' after value");
+		self.consume(TokenType::LEFT_BRACE, "expected '{' before match arms");
+
+		// The value is kept in a local named by the keyword, so no variable can refer to it
+		self.begin_scope();
+		self.declare_variable(keyword, true);
+		self.locals.last_mut().unwrap().initialized = true;
+		let slot = self.locals.len() - 1;
+
+		let mut jumps_to_end = vec![];
+		let mut exhaustive = false;
+		while self.current.ttype != TokenType::RIGHT_BRACE && self.current.ttype != TokenType::EOF {
+			if self.current.ttype == TokenType::IDENTIFIER && self.lexeme(self.current) == "_" {
+				self.advance();
+				self.consume(TokenType::ARROW, "expected '=>' after pattern");
+				self.statement();
+				jumps_to_end.push(self.placeholder_jump(JUMP));
+				exhaustive = true;
+				continue;
+			}
+
+			self.push_indexed(GETLOCAL, slot);
+			let bindings = self.match_pattern();
+			self.push_byte(MATCH);
+			let jump_to_next = self.placeholder_jump(JUMPIFFALSE);
+			self.push_byte(POP);
+
+			self.begin_scope();
+			if let Some(bindings) = &bindings {
+				self.push_indexed(GETLOCAL, slot);
+				self.push_bytes(&[UNPACKVARIANT, bindings.len() as u8]);
+				for binding in bindings {
+					self.declare_variable(*binding, false);
+					self.locals.last_mut().unwrap().initialized = true;
+				}
+			}
+			self.consume(TokenType::ARROW, "expected '=>' after pattern");
+			self.statement();
+			self.end_scope();
+
+			jumps_to_end.push(self.placeholder_jump(JUMP));
+			self.patch_jump(jump_to_next);
+			self.push_byte(POP);
+		}
+		self.consume(TokenType::RIGHT_BRACE, "expected '}' after match arms");
+
+		if !exhaustive {
+			self.push_indexed(GETLOCAL, slot);
+			self.push_byte(NOMATCH);
+		}
+		for jump in jumps_to_end {
+			self.patch_jump(jump);
+		}
+		self.end_scope();
+	}
+
+	/// Compile the value of a match pattern.
+	/// A pattern like `Shape.Circle(r)` returns the variables for the payload
+	fn match_pattern(&mut self) -> Option<Vec<Token>> {
+		// Without the dots this is an expression to compare with
+		if self.current.ttype != TokenType::IDENTIFIER || self.peek_next() != TokenType::DOT {
+			self.expression();
+			return None;
+		}
+
+		self.advance();
+		self.can_assign = false;
+		self.named_variable(self.previous);
+		while self.current.ttype == TokenType::DOT {
+			self.advance();
+			self.dot();
+		}
+
+		if self.current.ttype != TokenType::LEFT_PAREN {
+			return None;
+		}
+		self.advance();
+		let mut bindings = vec![];
+		loop {
+			self.consume(TokenType::IDENTIFIER, "expected variable name in pattern");
+			bindings.push(self.previous);
+			if self.current.ttype != TokenType::COMMA {
+				break;
+			}
+			self.advance();
+		}
+		self.consume(TokenType::RIGHT_PAREN, "expected ')' after pattern");
+		Some(bindings)
+	}
+
 	fn jump_to(&mut self, to: usize, op: OpCode) {
 		let placeholder = self.placeholder_jump(op);
 		self.patch_jump_to(to, placeholder);
 	}
 
-	/// Place a jump instruction with 0xffff as the destination offset;
+	/// Place a jump instruction with 0xffffffff as the destination offset;
 	/// The index of this instruction is returned.
+	/// Jumps start out long, they are made short when the function is done.
 	fn placeholder_jump(&mut self, op: OpCode) -> usize {
-		self.push_bytes(&[op, 0xff,0xff]);
-		// 1 because len and 2 because of the arguments
-		return self.chunk.code.len() - 3;
+		self.push_bytes(&[long_jump(op), 0xff, 0xff, 0xff, 0xff]);
+		// 1 because len and 4 because of the arguments
+		return self.chunk.code.len() - 5;
 	}
 
 	/// Takes the location of a jump and
 	/// inserts the offset between here and there
 	/// as the opcode argument.
 	fn patch_jump(&mut self, location: usize) {
+		// Code before the destination can't be folded with code after it
+		self.constant_ops.clear();
 		self.patch_jump_to(self.chunk.code.len(), location)
 	}
 
 	/// Patch the offset of a jump
 	fn patch_jump_to(&mut self, to: usize, location: usize) {
-		// -3 considering the op itself and the two arguments (I think)
-		let offset: Result<i16,_> = (to as i64 - location as i64 - 3).try_into();
-		if offset.is_err() {
-			self.error_at(self.current, "cannot jump over that much code");
-		}
-		let offset = offset.unwrap();
-		self.chunk.code[location + 1] = ((offset as u16 >> 8) & 0xff) as u8;
-		self.chunk.code[location + 2] = (offset & 0xff) as u8;
+		// -5 considering the op itself and the four arguments
+		let Ok(offset) = i32::try_from(to as i64 - location as i64 - 5) else {
+			return self.error_at(self.current, "cannot jump over that much code");
+		};
+		self.chunk.code[location + 1..location + 5].copy_from_slice(&offset.to_be_bytes());
 	}
 
 	fn block_statement(&mut self) {
@@ -511,8 +997,15 @@ impl Compiler {
 		self.locals.retain(|local| local.depth <= scope);
 		
 		// Create a pop for each removed
-		let localc = len_before - self.locals.len();
-		self.push_bytes(&[LEAVE, localc as u8])
+		let mut localc = len_before - self.locals.len();
+		loop {
+			let n = localc.min(u8::MAX as usize);
+			self.push_bytes(&[LEAVE, n as u8]);
+			localc -= n;
+			if localc == 0 {
+				break;
+			}
+		}
 	}
 
 	fn expression_statement(&mut self) {
@@ -562,6 +1055,9 @@ impl Compiler {
 		
 		self.parse_precedence(Precedence::Unary);
 
+		if self.fold(op_type, 1) {
+			return;
+		}
 		match op_type {
 			TokenType::MINUS => self.push_byte(NEGATE),
 			TokenType::BANG => self.push_byte(NOT),
@@ -592,6 +1088,57 @@ impl Compiler {
 		self.patch_jump(jump_over_false);
 	}
 
+	/// Suspend the generator with a value,
+	/// the value it is resumed with is the result
+	fn yield_expression(&mut self) {
+		if self.enclosing.is_empty() {
+			self.error_at(self.previous, "can't yield from top-level code");
+		}
+		self.generator = true;
+
+		// Yielding without a value yields nil
+		if matches!(self.current.ttype, TokenType::SEMICOLON | TokenType::RIGHT_PAREN) {
+			self.push_byte(NIL);
+		} else {
+			self.expression();
+		}
+		self.push_byte(YIELD);
+	}
+
+	fn coalesce(&mut self) {
+		// First operand is compiled
+		// operator is consumed
+		let jump_over_default = self.placeholder_jump(JUMPIFNOTNIL);
+		self.push_byte(POP);
+		self.parse_precedence(Precedence::Coalesce);
+		self.patch_jump(jump_over_default);
+	}
+
+	fn dot(&mut self) {
+		self.consume(TokenType::IDENTIFIER, "expected property name after '.'");
+		self.property();
+	}
+
+	/// Like a property access but nil stays nil,
+	/// the rest of the chain like `.b` and `()` in `a?.b.c()` is skipped as well
+	fn optional_dot(&mut self) {
+		self.consume(TokenType::IDENTIFIER, "expected property name after '?.'");
+		let jump_if_nil = self.placeholder_jump(JUMPIFNIL);
+		self.property();
+		while get_rule(self.current.ttype).precedence as u32 >= Precedence::Call as u32 {
+			self.advance();
+			let infix = get_rule(self.previous.ttype).infix.unwrap();
+			infix(self);
+		}
+		self.patch_jump(jump_if_nil);
+	}
+
+	fn property(&mut self) {
+		let name = Value::from(self.lexeme(self.previous).to_owned());
+		let index = self.make_constant(name);
+		self.push_indexed(GETPROPERTY, index);
+	}
+
 	fn and(&mut self) {
 		// First operand is compiled
 		// operator is consumed
@@ -613,6 +1160,9 @@ impl Compiler {
 	}
 
 	fn grouping(&mut self) {
+		if self.token_after_group(TokenType::LEFT_PAREN, TokenType::RIGHT_PAREN) == TokenType::ARROW {
+			return self.arrow_function();
+		}
 		self.expression();
 		self.consume(TokenType::RIGHT_PAREN, "expected ')' after expression");
 	}
@@ -627,6 +1177,10 @@ impl Compiler {
 		// Push the other operand
 		self.parse_precedence(rule.precedence as u32+1);
 
+		if self.fold(op_type, 2) {
+			return;
+		}
+
 		// Operator time
 		match op_type {
 			TokenType::PLUS => self.push_byte(ADD),
@@ -644,13 +1198,340 @@ impl Compiler {
 		
 	}
 
+	/// Replace the constants an operator works on with the result.
+	/// Returns false if the operands aren't constants
+	/// or if the operator would cause a runtime error.
+	#[allow(clippy::neg_cmp_op_on_partial_ord)]
+	fn fold(&mut self, op_type: TokenType, operands: usize) -> bool {
+		if !self.options.fold || self.constant_ops.len() < operands {
+			return false;
+		}
+		let first = self.constant_ops.len() - operands;
+		let values: Vec<&Value> = self.constant_ops[first..].iter().map(|(_, value)| value).collect();
+
+		// These have to match what the VM does
+		let result = match (op_type, values.as_slice()) {
+			(TokenType::MINUS, [Value::NUMBER(a)]) => Value::NUMBER(-a),
+			(TokenType::BANG, [a]) => Value::BOOL(!a.is_truthy()),
+			(TokenType::PLUS, [Value::STRING(a), Value::STRING(b)]) => Value::STRING(format!("{}{}", a, b)),
+			(TokenType::PLUS, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::NUMBER(a + b),
+			(TokenType::MINUS, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::NUMBER(a - b),
+			(TokenType::ASTERISK, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::NUMBER(a * b),
+			(TokenType::SLASH, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::NUMBER(a / b),
+			(TokenType::EQUAL_EQUAL, [a, b]) => Value::BOOL(a == b),
+			(TokenType::BANG_EQUAL, [a, b]) => Value::BOOL(a != b),
+			(TokenType::GREATER, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::BOOL(a > b),
+			(TokenType::LESS, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::BOOL(a < b),
+			// Compiled as the negation of the opposite, which differs for NaN
+			(TokenType::GREATER_EQUAL, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::BOOL(!(a < b)),
+			(TokenType::LESS_EQUAL, [Value::NUMBER(a), Value::NUMBER(b)]) => Value::BOOL(!(a > b)),
+			_ => return false,
+		};
+
+		let location = self.constant_ops[first].0;
+		self.chunk.truncate(location);
+		self.constant_ops.truncate(first);
+		self.push_constant(result);
+		true
+	}
+
+	/// The value of the expression compiled from `start`, if it is a single constant
+	fn constant_expression(&self, start: usize) -> Option<Value> {
+		match self.constant_ops.last() {
+			Some((location, value)) if *location == start => Some(value.clone()),
+			_ => None,
+		}
+	}
+
+	fn call(&mut self) {
+		// The callee is compiled
+		// Parenthesis is consumed
+		let (argc, names) = self.arguments();
+		if names.is_empty() {
+			self.push_bytes(&[CALL, argc]);
+			self.last_call = Some(self.chunk.code.len() - 2);
+		} else {
+			self.push_names(&[CALLNAMED, argc, names.len() as u8], &names);
+		}
+	}
+
+	/// Compile the arguments of a call, up to the closing parenthesis.
+	/// Returns the amount of positional arguments
+	/// and the constant indexes of the names of the named arguments.
+	fn arguments(&mut self) -> (u8, Vec<usize>) {
+		let mut argc: usize = 0;
+		let mut names = vec![];
+		if self.current.ttype != TokenType::RIGHT_PAREN {
+			loop {
+				if argc + names.len() == u8::MAX as usize {
+					self.error_at(self.current, "can't have more than 255 arguments");
+				}
+
+				// A named argument like `b: 3`
+				if self.current.ttype == TokenType::IDENTIFIER && self.peek_next() == TokenType::COLON {
+					self.advance();
+					let name = Value::from(self.lexeme(self.previous).to_owned());
+					names.push(self.make_constant(name));
+					self.advance();
+					self.expression();
+				} else {
+					if !names.is_empty() {
+						self.error_at(self.current, "positional argument after named argument");
+					}
+					self.expression();
+					argc += 1;
+				}
+
+				if self.current.ttype != TokenType::COMMA {
+					break;
+				}
+				self.advance();
+			}
+		}
+		self.consume(TokenType::RIGHT_PAREN, "expected ')' after arguments");
+		names.truncate(u8::MAX as usize);
+		(argc.min(u8::MAX as usize) as u8, names)
+	}
+
+	/// An anonymous function like `fun (a, b) { return a + b; }`
+	fn lambda(&mut self) {
+		self.consume(TokenType::LEFT_PAREN, "expected '(' after 'fun'");
+		self.function(None);
+	}
+
+	/// An anonymous function like `(a, b) => a + b`
+	fn arrow_function(&mut self) {
+		// The parenthesis is consumed
+		self.begin_function();
+		self.begin_scope();
+		let (params, rest) = self.parameters();
+		self.consume(TokenType::ARROW, "expected '=>' after parameters");
+		self.expression();
+		self.mark_tail_call();
+		self.push_byte(RETURN);
+		self.end_function(None, params, rest);
+	}
+
+	/// Compile the parameters and body of a function,
+	/// after the opening parenthesis of the parameters
+	fn function(&mut self, name: Option<String>) {
+		self.begin_function();
+		self.begin_scope();
+		let (params, rest) = self.parameters();
+		if self.current.ttype == TokenType::LEFT_BRACE {
+			self.block_statement();
+		} else {
+			self.error_at(self.current, "expected '{' before function body");
+		}
+		// Return nil if the body didn't return
+		self.push_bytes(&[NIL, RETURN]);
+		self.end_function(name, params, rest);
+	}
+
+	/// Declare the parameters as locals, up to the closing parenthesis.
+	/// Returns the parameters and the name of the rest parameter.
+	fn parameters(&mut self) -> (Vec<Parameter>, Option<String>) {
+		let mut params: Vec<Parameter> = vec![];
+		let mut rest = None;
+		if self.current.ttype != TokenType::RIGHT_PAREN {
+			loop {
+				// A rest parameter like `...rest`
+				if self.current.ttype == TokenType::ELLIPSIS {
+					self.advance();
+					self.consume(TokenType::IDENTIFIER, "expected parameter name after '...'");
+					self.declare_variable(self.previous, false);
+					self.locals.last_mut().unwrap().initialized = true;
+					rest = Some(self.lexeme(self.previous).to_owned());
+					if self.current.ttype != TokenType::RIGHT_PAREN {
+						self.error_at(self.current, "rest parameter has to be the last parameter");
+					}
+					break;
+				}
+
+				if params.len() == u8::MAX as usize {
+					self.error_at(self.current, "can't have more than 255 parameters");
+				}
+				self.consume(TokenType::IDENTIFIER, "expected parameter name");
+				let name = self.lexeme(self.previous).to_owned();
+				self.declare_variable(self.previous, false);
+
+				// The default can't read the parameter, which isn't set yet
+				let default = if self.current.ttype == TokenType::EQUAL {
+					self.advance();
+					Some(self.default_value(params.len()))
+				} else {
+					if params.last().is_some_and(|param| param.default.is_some()) {
+						self.error_at(self.previous, "parameter without default after parameter with default");
+					}
+					None
+				};
+				self.locals.last_mut().unwrap().initialized = true;
+				params.push(Parameter {name, default});
+
+				if self.current.ttype != TokenType::COMMA {
+					break;
+				}
+				self.advance();
+			}
+		}
+		self.consume(TokenType::RIGHT_PAREN, "expected ')' after parameters");
+		params.truncate(u8::MAX as usize);
+		(params, rest)
+	}
+
+	/// Compile the default value of a parameter,
+	/// this is evaluated at the start of the function when no argument was passed for the parameter.
+	/// Returns the source of the default value.
+	fn default_value(&mut self, slot: usize) -> String {
+		self.push_indexed(GETLOCAL, slot);
+		let skip_default = self.placeholder_jump(JUMPIFPASSED);
+		self.push_byte(POP);
+
+		let start = self.current.start;
+		self.expression();
+		let end = self.previous.start + self.previous.length as usize;
+
+		self.push_indexed(SETLOCAL, slot);
+		self.patch_jump(skip_default);
+		self.push_byte(POP);
+		self.scanner.source[start..end].to_owned()
+	}
+
+	/// Optimize the code of a function that is done, which starts with its parameters on the stack.
+	/// This also gives the jumps their short form
+	fn finish_chunk(&self, chunk: &mut Chunk, params: usize, script: bool) {
+		let mut instructions = chunk.decode();
+		if self.options.peephole {
+			peephole::optimize(&mut instructions);
+		}
+		if self.options.registers {
+			registers::select(&mut instructions);
+			registers::allocate(&mut instructions, params, script);
+		}
+		if self.options.peephole {
+			peephole::superinstructions(&mut instructions);
+		}
+		// This also gives every jump the shortest form that reaches
+		chunk.encode(&instructions);
+	}
+
+	/// Store away the state of the current function to start compiling a nested one
+	fn begin_function(&mut self) {
+		let state = FunctionState {
+			chunk: std::mem::replace(&mut self.chunk, Chunk::new()),
+			locals: std::mem::take(&mut self.locals),
+			upvalues: std::mem::take(&mut self.upvalues),
+			scope: std::mem::replace(&mut self.scope, 0),
+			labels: std::mem::take(&mut self.labels),
+			gotos: std::mem::take(&mut self.gotos),
+			continues: std::mem::take(&mut self.continues),
+			generator: std::mem::replace(&mut self.generator, false),
+			tail_calls: std::mem::take(&mut self.tail_calls),
+		};
+		self.enclosing.push(state);
+		self.constant_ops.clear();
+		// The last call is an offset in the enclosing chunk
+		self.last_call = None;
+	}
+
+	/// Restore the enclosing function and create a closure of the finished one there
+	fn end_function(&mut self, name: Option<String>, params: Vec<Parameter>, rest: Option<String>) {
+		// Labels can only be used inside the function they are in
+		#[allow(unused_must_use)] {
+			self.resolve_gotos();
+		}
+
+		let state = self.enclosing.pop().unwrap();
+		self.constant_ops.clear();
+		let mut chunk = std::mem::replace(&mut self.chunk, state.chunk);
+		let upvalues = std::mem::replace(&mut self.upvalues, state.upvalues);
+		self.locals = state.locals;
+		self.scope = state.scope;
+		self.labels = state.labels;
+		self.gotos = state.gotos;
+		self.continues = state.continues;
+		let generator = std::mem::replace(&mut self.generator, state.generator);
+		let tail_calls = std::mem::replace(&mut self.tail_calls, state.tail_calls);
+		self.last_call = None;
+
+		// A generator has to keep its frame
+		if self.options.tail_calls && !generator {
+			for location in tail_calls {
+				chunk.code[location] = TAILCALL;
+			}
+		}
+		// Unpatched jumps can't be decoded
+		if self.success {
+			self.finish_chunk(&mut chunk, params.len() + rest.is_some() as usize, false);
+		}
+
+		let function = Function {
+			arity: params.len() as u8,
+			required: params.iter().take_while(|param| param.default.is_none()).count() as u8,
+			params,
+			rest,
+			chunk,
+			name,
+			upvalue_count: upvalues.len(),
+			generator,
+			decoded: OnceCell::new(),
+		};
+		let index = self.make_constant(Value::FUNCTION(Rc::new(function)));
+		self.push_indexed(CLOSURE, index);
+		for upvalue in upvalues {
+			self.push_bytes(&[upvalue.is_local as u8, (upvalue.index >> 8) as u8, upvalue.index as u8]);
+		}
+	}
+
+	/// Look ahead for the token after the group that was just opened.
+	/// This is used to tell the parameters of an arrow function apart from a grouping
+	/// and a destructuring assignment apart from a list or map.
+	fn token_after_group(&mut self, open: TokenType, close: TokenType) -> TokenType {
+		let state = self.scanner.save();
+		let mut token = self.current;
+		let mut depth = 1;
+		let after = loop {
+			if token.ttype == open {
+				depth += 1;
+			} else if token.ttype == close {
+				depth -= 1;
+				if depth == 0 {
+					break match self.scanner.scan_token() {
+						TokenResult::TOKEN(next) => next.ttype,
+						TokenResult::ERROR(_) => TokenType::EOF,
+					};
+				}
+			} else if matches!(token.ttype, TokenType::SEMICOLON | TokenType::EOF) {
+				break TokenType::EOF;
+			}
+			token = match self.scanner.scan_token() {
+				TokenResult::TOKEN(token) => token,
+				TokenResult::ERROR(_) => break TokenType::EOF,
+			};
+		};
+		self.scanner.restore(state);
+		after
+	}
+
+	/// Get the type of the token after the current one
+	fn peek_next(&mut self) -> TokenType {
+		let state = self.scanner.save();
+		let ttype = match self.scanner.scan_token() {
+			TokenResult::TOKEN(token) => token.ttype,
+			TokenResult::ERROR(_) => TokenType::EOF,
+		};
+		self.scanner.restore(state);
+		ttype
+	}
+
 	fn literal(&mut self) {
-		match self.previous.ttype {
-			TokenType::NIL => self.push_byte(NIL),
-			TokenType::TRUE => self.push_byte(TRUE),
-			TokenType::FALSE => self.push_byte(FALSE),
+		let (op, value) = match self.previous.ttype {
+			TokenType::NIL => (NIL, Value::NIL),
+			TokenType::TRUE => (TRUE, Value::BOOL(true)),
+			TokenType::FALSE => (FALSE, Value::BOOL(false)),
 			_ => unreachable!()
-		}
+		};
+		self.push_constant_ops(&[op], value);
 	}
 
 	fn string(&mut self) {
@@ -671,35 +1552,124 @@ impl Compiler {
 	}
 
 	fn named_variable(&mut self, identifier: Token) {
-		
-		// I feel like these should be mutable but whatever
-		let variable_index;
-		let set_op;
-		let get_op;
-
 		let assignment = self.can_assign && self.current.ttype == TokenType::EQUAL;
-		
+		let (get_op, set_op, variable_index) = self.resolve_variable(identifier, assignment);
+
+		if assignment {
+			self.advance();
+			self.expression();
+			self.push_indexed(set_op, variable_index);
+		} else if let Some(value) = self.constant_value(get_op, variable_index) {
+			self.push_constant(value);
+		} else {
+			self.push_indexed(get_op, variable_index);
+		}
+	}
+
+	/// The value of a constant global that can replace reading it
+	fn constant_value(&self, get_op: OpCode, index: usize) -> Option<Value> {
+		if get_op == GETGLOBAL && self.globals[index].initialized {
+			self.globals[index].value.clone()
+		} else {
+			None
+		}
+	}
+
+	/// Find the ops and index to get and set a variable with
+	fn resolve_variable(&mut self, identifier: Token, assignment: bool) -> (OpCode, OpCode, usize) {
 		if let Some(index) = self.resolve_local(identifier, assignment) {
-			set_op = SETLOCAL;
-			get_op = GETLOCAL;
-			variable_index = index;
+			(GETLOCAL, SETLOCAL, index)
+		} else if let Some(index) = self.resolve_upvalue(identifier, self.enclosing.len(), assignment) {
+			(GETUPVALUE, SETUPVALUE, index)
 		} else if let Some(index) = self.get_global(identifier, assignment) {
-			set_op = SETGLOBAL;
-			get_op = GETGLOBAL;
-			variable_index = index;
+			(GETGLOBAL, SETGLOBAL, index)
+		} else if let Some(native) = native::find(self.lexeme(identifier)).filter(|_| !assignment) {
+			// Natives are read like a constant
+			let index = self.make_constant(Value::NATIVE(native));
+			(CONSTANT, CONSTANT, index)
+		} else if !self.enclosing.is_empty() {
+			// A function runs later, so it can use a global that is declared after it.
+			// Globals that are never declared are reported once the script is compiled.
+			(GETGLOBAL, SETGLOBAL, self.add_global(identifier, false, false))
 		} else {
 			self.error_at(identifier, "cannot find variable");
-			set_op = SETLOCAL;
-			get_op = GETLOCAL;
-			variable_index = 0;
+			(GETLOCAL, SETLOCAL, 0)
+		}
+	}
+
+	fn list(&mut self) {
+		if self.can_assign && self.token_after_group(TokenType::LEFT_BRACKET, TokenType::RIGHT_BRACKET) == TokenType::EQUAL {
+			return self.destructuring_assignment(false);
 		}
 
-		if self.can_assign && self.current.ttype == TokenType::EQUAL {
+		let mut count: usize = 0;
+		while self.current.ttype != TokenType::RIGHT_BRACKET {
+			if count == u8::MAX as usize {
+				self.error_at(self.current, "can't have more than 255 items in a list literal");
+			}
+			self.expression();
+			count += 1;
+			if self.current.ttype != TokenType::COMMA {
+				break;
+			}
 			self.advance();
+		}
+		self.consume(TokenType::RIGHT_BRACKET, "expected ']' after list items");
+		self.push_bytes(&[BUILDLIST, count.min(u8::MAX as usize) as u8]);
+	}
+
+	fn map(&mut self) {
+		if self.can_assign && self.token_after_group(TokenType::LEFT_BRACE, TokenType::RIGHT_BRACE) == TokenType::EQUAL {
+			return self.destructuring_assignment(true);
+		}
+
+		let mut count: usize = 0;
+		while self.current.ttype != TokenType::RIGHT_BRACE {
+			if count == u8::MAX as usize {
+				self.error_at(self.current, "can't have more than 255 items in a map literal");
+			}
+			// Keys are identifiers or strings
+			match self.current.ttype {
+				TokenType::IDENTIFIER => {
+					self.advance();
+					let key = Value::from(self.lexeme(self.previous).to_owned());
+					self.push_constant(key);
+				},
+				TokenType::STRING => {
+					self.advance();
+					self.string();
+				},
+				_ => self.error_at(self.current, "expected key in map literal"),
+			}
+			self.consume(TokenType::COLON, "expected ':' after map key");
 			self.expression();
-			self.push_bytes(&[set_op, variable_index as u8]);
-		} else {
-			self.push_bytes(&[get_op, variable_index as u8]);
+			count += 1;
+			if self.current.ttype != TokenType::COMMA {
+				break;
+			}
+			self.advance();
+		}
+		self.consume(TokenType::RIGHT_BRACE, "expected '}' after map items");
+		self.push_bytes(&[BUILDMAP, count.min(u8::MAX as usize) as u8]);
+	}
+
+	/// An assignment like `[a, b] = [b, a]`, the value of it is the right side
+	fn destructuring_assignment(&mut self, map: bool) {
+		let pattern = self.pattern(map);
+		self.consume(TokenType::EQUAL, "expected '=' after destructuring pattern");
+		self.expression();
+		self.push_byte(DUP);
+		self.unpack(&pattern);
+
+		let targets: Vec<(OpCode, usize)> = pattern.targets.iter().map(|target| {
+			let (_, set_op, index) = self.resolve_variable(*target, true);
+			(set_op, index)
+		}).collect();
+
+		// The last value is on top of the stack
+		for (set_op, index) in targets.into_iter().rev() {
+			self.push_indexed(set_op, index);
+			self.push_byte(POP);
 		}
 	}
 
@@ -710,11 +1680,78 @@ impl Compiler {
 	}
 
 	fn push_constant(&mut self, value: Value) {
-		if self.chunk.constants.len() >= 256^std::mem::size_of::<OpCode>() {
-			self.error_at(self.previous, "reached constant limit");
+		let index = self.make_constant(value.clone());
+		let ops = self.indexed_ops(CONSTANT, index);
+		self.push_constant_ops(&ops, value);
+	}
+
+	/// Push an op that takes an index, using the long form of the op if needed
+	fn push_indexed(&mut self, op: OpCode, index: usize) {
+		let ops = self.indexed_ops(op, index);
+		self.push_bytes(&ops);
+	}
+
+	fn indexed_ops(&self, op: OpCode, index: usize) -> Vec<OpCode> {
+		if index <= u8::MAX as usize {
+			return vec![op, index as u8];
+		}
+		let long = match op {
+			CONSTANT => CONSTANTLONG,
+			DEFGLOBAL => DEFGLOBALLONG,
+			GETGLOBAL => GETGLOBALLONG,
+			SETGLOBAL => SETGLOBALLONG,
+			GETLOCAL => GETLOCALLONG,
+			SETLOCAL => SETLOCALLONG,
+			CLOSURE => CLOSURELONG,
+			GETPROPERTY => GETPROPERTYLONG,
+			// Upvalues have no long form, too many of them is already an error
+			_ => return vec![op, index as u8],
+		};
+		vec![long, (index >> 8) as u8, index as u8]
+	}
+
+	/// Push an op with its operands followed by the constant indexes of names,
+	/// if one of the indexes doesn't fit in a byte the long form of the op is used for all of them
+	fn push_names(&mut self, ops: &[OpCode], indexes: &[usize]) {
+		let long = indexes.iter().any(|index| *index > u8::MAX as usize);
+		let mut ops = ops.to_vec();
+		if long {
+			ops[0] = match ops[0] {
+				UNPACKMAP => UNPACKMAPLONG,
+				CALLNAMED => CALLNAMEDLONG,
+				op => op,
+			};
+		}
+		for index in indexes {
+			if long {
+				ops.push((index >> 8) as u8);
+			}
+			ops.push(*index as u8);
+		}
+		self.push_bytes(&ops);
+	}
+
+	/// Push the ops that load a constant,
+	/// unlike other ops these can be folded with the constants before them
+	fn push_constant_ops(&mut self, ops: &[OpCode], value: Value) {
+		let location = self.chunk.code.len();
+		for op in ops {
+			self.chunk.push_op(*op, self.previous.line);
+		}
+		self.constant_ops.push((location, value));
+	}
+
+	/// Add a value to the constants and return the index,
+	/// an equal constant is reused
+	fn make_constant(&mut self, value: Value) -> usize {
+		if let Some(index) = self.chunk.find_constant(&value) {
+			return index;
+		}
+		if self.chunk.constants.len() > u16::MAX as usize {
+			self.error_at(self.previous, "too many constants in one chunk");
+			return 0;
 		}
-		let index = self.chunk.push_constant(value) as OpCode;
-		self.push_bytes(&[CONSTANT, index]);
+		self.chunk.push_constant(value)
 	}
 
 	fn lexeme(&self, token: Token) -> &str {
@@ -737,6 +1774,7 @@ impl Compiler {
 	}
 
 	fn push_byte(&mut self, op: OpCode) {
+		self.constant_ops.clear();
 		self.chunk.push_op(op, self.previous.line);
 	}
 
@@ -790,6 +1828,8 @@ impl Compiler {
 				TokenType::PRINT 	|
 				TokenType::LABEL    |
 				TokenType::GOTO     |
+				TokenType::ENUM     |
+				TokenType::MATCH    |
 				TokenType::RETURN => return,
 				_ => {}
 			}
@@ -0,0 +1,152 @@
+use crate::vm::chunk::Instruction;
+use crate::vm::op_codes::*;
+
+/// Rewrite the code of a finished function without the waste the compiler leaves behind
+pub fn optimize(instructions: &mut Vec<Instruction>) {
+	// One rewrite can make another possible, so keep going until nothing changes
+	while fuse(instructions) | remove(instructions) | thread_jumps(instructions) {}
+}
+
+/// Which instructions are jumped to, including the end of the code
+pub fn targets(instructions: &[Instruction]) -> Vec<bool> {
+	let mut targets = vec![false; instructions.len() + 1];
+	for instruction in instructions {
+		if let Some(target) = instruction.target {
+			targets[target] = true;
+		}
+	}
+	targets
+}
+
+/// Replace pairs of ops with a single op
+fn fuse(instructions: &mut Vec<Instruction>) -> bool {
+	let targets = targets(instructions);
+	let mut removed = vec![false; instructions.len()];
+	for i in 0..instructions.len().saturating_sub(1) {
+		// The second op can't be fused if something jumps to it
+		if removed[i] || targets[i + 1] {
+			continue;
+		}
+		match (instructions[i].op, instructions[i + 1].op) {
+			(EQUAL, NOT) => instructions[i].op = NOTEQUAL,
+			(LESS, NOT) => instructions[i].op = GREATEREQUAL,
+			(GREATER, NOT) => instructions[i].op = LESSEQUAL,
+			// Both paths pop the condition, so pop it before jumping
+			(JUMPIFFALSE, POP) => {
+				let target = instructions[i].target.unwrap();
+				if instructions.get(target).map(|instruction| instruction.op) != Some(POP) {
+					continue;
+				}
+				instructions[i].op = JUMPIFFALSEPOP;
+				instructions[i].target = Some(target + 1);
+			},
+			_ => continue,
+		}
+		removed[i + 1] = true;
+	}
+	compact(instructions, &removed)
+}
+
+/// Replace the sequences of ops that are run the most in loops with a single op.
+/// This is done last, so the other rewrites don't have to know these ops.
+pub fn superinstructions(instructions: &mut Vec<Instruction>) {
+	let targets = targets(instructions);
+	let mut removed = vec![false; instructions.len()];
+	let mut i = 0;
+	while i < instructions.len() {
+		let sequence = &instructions[i..instructions.len().min(i + 5)];
+		let ops: Vec<OpCode> = sequence.iter().map(|instruction| instruction.op).collect();
+		let operand = |n: usize| sequence[n].operands[0];
+		let (op, length) = match ops[..] {
+			[GETLOCAL, CONSTANT, ADD, SETLOCAL, POP, ..] if operand(0) == operand(3) => (INCRLOCAL, 5),
+			[GETLOCAL, GETLOCAL, LESS, JUMPIFFALSEPOP, ..] => (LESSLOCALSJUMP, 4),
+			[GETLOCAL, CONSTANT, LESS, JUMPIFFALSEPOP, ..] => (LESSLOCALCONSTJUMP, 4),
+			[GETLOCAL, CONSTANT, ADD, ..] => (ADDLOCALCONST, 3),
+			[GETLOCAL, CONSTANT, SUBTRACT, ..] => (SUBTRACTLOCALCONST, 3),
+			_ => {
+				i += 1;
+				continue;
+			}
+		};
+		// Something jumping into the middle of the sequence still needs the separate ops
+		if targets[i + 1..i + length].contains(&true) {
+			i += 1;
+			continue;
+		}
+		instructions[i] = Instruction {
+			op,
+			operands: vec![operand(0), operand(1)],
+			target: instructions[i + length - 1].target,
+			// Errors come from the operator
+			line: instructions[i + 2].line,
+		};
+		removed[i + 1..i + length].fill(true);
+		i += length;
+	}
+	compact(instructions, &removed);
+}
+
+/// Remove instructions that do nothing or can't be reached
+fn remove(instructions: &mut Vec<Instruction>) -> bool {
+	let targets = targets(instructions);
+	let mut removed = vec![false; instructions.len()];
+	let mut reachable = true;
+	for (i, instruction) in instructions.iter().enumerate() {
+		reachable |= targets[i];
+		removed[i] = !reachable
+			|| instruction.op == LEAVE && instruction.operands == [0]
+			|| instruction.op == JUMP && instruction.target == Some(i + 1);
+		if !removed[i] {
+			reachable = !matches!(instruction.op, JUMP | RETURN);
+		}
+	}
+	compact(instructions, &removed)
+}
+
+/// Let jumps that land on a jump go to where that jump goes
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+	let mut changed = false;
+	for i in 0..instructions.len() {
+		let Some(mut target) = instructions[i].target else {
+			continue;
+		};
+		// Limited, as jumps can go around in a circle
+		for _ in 0..instructions.len() {
+			match instructions.get(target) {
+				Some(next) if next.op == JUMP && next.target != Some(target) => target = next.target.unwrap(),
+				_ => break,
+			}
+		}
+		if instructions[i].target != Some(target) {
+			instructions[i].target = Some(target);
+			changed = true;
+		}
+	}
+	changed
+}
+
+/// Take out the removed instructions.
+/// A jump to a removed instruction goes to the instruction after it.
+pub fn compact(instructions: &mut Vec<Instruction>, removed: &[bool]) -> bool {
+	if !removed.contains(&true) {
+		return false;
+	}
+	let mut index = Vec::with_capacity(instructions.len() + 1);
+	let mut kept = 0;
+	for is_removed in removed {
+		index.push(kept);
+		if !is_removed {
+			kept += 1;
+		}
+	}
+	index.push(kept);
+
+	let mut removed = removed.iter();
+	instructions.retain(|_| !removed.next().unwrap());
+	for instruction in instructions.iter_mut() {
+		if let Some(target) = &mut instruction.target {
+			*target = index[*target];
+		}
+	}
+	true
+}
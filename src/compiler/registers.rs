@@ -0,0 +1,247 @@
+use crate::vm::chunk::Instruction;
+use crate::vm::op_codes::*;
+use crate::vm::verifier::stack_effect;
+use super::peephole::{targets, compact};
+
+/*
+The locals of a function are in fixed slots on the stack, so they can be used as registers.
+Code that only moves values between locals and constants is turned into register ops,
+which read and write the locals directly instead of pushing and popping.
+The temporaries of expressions are registers too, the ones above the locals in the order
+the stack code would push them, so the result of an operator is stored where it would be pushed.
+Locals and constants that an operator, branch or return uses are read where they are
+instead of being pushed first. Everything else, like calls, still uses the stack,
+the values it needs are pushed right before it.
+*/
+
+/// Replace assignments of a local from other locals and constants with register ops
+pub fn select(instructions: &mut Vec<Instruction>) {
+	let targets = targets(instructions);
+	let mut removed = vec![false; instructions.len()];
+	let mut i = 0;
+	while i < instructions.len() {
+		let sequence = &instructions[i..instructions.len().min(i + 5)];
+		let ops: Vec<OpCode> = sequence.iter().map(|instruction| instruction.op).collect();
+		let operand = |n: usize| sequence[n].operands[0];
+		// The assignment has to be a statement, the value can't be left on the stack
+		let (op, operands, length) = match ops[..] {
+			[GETLOCAL, SETLOCAL, POP, ..] => (MOVEREG, vec![operand(1), operand(0)], 3),
+			[CONSTANT, SETLOCAL, POP, ..] => (LOADREG, vec![operand(1), operand(0)], 3),
+			[GETLOCAL, GETLOCAL, op, SETLOCAL, POP] if register_op(op).is_some() => {
+				(register_op(op).unwrap().0, vec![operand(3), operand(0), operand(1)], 5)
+			},
+			[GETLOCAL, CONSTANT, op, SETLOCAL, POP] if register_op(op).is_some() => {
+				(register_op(op).unwrap().1, vec![operand(3), operand(0), operand(1)], 5)
+			},
+			_ => {
+				i += 1;
+				continue;
+			}
+		};
+		if targets[i + 1..i + length].contains(&true) {
+			i += 1;
+			continue;
+		}
+		instructions[i] = Instruction {
+			op,
+			operands,
+			target: None,
+			// Errors come from the operator
+			line: instructions[i + length - 3].line,
+		};
+		removed[i + 1..i + length].fill(true);
+		i += length;
+	}
+	compact(instructions, &removed);
+}
+
+/// The register ops for an operator, with a local and with a constant as second operand
+fn register_op(op: OpCode) -> Option<(OpCode, OpCode)> {
+	match op {
+		ADD => Some((ADDREG, ADDREGCONST)),
+		SUBTRACT => Some((SUBTRACTREG, SUBTRACTREGCONST)),
+		MULTIPLY => Some((MULTIPLYREG, MULTIPLYREGCONST)),
+		DIVIDE => Some((DIVIDEREG, DIVIDEREGCONST)),
+		_ => None,
+	}
+}
+
+/// A value the stack code pushes that isn't pushed yet, as it can be read where it is
+#[derive(Clone, Copy)]
+enum Pending {
+	Register(u8),
+	Constant(u8),
+}
+
+/// Replace the pushes of locals and constants that are only used by an operator,
+/// a conditional jump or a return with register ops that read them where they are
+pub fn allocate(instructions: &mut Vec<Instruction>, params: usize, script: bool) {
+	let depths = depths(instructions, params, script);
+	let targets = targets(instructions);
+	let mut code = Vec::with_capacity(instructions.len());
+	// Where every instruction ends up, for the jumps
+	let mut index = Vec::with_capacity(instructions.len() + 1);
+	// The top of the stack that isn't pushed yet, with the line of the instruction that pushes it
+	let mut pending: Vec<(Pending, u32)> = vec![];
+	let mut i = 0;
+	while i < instructions.len() {
+		// Other paths reach a target with everything pushed
+		if targets[i] {
+			push(&mut code, &mut pending, 0);
+		}
+		index.push(code.len());
+		let instruction = &instructions[i];
+		let line = instruction.line;
+		let Some(depth) = depths[i] else {
+			code.push(instruction.clone());
+			i += 1;
+			continue;
+		};
+		let stacked = depth - pending.len();
+		let top = pending.last().map(|(value, _)| *value);
+		match (instruction.op, top) {
+			(GETLOCAL, _) => {
+				// A register that isn't pushed yet can't be read
+				if instruction.operands[0] as usize >= stacked {
+					push(&mut code, &mut pending, 0);
+				}
+				pending.push((Pending::Register(instruction.operands[0]), line));
+			},
+			(CONSTANT, _) => pending.push((Pending::Constant(instruction.operands[0]), line)),
+			(ADD | SUBTRACT | MULTIPLY | DIVIDE | EQUAL | NOTEQUAL | GREATER | LESS | GREATEREQUAL | LESSEQUAL, Some(b))
+				if depth - 2 <= u8::MAX as usize =>
+			{
+				let register = (depth - 2) as u8;
+				// The first operand is either pending as well or the temporary that is stored in
+				let a = if pending.len() >= 2 {pending[pending.len() - 2].0} else {Pending::Register(register)};
+				let kept = pending.len().min(2);
+				push(&mut code, &mut pending, kept);
+				pending.clear();
+				let jump = instructions.get(i + 1).filter(|next| next.op == JUMPIFFALSEPOP && !targets[i + 1]);
+				match (comparison_jump(instruction.op, a, b), jump) {
+					(Some((op, operands)), Some(jump)) if kept == 2 => {
+						code.push(Instruction {op, operands, target: jump.target, line});
+						index.push(code.len());
+						i += 1;
+					},
+					_ => code.push(Instruction {
+						op: BINARYREG,
+						operands: with_sources(vec![instruction.op, register], &[a, b]),
+						target: None,
+						line,
+					}),
+				}
+			},
+			(NEGATE | NOT, Some(value)) => {
+				push(&mut code, &mut pending, 1);
+				pending.clear();
+				code.push(Instruction {
+					op: UNARYREG,
+					operands: with_sources(vec![instruction.op, (depth - 1) as u8], &[value]),
+					target: None,
+					line,
+				});
+			},
+			(POP, Some(_)) => {
+				pending.pop();
+			},
+			(DUP, Some(value)) => pending.push((value, line)),
+			(JUMPIFFALSEPOP, Some(Pending::Register(register))) => {
+				push(&mut code, &mut pending, 1);
+				pending.clear();
+				code.push(Instruction {op: JUMPIFFALSEREG, operands: vec![register], target: instruction.target, line});
+			},
+			// The values under the one that is returned are thrown away with the frame
+			(RETURN, Some(value)) if !script => {
+				pending.clear();
+				code.push(Instruction {op: RETURNREG, operands: with_sources(vec![], &[value]), target: None, line});
+			},
+			_ => {
+				push(&mut code, &mut pending, 0);
+				code.push(instruction.clone());
+			},
+		}
+		i += 1;
+	}
+	push(&mut code, &mut pending, 0);
+	index.push(code.len());
+	for instruction in &mut code {
+		if let Some(target) = &mut instruction.target {
+			*target = index[*target];
+		}
+	}
+	*instructions = code;
+}
+
+/// The depth of the stack before every instruction, none for the ones that can't be reached
+fn depths(instructions: &[Instruction], params: usize, script: bool) -> Vec<Option<usize>> {
+	let mut depths = vec![None; instructions.len()];
+	if instructions.is_empty() {
+		return depths;
+	}
+	depths[0] = Some(params);
+	let mut pending = vec![0];
+	while let Some(i) = pending.pop() {
+		let instruction = &instructions[i];
+		let (pops, pushes) = stack_effect(instruction.op, &instruction.operands, depths[i].unwrap(), script);
+		let depth = depths[i].unwrap() - pops + pushes;
+		let next = (!matches!(instruction.op, JUMP | RETURN | NOMATCH)).then_some(i + 1);
+		for next in next.into_iter().chain(instruction.target) {
+			if next < instructions.len() && depths[next].is_none() {
+				depths[next] = Some(depth);
+				pending.push(next);
+			}
+		}
+	}
+	depths
+}
+
+/// Push the pending values, except for the last ones that are kept
+fn push(code: &mut Vec<Instruction>, pending: &mut Vec<(Pending, u32)>, kept: usize) {
+	for (value, line) in pending.drain(..pending.len() - kept) {
+		let (op, operand) = match value {
+			Pending::Register(register) => (GETLOCAL, register),
+			Pending::Constant(index) => (CONSTANT, index),
+		};
+		code.push(Instruction {op, operands: vec![operand], target: None, line});
+	}
+}
+
+/// The operands of a register op, followed by the kinds and sources
+fn with_sources(mut operands: Vec<u8>, sources: &[Pending]) -> Vec<u8> {
+	let mut kinds = 0;
+	let mut indexes = vec![];
+	for (i, source) in sources.iter().enumerate() {
+		indexes.push(match *source {
+			Pending::Register(register) => register,
+			Pending::Constant(index) => {
+				kinds |= 1 << i;
+				index
+			},
+		});
+	}
+	operands.push(kinds);
+	operands.extend(indexes);
+	operands
+}
+
+/// The jump for a comparison of two pending values, a constant has to be the second one
+fn comparison_jump(op: OpCode, a: Pending, b: Pending) -> Option<(OpCode, Vec<u8>)> {
+	if !matches!(op, EQUAL | NOTEQUAL | GREATER | LESS | GREATEREQUAL | LESSEQUAL) {
+		return None;
+	}
+	// With the constant first the comparison is turned around, a < 1 is the same as 1 > a
+	let mirrored = match op {
+		GREATER => LESS,
+		LESS => GREATER,
+		GREATEREQUAL => LESSEQUAL,
+		LESSEQUAL => GREATEREQUAL,
+		_ => op,
+	};
+	match (a, b) {
+		(Pending::Register(a), Pending::Register(b)) => Some((JUMPUNLESSREG, vec![op, a, b])),
+		(Pending::Register(a), Pending::Constant(b)) => Some((JUMPUNLESSREGCONST, vec![op, a, b])),
+		(Pending::Constant(a), Pending::Register(b)) => Some((JUMPUNLESSREGCONST, vec![mirrored, b, a])),
+		(Pending::Constant(_), Pending::Constant(_)) => None,
+	}
+}
@@ -9,6 +9,7 @@ pub enum Precedence {
 	None = 0,
 	Assignment,  // =
 	Ternary,     // ? :
+	Coalesce,    // ??
 	Or,          // or
 	And,         // and
 	Equality,    // == !=
@@ -16,7 +17,7 @@ pub enum Precedence {
 	Term,        // + -
 	Factor,      // * /
 	Unary,       // ! -
-	Call,        // . ()
+	Call,        // . ?. ()
 	Primary
 }
 
@@ -50,7 +51,7 @@ macro_rules! parse_rule {
 
 pub(super) fn get_rule(ttype: TokenType) -> ParseRule {
 	match ttype {
-		TokenType::LEFT_PAREN => parse_rule!(prefix => grouping, None),
+		TokenType::LEFT_PAREN => parse_rule!(both => grouping, call, Call),
 		TokenType::MINUS => parse_rule!(both => unary,binary,Term),
 		TokenType::PLUS => parse_rule!(infix => binary,Term),
 		TokenType::ASTERISK | TokenType::SLASH => parse_rule!(infix => binary,Factor),
@@ -62,7 +63,14 @@ pub(super) fn get_rule(ttype: TokenType) -> ParseRule {
 		TokenType::EQUAL_EQUAL | TokenType::BANG_EQUAL => parse_rule!(infix => binary, Equality),
 		TokenType::GREATER | TokenType::LESS | TokenType::GREATER_EQUAL | TokenType::LESS_EQUAL => parse_rule!(infix => binary, Comparison),
 		TokenType::IDENTIFIER => parse_rule!(prefix => variable, None),
+		TokenType::FUN => parse_rule!(prefix => lambda, None),
+		TokenType::YIELD => parse_rule!(prefix => yield_expression, None),
+		TokenType::LEFT_BRACKET => parse_rule!(prefix => list, None),
+		TokenType::LEFT_BRACE => parse_rule!(prefix => map, None),
         TokenType::QUESTION => parse_rule!(infix => ternary, Ternary),
+        TokenType::QUESTION_QUESTION => parse_rule!(infix => coalesce, Coalesce),
+        TokenType::DOT => parse_rule!(infix => dot, Call),
+        TokenType::QUESTION_DOT => parse_rule!(infix => optional_dot, Call),
         TokenType::AND => parse_rule!(infix => and, And),
         TokenType::OR => parse_rule!(infix => or, Or),
 		_ => parse_rule!(none)
@@ -3,20 +3,96 @@ mod vm;
 mod repl;
 mod scanner;
 mod compiler;
+mod transpiler;
 use std::fs;
-use compiler::compile;
+use std::path::Path;
+use compiler::{compile, Options};
+use vm::chunk::Chunk;
 use scanner::Scanner;
 
 fn main() {
-    let args: Vec<String> = std::env::args().skip(1).collect();
-    if args.len() >= 1 {
-        let source = fs::read_to_string(&args[0]).unwrap();
-        if let Ok(chunk) = compile(source) {
-            #[cfg(debug_assertions)]
-            eprintln!("{}", chunk.disassemble(&args[0]));
-            vm::interpret(chunk);
+    let mut args = std::env::args().skip(1).peekable();
+    // `loxidation build script.lox -o script.c` compiles to C instead of running
+    let build = args.next_if(|arg| arg == "build").is_some();
+    let mut options = Options::default();
+    let mut files = vec![];
+    let mut output = None;
+    // `--emit-bytecode` saves the compiled chunk to a .loxc file instead of running it
+    let mut emit_bytecode = false;
+    // `--emit-assembly` saves the disassembly to a .loxasm file instead of running it
+    let mut emit_assembly = false;
+    // `--emit-cfg` writes the control flow graph to a .dot file instead of running it
+    let mut emit_cfg = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-tail-calls" => options.tail_calls = false,
+            "--no-fold" => options.fold = false,
+            "--no-peephole" => options.peephole = false,
+            "--registers" => options.registers = true,
+            "--emit-bytecode" => emit_bytecode = true,
+            "--emit-assembly" => emit_assembly = true,
+            "--emit-cfg" => emit_cfg = true,
+            "-o" if build || emit_bytecode || emit_assembly || emit_cfg => output = args.next(),
+            flag if flag.starts_with("--") => {
+                eprintln!("Unknown option {}", flag);
+                return;
+            },
+            _ => files.push(arg),
         }
+    }
+
+    let Some(file) = files.first() else {
+        if build || emit_bytecode || emit_assembly || emit_cfg {
+            eprintln!("Usage: loxidation [build | --emit-bytecode | --emit-assembly | --emit-cfg] script.lox -o output");
+        } else {
+            repl::repl(options);
+        }
+        return;
+    };
+    let Some(chunk) = load(file, options) else {
+        return;
+    };
+    let output = |extension| output.clone().unwrap_or_else(|| Path::new(file).with_extension(extension).to_string_lossy().into_owned());
+
+    if build {
+        match transpiler::transpile(&chunk) {
+            Ok(c) => fs::write(output("c"), c).unwrap(),
+            Err(msg) => eprintln!("Error: {}", msg),
+        }
+    } else if emit_bytecode {
+        match vm::bytecode::save(&chunk) {
+            Ok(bytes) => fs::write(output("loxc"), bytes).unwrap(),
+            Err(msg) => eprintln!("Error: {}", msg),
+        }
+    } else if emit_assembly {
+        fs::write(output("loxasm"), chunk.disassemble(file)).unwrap();
+    } else if emit_cfg {
+        match vm::cfg::to_dot(&chunk, file) {
+            Ok(dot) => fs::write(output("dot"), dot).unwrap(),
+            Err(msg) => eprintln!("Error: {}", msg),
+        }
+    } else {
+        #[cfg(debug_assertions)]
+        eprintln!("{}", chunk.disassemble(file));
+        vm::interpret(chunk);
+    }
+}
+
+/// Compile a script, or read it from bytecode or assembly
+fn load(file: &str, options: Options) -> Option<Chunk> {
+    let loaded = if file.ends_with(".loxc") {
+        vm::bytecode::load(&fs::read(file).unwrap()).map_err(|msg| format!("Invalid bytecode file, {}", msg))
+    } else if file.ends_with(".loxasm") {
+        vm::assembler::assemble(&fs::read_to_string(file).unwrap()).map_err(|msg| format!("Invalid assembly, {}", msg))
     } else {
-        repl::repl();
+        // The compiler reports its own errors
+        return compile(fs::read_to_string(file).unwrap(), options).ok();
+    };
+    match loaded {
+        Ok(chunk) => Some(chunk),
+        Err(msg) => {
+            eprintln!("Error: {}", msg);
+            None
+        },
     }
 }
@@ -10,6 +10,14 @@ pub struct Scanner {
 	pub source: String
 }
 
+/// A saved position of the scanner, used for looking ahead
+#[derive(Copy, Clone)]
+pub struct ScannerState {
+	start: usize,
+	current: usize,
+	line: u32,
+}
+
 impl Scanner {
 	pub fn new(source: String) -> Scanner {
 		Scanner {
@@ -20,6 +28,20 @@ impl Scanner {
 		}
 	}
 
+	pub fn save(&self) -> ScannerState {
+		ScannerState {
+			start: self.start,
+			current: self.current,
+			line: self.line
+		}
+	}
+
+	pub fn restore(&mut self, state: ScannerState) {
+		self.start = state.start;
+		self.current = state.current;
+		self.line = state.line;
+	}
+
 	/// Not used
 	#[allow(dead_code)]
 	pub fn scan_all(&mut self) -> Vec<TokenResult> {
@@ -122,7 +144,7 @@ impl Scanner {
 		// Glorious identifier loop
 		if character.is_alphabetic() || character == '_' {
 			loop {
-				if self.peek() == None || !self.peek().unwrap().is_alphabetic() && self.peek().unwrap() != '_' {
+				if self.peek() == None || !self.peek().unwrap().is_alphanumeric() && self.peek().unwrap() != '_' {
 					//Yes bob I know trie's are faster
 					//But guess what, this isn't C and you aren't my dad
 					let string = &self.source[self.start..self.current];
@@ -148,6 +170,9 @@ impl Scanner {
 						"goto" => token!(GOTO),
 						"label" => token!(LABEL),
 						"continue" => token!(CONTINUE),
+						"yield" => token!(YIELD),
+						"enum" => token!(ENUM),
+						"match" => token!(MATCH),
 						_ => token!(IDENTIFIER)
 					}
 				}
@@ -162,19 +187,30 @@ impl Scanner {
 			')' => token!(RIGHT_PAREN),
 			'{' => token!(LEFT_BRACE),
 			'}' => token!(RIGHT_BRACE),
+			'[' => token!(LEFT_BRACKET),
+			']' => token!(RIGHT_BRACKET),
 			';' => token!(SEMICOLON),
 			',' => token!(COMMA),
-			'.' => token!(DOT),
+			'.' => if self.source[self.current..].starts_with("..") {self.advance(); self.advance(); token!(ELLIPSIS)} else {token!(DOT)},
 			'-' => token!(MINUS),
 			'+' => token!(PLUS),
 			'/' => token!(SLASH),
 			'*' => token!(ASTERISK),
-			'?' => token!(QUESTION),
+			'?' => match self.peek() {
+				Some('?') => {self.advance(); token!(QUESTION_QUESTION)},
+				// Not a ternary followed by a number like `.5`
+				Some('.') if !self.source[self.current+1..].starts_with(|c: char| c.is_ascii_digit()) => {self.advance(); token!(QUESTION_DOT)},
+				_ => token!(QUESTION)
+			},
 			':' => token!(COLON),
 			'|' => if self.peek() == Some('|') {self.advance(); token!(OR)} else {token!(PIPE)},
 			'&' => if self.peek() == Some('&') {self.advance(); token!(AND)} else {token!(AMPERSAND)},
 			'!' => if self.peek() == Some('=') {self.advance(); token!(BANG_EQUAL)} else {token!(BANG)},
-			'=' => if self.peek() == Some('=') {self.advance(); token!(EQUAL_EQUAL)} else {token!(EQUAL)},
+			'=' => match self.peek() {
+				Some('=') => {self.advance(); token!(EQUAL_EQUAL)},
+				Some('>') => {self.advance(); token!(ARROW)},
+				_ => token!(EQUAL)
+			},
 			'<' => if self.peek() == Some('=') {self.advance(); token!(LESS_EQUAL)} else {token!(LESS)},
 			'>' => if self.peek() == Some('=') {self.advance(); token!(GREATER_EQUAL)} else {token!(GREATER)},
 			'"' => if self.consume_till('"') {token!(STRING)} else {error!("non-terminated string")},
@@ -6,17 +6,20 @@ pub enum TokenType {
 	// Single-character tokens.
 	LEFT_PAREN = 0, RIGHT_PAREN,
 	LEFT_BRACE, RIGHT_BRACE,
+	LEFT_BRACKET, RIGHT_BRACKET,
 	COMMA, DOT, MINUS, PLUS,
 	SEMICOLON, SLASH, ASTERISK,
 	QUESTION, COLON,
   
 	// One or two character tokens.
 	BANG, BANG_EQUAL,
-	EQUAL, EQUAL_EQUAL,
+	EQUAL, EQUAL_EQUAL, ARROW,
+	QUESTION_QUESTION, QUESTION_DOT,
 	GREATER, GREATER_EQUAL,
 	LESS, LESS_EQUAL,
 	AMPERSAND,// AMPERSAND_AMPERSAND,
 	PIPE,// PIPE_PIPE,
+	ELLIPSIS,
   
 	// Literals.
 	IDENTIFIER, STRING, NUMBER, CHAR,
@@ -26,7 +29,8 @@ pub enum TokenType {
 	FOR, FUN, IF, NIL, OR,
 	PRINT, RETURN, SUPER, THIS,
 	TRUE, VAR, CONST, WHILE,
-	LABEL, GOTO, CONTINUE,
+	LABEL, GOTO, CONTINUE, YIELD,
+	ENUM, MATCH,
   
 	EOF
 }
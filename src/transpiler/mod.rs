@@ -0,0 +1,336 @@
+use std::fmt::Write;
+use std::collections::HashSet;
+use crate::vm::chunk::{Chunk, Instruction};
+use crate::vm::op_codes::*;
+use crate::vm::value::Value;
+use crate::vm::function::Function;
+use crate::vm::enums::Enum;
+use crate::vm::verifier;
+
+/*
+A program is compiled to C from its finished chunk, so it gets the same optimizations as in the VM.
+Every Lox function becomes a C function with a statement for every instruction,
+jumps become gotos to a label on the instruction they go to.
+Calls recurse into the C function of the callee, with the values on a stack like the VM has.
+A generator function starts with a switch that goes back to where it last yielded.
+*/
+
+/// The C code that every program starts with
+const RUNTIME: &str = include_str!("runtime.c");
+
+/// A function to compile, the script is the one without a Function
+struct Unit<'a> {
+	chunk: &'a Chunk,
+	function: Option<&'a Function>,
+}
+
+/// Compile a chunk to a C program with the same output as running it
+pub fn transpile(chunk: &Chunk) -> Result<String, String> {
+	// The generated code trusts the chunk like the VM does
+	verifier::verify(chunk).map_err(|error| format!("Invalid bytecode, {}", error))?;
+
+	let mut units = vec![Unit {chunk, function: None}];
+	collect_functions(chunk, &mut units);
+
+	let mut c = String::from(RUNTIME);
+	c.push('\n');
+	for id in 0..units.len() {
+		writeln!(c, "static int f{}(void);", id).unwrap();
+	}
+	c.push('\n');
+	for (id, unit) in units.iter().enumerate() {
+		write_proto(&mut c, id, unit);
+		write_enums(&mut c, id, unit.chunk);
+		writeln!(c, "static Value k{}[{}];", id, unit.chunk.constants.len().max(1)).unwrap();
+		c.push('\n');
+	}
+
+	c.push_str("static void init_constants(void) {\n");
+	for (id, unit) in units.iter().enumerate() {
+		for (index, constant) in unit.chunk.constants.iter().enumerate() {
+			let value = match constant {
+				Value::NUMBER(number) => format!("number_from_bits({:#x}ull)", number.to_bits()),
+				Value::STRING(string) => format!("OBJ_VAL(new_string({}, {}))", c_string(string), string.len()),
+				Value::CHAR(char) => format!("CHAR_VAL({:#x})", *char as u32),
+				Value::BOOL(bool) => format!("BOOL_VAL({})", bool),
+				Value::NATIVE(native) => format!("NATIVE_VAL(find_native({}))", c_string(native.name)),
+				Value::ENUM(_) => format!("ENUM_VAL(&enum_{}_{})", id, index),
+				// Functions are only used by CLOSURE, which refers to their proto
+				Value::NIL | Value::FUNCTION(_) => continue,
+				_ => return Err(format!("Can't compile constant {} to C", constant)),
+			};
+			writeln!(c, "\tk{}[{}] = {};", id, index, value).unwrap();
+		}
+	}
+	c.push_str("}\n\nstatic void mark_constants(void) {\n");
+	for (id, unit) in units.iter().enumerate() {
+		writeln!(c, "\tmark_values(k{}, {});", id, unit.chunk.constants.len()).unwrap();
+	}
+	c.push_str("}\n");
+
+	for (id, unit) in units.iter().enumerate() {
+		c.push('\n');
+		write_function(&mut c, id, unit, &units)?;
+	}
+
+	c.push_str("\nint main(void) {\n\tinit_constants();\n\trun(&proto_0);\n\treturn 0;\n}\n");
+	Ok(c)
+}
+
+/// Find the functions in the constants of a chunk, and in theirs
+fn collect_functions<'a>(chunk: &'a Chunk, units: &mut Vec<Unit<'a>>) {
+	for constant in &chunk.constants {
+		if let Value::FUNCTION(function) = constant {
+			units.push(Unit {chunk: &function.chunk, function: Some(function)});
+			collect_functions(&function.chunk, units);
+		}
+	}
+}
+
+fn write_proto(c: &mut String, id: usize, unit: &Unit) {
+	let Some(function) = unit.function else {
+		writeln!(c, "static const Proto proto_{} = {{NULL, 0, 0, NULL, false, 0, false, f{}}};", id, id).unwrap();
+		return;
+	};
+	let params = if function.params.is_empty() {
+		"NULL".to_owned()
+	} else {
+		let names: Vec<String> = function.params.iter().map(|param| c_string(&param.name)).collect();
+		writeln!(c, "static const char *const params_{}[] = {{{}}};", id, names.join(", ")).unwrap();
+		format!("params_{}", id)
+	};
+	writeln!(c, "static const Proto proto_{} = {{{}, {}, {}, {}, {}, {}, {}, f{}}};",
+		id,
+		function.name.as_deref().map(c_string).unwrap_or_else(|| "NULL".to_owned()),
+		function.arity,
+		function.required,
+		params,
+		function.rest.is_some(),
+		function.upvalue_count,
+		function.generator,
+		id,
+	).unwrap();
+}
+
+/// Enums are static data, named after the function and constant index
+fn write_enums(c: &mut String, id: usize, chunk: &Chunk) {
+	for (index, constant) in chunk.constants.iter().enumerate() {
+		let Value::ENUM(enumeration) = constant else {
+			continue;
+		};
+		let Enum {name, variants} = &**enumeration;
+		let mut definitions = vec![];
+		for (i, variant) in variants.iter().enumerate() {
+			let fields = if variant.fields.is_empty() {
+				"NULL".to_owned()
+			} else {
+				let fields: Vec<String> = variant.fields.iter().map(|field| c_string(field)).collect();
+				writeln!(c, "static const char *const fields_{}_{}_{}[] = {{{}}};", id, index, i, fields.join(", ")).unwrap();
+				format!("fields_{}_{}_{}", id, index, i)
+			};
+			definitions.push(format!("{{{}, {}, {}}}", c_string(&variant.name), variant.fields.len(), fields));
+		}
+		writeln!(c, "static const VariantDef variants_{}_{}[] = {{{}}};", id, index, definitions.join(", ")).unwrap();
+		writeln!(c, "static const EnumDef enum_{}_{} = {{{}, {}, variants_{}_{}}};", id, index, c_string(name), variants.len(), id, index).unwrap();
+	}
+}
+
+fn write_function(c: &mut String, id: usize, unit: &Unit, units: &[Unit]) -> Result<(), String> {
+	let instructions = unit.chunk.decode();
+	let resume_points: Vec<usize> = instructions.iter().enumerate()
+		.filter(|(_, instruction)| instruction.op == YIELD)
+		.map(|(i, _)| i + 1)
+		.collect();
+	let mut labels: HashSet<usize> = instructions.iter().filter_map(|instruction| instruction.target).collect();
+	labels.extend(&resume_points);
+
+	let name = match unit.function {
+		Some(function) => function.signature(),
+		None => "script".to_owned(),
+	};
+	writeln!(c, "// {}\nstatic int f{}(void) {{\n\tENTER(k{});", name.replace('\n', " "), id, id).unwrap();
+	if !resume_points.is_empty() {
+		c.push_str("\tswitch (frame->ip) {\n");
+		for point in &resume_points {
+			writeln!(c, "\t\tcase {}: goto l{};", point, point).unwrap();
+		}
+		c.push_str("\t}\n");
+	}
+	for (i, instruction) in instructions.iter().enumerate() {
+		if labels.contains(&i) {
+			writeln!(c, "l{}:;", i).unwrap();
+		}
+		let statement = statement(instruction, i, unit.chunk, units)?;
+		writeln!(c, "\t{}", statement).unwrap();
+	}
+	c.push_str("}\n");
+	Ok(())
+}
+
+/// The C statement for an instruction
+fn statement(instruction: &Instruction, i: usize, chunk: &Chunk, units: &[Unit]) -> Result<String, String> {
+	let operands = &instruction.operands;
+	let line = instruction.line;
+	let operand = |n: usize| operands[n] as usize;
+	// The long ops have their index in two bytes
+	let index = || if operands.len() == 2 {operand(0) << 8 | operand(1)} else {operand(0)};
+	let target = || format!("goto l{};", instruction.target.unwrap());
+	// The long ops have every name in two bytes
+	let names = |operands: &[u8], long: bool| if long {
+		operands.chunks(2).map(|pair| (pair[0] as usize) << 8 | pair[1] as usize).map(|index| index.to_string()).collect::<Vec<String>>().join(", ")
+	} else {
+		operands.iter().map(u8::to_string).collect::<Vec<String>>().join(", ")
+	};
+
+	Ok(match instruction.op {
+		RETURN => "RETURN();".to_owned(),
+		YIELD => format!("YIELD({});", i + 1),
+		CONSTANT | CONSTANTLONG => format!("PUSH(k[{}]);", index()),
+		NEGATE => format!("NEGATE({});", line),
+		ADD => format!("ADD({});", line),
+		SUBTRACT => format!("ARITHMETIC(-, {});", line),
+		MULTIPLY => format!("ARITHMETIC(*, {});", line),
+		DIVIDE => format!("ARITHMETIC(/, {});", line),
+		GREATER => format!("COMPARE(>, false, {});", line),
+		LESS => format!("COMPARE(<, false, {});", line),
+		GREATEREQUAL => format!("COMPARE(<, true, {});", line),
+		LESSEQUAL => format!("COMPARE(>, true, {});", line),
+		INCRLOCAL => format!("slots[{0}] = add_values(slots[{0}], k[{1}], {2});", operand(0), operand(1), line),
+		ADDLOCALCONST => format!("PUSH(add_values(slots[{}], k[{}], {}));", operand(0), operand(1), line),
+		SUBTRACTLOCALCONST => format!("SUBTRACTLOCALCONST({}, {}, {});", operand(0), operand(1), line),
+		LESSLOCALSJUMP => format!("if (!less(slots[{}], slots[{}], {})) {}", operand(0), operand(1), line, target()),
+		LESSLOCALCONSTJUMP => format!("if (!less(slots[{}], k[{}], {})) {}", operand(0), operand(1), line, target()),
+		MOVEREG => format!("slots[{}] = slots[{}];", operand(0), operand(1)),
+		LOADREG => format!("slots[{}] = k[{}];", operand(0), operand(1)),
+		ADDREG => format!("slots[{}] = add_values(slots[{}], slots[{}], {});", operand(0), operand(1), operand(2), line),
+		ADDREGCONST => format!("slots[{}] = add_values(slots[{}], k[{}], {});", operand(0), operand(1), operand(2), line),
+		SUBTRACTREG | MULTIPLYREG | DIVIDEREG | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST => {
+			let op = match instruction.op {
+				SUBTRACTREG | SUBTRACTREGCONST => "-",
+				MULTIPLYREG | MULTIPLYREGCONST => "*",
+				_ => "/",
+			};
+			let second = match instruction.op {
+				SUBTRACTREG | MULTIPLYREG | DIVIDEREG => "slots",
+				_ => "k",
+			};
+			format!("ARITHMETIC_TO(slots[{}], slots[{}], {}[{}], {}, {});", operand(0), operand(1), second, operand(2), op, line)
+		},
+		NIL => "PUSH(NIL_VAL);".to_owned(),
+		TRUE => "PUSH(BOOL_VAL(true));".to_owned(),
+		FALSE => "PUSH(BOOL_VAL(false));".to_owned(),
+		NOT => "NOT();".to_owned(),
+		EQUAL => "EQUAL(true);".to_owned(),
+		NOTEQUAL => "EQUAL(false);".to_owned(),
+		PRINT => "print_value(*--top);".to_owned(),
+		POP => "top--;".to_owned(),
+		DUP => "PUSH(PEEK(0));".to_owned(),
+		BUILDLIST => format!("build_list({});", operand(0)),
+		BUILDMAP => format!("build_map({});", operand(0)),
+		UNPACKLIST => format!("unpack_list({}, {}, {});", operand(0), operand(1) == 1, line),
+		UNPACKMAP | UNPACKMAPLONG => format!("{{static const uint16_t keys[] = {{{}}}; unpack_map({}, keys, k, {});}}", names(&operands[1..], instruction.op == UNPACKMAPLONG), operand(0), line),
+		DEFGLOBAL | DEFGLOBALLONG => format!("define_global({}, *--top);", index()),
+		GETGLOBAL | GETGLOBALLONG => format!("PUSH(get_global({}, {}));", index(), line),
+		SETGLOBAL | SETGLOBALLONG => format!("define_global({}, PEEK(0));", index()),
+		GETLOCAL | GETLOCALLONG => format!("PUSH(slots[{}]);", index()),
+		SETLOCAL | SETLOCALLONG => format!("slots[{}] = PEEK(0);", index()),
+		JUMPIFFALSE => format!("if (!is_truthy(PEEK(0))) {}", target()),
+		JUMPIFFALSEPOP => format!("if (!is_truthy(*--top)) {}", target()),
+		JUMPIFNIL => format!("if (IS_NIL(PEEK(0))) {}", target()),
+		JUMPIFNOTNIL => format!("if (!IS_NIL(PEEK(0))) {}", target()),
+		JUMPIFPASSED => format!("if (!IS_MISSING(PEEK(0))) {}", target()),
+		JUMP => target(),
+		GETPROPERTY | GETPROPERTYLONG => format!("get_property(k[{}], {});", index(), line),
+		MATCH => "match();".to_owned(),
+		UNPACKVARIANT => format!("unpack_variant({}, {});", operand(0), line),
+		NOMATCH => format!("runtime_error({}, \"No match arm for %s\", to_string(*--top));", line),
+		LEAVE => format!("leave({});", operand(0)),
+		CALL => format!("CALL({}, {});", operand(0), line),
+		TAILCALL => format!("TAILCALL({}, {});", operand(0), line),
+		CALLNAMED | CALLNAMEDLONG => format!("{{static const uint16_t names[] = {{{}}}; CALLNAMED({}, {}, names, {});}}", names(&operands[2..], instruction.op == CALLNAMEDLONG), operand(0), operand(1), line),
+		CLOSURE | CLOSURELONG => {
+			let long = instruction.op == CLOSURELONG;
+			let (index, captures) = if long {(operand(0) << 8 | operand(1), &operands[2..])} else {(operand(0), &operands[1..])};
+			let Value::FUNCTION(function) = &chunk.constants[index] else {
+				unreachable!()
+			};
+			let proto = units.iter().position(|unit| std::ptr::eq(unit.chunk, &function.chunk)).unwrap();
+			if captures.is_empty() {
+				format!("make_closure(&proto_{}, NULL);", proto)
+			} else {
+				let captures: Vec<String> = captures.chunks(3)
+					.map(|capture| format!("{{{}, {}}}", capture[0] == 1, (capture[1] as usize) << 8 | capture[2] as usize))
+					.collect();
+				format!("{{static const Capture captures[] = {{{}}}; make_closure(&proto_{}, captures);}}", captures.join(", "), proto)
+			}
+		},
+		GETUPVALUE => format!("PUSH(get_upvalue(frame->closure->upvalues[{}]));", operand(0)),
+		SETUPVALUE => format!("set_upvalue(frame->closure->upvalues[{}], PEEK(0));", operand(0)),
+		BINARYREG => {
+			let (a, b) = (source(instruction, 0), source(instruction, 1));
+			match operand(0) as OpCode {
+				ADD => format!("TO_REGISTER({}, add_values({}, {}, {}));", operand(1), a, b, line),
+				op @ (SUBTRACT | MULTIPLY | DIVIDE) => {
+					let op = match op {
+						SUBTRACT => "-",
+						MULTIPLY => "*",
+						_ => "/",
+					};
+					format!("{{Value result_; ARITHMETIC_TO(result_, {}, {}, {}, {}); TO_REGISTER({}, result_);}}", a, b, op, line, operand(1))
+				},
+				op => format!("TO_REGISTER({}, BOOL_VAL({}));", operand(1), comparison(op, &a, &b, line)),
+			}
+		},
+		UNARYREG => {
+			let value = source(instruction, 0);
+			if operand(0) as OpCode == NOT {
+				format!("TO_REGISTER({}, BOOL_VAL(!is_truthy({})));", operand(1), value)
+			} else {
+				format!("{{Value operand_ = {}; if (!IS_NUMBER(operand_)) runtime_error({}, \"Operand must be a number\"); TO_REGISTER({}, NUMBER_VAL(-AS_NUMBER(operand_)));}}", value, line, operand(1))
+			}
+		},
+		RETURNREG => format!("PUSH({}); RETURN();", source(instruction, 0)),
+		JUMPIFFALSEREG => format!("if (!is_truthy(slots[{}])) {}", operand(0), target()),
+		JUMPUNLESSREG => format!("if (!({})) {}", comparison(operand(0) as OpCode, &format!("slots[{}]", operand(1)), &format!("slots[{}]", operand(2)), line), target()),
+		JUMPUNLESSREGCONST => format!("if (!({})) {}", comparison(operand(0) as OpCode, &format!("slots[{}]", operand(1)), &format!("k[{}]", operand(2)), line), target()),
+		op => return Err(format!("Can't compile {} to C", name(op).unwrap_or("unknown op"))),
+	})
+}
+
+/// A source of a register op, as a register in the slots or a constant
+fn source(instruction: &Instruction, n: usize) -> String {
+	let operands = &instruction.operands;
+	let (kinds, sources) = operands[operands.len() - sources(instruction.op) - 1..].split_first().unwrap();
+	if is_constant(*kinds, n) {
+		format!("k[{}]", sources[n])
+	} else {
+		format!("slots[{}]", sources[n])
+	}
+}
+
+/// A comparison of two values as a C condition.
+/// Greater is less with the values swapped and the negated forms are for >= and <=, which are the negation of the opposite
+fn comparison(op: OpCode, a: &str, b: &str, line: u32) -> String {
+	match op {
+		EQUAL => format!("values_equal({}, {})", a, b),
+		NOTEQUAL => format!("!values_equal({}, {})", a, b),
+		LESS => format!("less({}, {}, {})", a, b, line),
+		GREATER => format!("less({}, {}, {})", b, a, line),
+		GREATEREQUAL => format!("!less({}, {}, {})", a, b, line),
+		_ => format!("!less({}, {}, {})", b, a, line),
+	}
+}
+
+/// A C string literal, everything but letters and digits is escaped
+fn c_string(string: &str) -> String {
+	let mut literal = String::from("\"");
+	for byte in string.bytes() {
+		if byte.is_ascii_alphanumeric() || byte == b' ' || byte == b'_' {
+			literal.push(byte as char);
+		} else {
+			write!(literal, "\\{:03o}", byte).unwrap();
+		}
+	}
+	literal.push('"');
+	literal
+}
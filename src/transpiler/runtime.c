@@ -0,0 +1,1354 @@
+/*
+The runtime of a program made by `loxidation build`.
+The generated code comes after this, it has a C function for every Lox function
+and uses the macros and functions here for the ops.
+Values behave like they do in the VM and errors are reported the same way.
+Objects are freed by a mark and sweep collector instead of reference counting,
+there is nothing in Lox that can tell the difference.
+*/
+
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <stdarg.h>
+#include <math.h>
+#include <time.h>
+
+#define STACK_MAX (256 * 1024)
+#define FRAMES_MAX 64
+
+#define BINARY_OPERANDS "Binary operands must both be numbers or both be strings"
+
+typedef struct Obj Obj;
+typedef struct EnumDef EnumDef;
+typedef struct Native Native;
+
+/// VAL_MISSING is an argument that wasn't passed, until the function gives it its default value
+typedef enum {VAL_NIL, VAL_BOOL, VAL_NUMBER, VAL_CHAR, VAL_ENUM, VAL_NATIVE, VAL_OBJ, VAL_MISSING} ValueType;
+
+typedef struct {
+	ValueType type;
+	union {
+		bool boolean;
+		double number;
+		uint32_t character;
+		const EnumDef *enumeration;
+		const Native *native;
+		Obj *obj;
+	} as;
+} Value;
+
+typedef enum {OBJ_STRING, OBJ_CLOSURE, OBJ_UPVALUE, OBJ_GENERATOR, OBJ_LIST, OBJ_MAP, OBJ_VARIANT} ObjType;
+
+struct Obj {
+	ObjType type;
+	bool marked;
+	size_t size;
+	Obj *next;
+};
+
+typedef struct {
+	Obj obj;
+	size_t length;
+	char chars[];
+} ObjString;
+
+/// A compiled function, these are static data in the generated code
+typedef struct {
+	/// NULL for anonymous functions
+	const char *name;
+	int arity;
+	int required;
+	const char *const *params;
+	bool rest;
+	int upvalue_count;
+	bool generator;
+	int (*code)(void);
+} Proto;
+
+/// An upvalue points into the stack until the variable goes out of scope
+typedef struct {
+	Obj obj;
+	bool open;
+	size_t slot;
+	Value closed;
+} ObjUpvalue;
+
+typedef struct {
+	Obj obj;
+	const Proto *proto;
+	ObjUpvalue *upvalues[];
+} ObjClosure;
+
+typedef enum {GENERATOR_CREATED, GENERATOR_SUSPENDED, GENERATOR_RUNNING, GENERATOR_DONE} GeneratorState;
+
+/// An upvalue of a suspended generator, with its offset from the first local
+typedef struct {
+	ObjUpvalue *upvalue;
+	size_t offset;
+} Suspended;
+
+typedef struct {
+	Obj obj;
+	ObjClosure *closure;
+	/// Where to continue, this is a resume point of the generated code
+	int ip;
+	GeneratorState state;
+	Value *stack;
+	size_t stack_count;
+	Suspended *upvalues;
+	size_t upvalue_count;
+} ObjGenerator;
+
+typedef struct {
+	Obj obj;
+	size_t count;
+	Value items[];
+} ObjList;
+
+typedef struct {
+	ObjString *key;
+	Value value;
+} Entry;
+
+typedef struct {
+	Obj obj;
+	size_t count;
+	Entry entries[];
+} ObjMap;
+
+typedef struct {
+	const char *name;
+	int field_count;
+	const char *const *fields;
+} VariantDef;
+
+/// Enums are constants, so they are static data like functions
+struct EnumDef {
+	const char *name;
+	int variant_count;
+	const VariantDef *variants;
+};
+
+/// A variant with fields but without a payload is the constructor of that variant
+typedef struct {
+	Obj obj;
+	const EnumDef *enumeration;
+	int variant;
+	size_t count;
+	Value payload[];
+} ObjVariant;
+
+struct Native {
+	const char *name;
+	int arity;
+	Value (*function)(Value *args);
+};
+
+/// An upvalue a closure captures, from the function creating it
+typedef struct {
+	bool is_local;
+	int index;
+} Capture;
+
+/// A call of a function that hasn't returned yet
+typedef struct {
+	ObjClosure *closure;
+	size_t slots;
+	/// Where a generator continues
+	int ip;
+	/// The line of the last call made from this frame, for tracebacks
+	int line;
+	/// Calls replaced by tail calls since this function was called
+	int elided;
+} Frame;
+
+/// What the code of a function returns to the function running it
+enum {RETURNED, TAIL};
+
+#define NIL_VAL ((Value){VAL_NIL, {.number = 0}})
+#define MISSING_VAL ((Value){VAL_MISSING, {.number = 0}})
+#define BOOL_VAL(value) ((Value){VAL_BOOL, {.boolean = (value)}})
+#define NUMBER_VAL(value) ((Value){VAL_NUMBER, {.number = (value)}})
+#define CHAR_VAL(value) ((Value){VAL_CHAR, {.character = (value)}})
+#define ENUM_VAL(value) ((Value){VAL_ENUM, {.enumeration = (value)}})
+#define NATIVE_VAL(value) ((Value){VAL_NATIVE, {.native = (value)}})
+#define OBJ_VAL(value) ((Value){VAL_OBJ, {.obj = (Obj *)(value)}})
+
+#define IS_NIL(value) ((value).type == VAL_NIL)
+#define IS_MISSING(value) ((value).type == VAL_MISSING)
+#define IS_NUMBER(value) ((value).type == VAL_NUMBER)
+#define IS_OBJ(value, kind) ((value).type == VAL_OBJ && (value).as.obj->type == (kind))
+#define AS_NUMBER(value) ((value).as.number)
+#define AS_STRING(value) ((ObjString *)(value).as.obj)
+#define AS_CLOSURE(value) ((ObjClosure *)(value).as.obj)
+#define AS_GENERATOR(value) ((ObjGenerator *)(value).as.obj)
+#define AS_LIST(value) ((ObjList *)(value).as.obj)
+#define AS_MAP(value) ((ObjMap *)(value).as.obj)
+#define AS_VARIANT(value) ((ObjVariant *)(value).as.obj)
+
+static Value stack[STACK_MAX];
+static Value *top = stack;
+static Frame frames[FRAMES_MAX];
+static int frame_count = 0;
+static Value *globals = NULL;
+/// Globals that are only used by functions so far aren't defined yet
+static bool *defined_globals = NULL;
+static size_t global_count = 0;
+static ObjUpvalue **open_upvalues = NULL;
+static size_t open_count = 0;
+static size_t open_capacity = 0;
+
+static Obj *objects = NULL;
+static size_t bytes_allocated = 0;
+static size_t next_gc = 1024 * 1024;
+static Obj **gray = NULL;
+static size_t gray_count = 0;
+static size_t gray_capacity = 0;
+
+/// Defined by the generated code, marks the constants of every function
+static void mark_constants(void);
+
+//#region Memory
+
+static void *reallocate(void *pointer, size_t size) {
+	void *result = realloc(pointer, size);
+	if (result == NULL && size > 0) {
+		fprintf(stderr, "Error: Out of memory\n");
+		exit(70);
+	}
+	return result;
+}
+
+static void mark_object(Obj *object) {
+	if (object == NULL || object->marked) {
+		return;
+	}
+	object->marked = true;
+	if (gray_count == gray_capacity) {
+		gray_capacity = gray_capacity < 64 ? 64 : gray_capacity * 2;
+		gray = reallocate(gray, gray_capacity * sizeof(Obj *));
+	}
+	gray[gray_count++] = object;
+}
+
+static void mark_value(Value value) {
+	if (value.type == VAL_OBJ) {
+		mark_object(value.as.obj);
+	}
+}
+
+static void mark_values(Value *values, size_t count) {
+	for (size_t i = 0; i < count; i++) {
+		mark_value(values[i]);
+	}
+}
+
+/// Mark everything an object refers to
+static void blacken(Obj *object) {
+	switch (object->type) {
+		case OBJ_STRING:
+			break;
+		case OBJ_CLOSURE: {
+			ObjClosure *closure = (ObjClosure *)object;
+			// Upvalues are NULL while the closure is being made
+			for (int i = 0; i < closure->proto->upvalue_count; i++) {
+				mark_object((Obj *)closure->upvalues[i]);
+			}
+			break;
+		}
+		case OBJ_UPVALUE: {
+			ObjUpvalue *upvalue = (ObjUpvalue *)object;
+			if (!upvalue->open) {
+				mark_value(upvalue->closed);
+			}
+			break;
+		}
+		case OBJ_GENERATOR: {
+			ObjGenerator *generator = (ObjGenerator *)object;
+			mark_object((Obj *)generator->closure);
+			mark_values(generator->stack, generator->stack_count);
+			for (size_t i = 0; i < generator->upvalue_count; i++) {
+				mark_object((Obj *)generator->upvalues[i].upvalue);
+			}
+			break;
+		}
+		case OBJ_LIST: {
+			ObjList *list = (ObjList *)object;
+			mark_values(list->items, list->count);
+			break;
+		}
+		case OBJ_MAP: {
+			ObjMap *map = (ObjMap *)object;
+			for (size_t i = 0; i < map->count; i++) {
+				mark_object((Obj *)map->entries[i].key);
+				mark_value(map->entries[i].value);
+			}
+			break;
+		}
+		case OBJ_VARIANT: {
+			ObjVariant *variant = (ObjVariant *)object;
+			mark_values(variant->payload, variant->count);
+			break;
+		}
+	}
+}
+
+static void free_object(Obj *object) {
+	if (object->type == OBJ_GENERATOR) {
+		ObjGenerator *generator = (ObjGenerator *)object;
+		free(generator->stack);
+		free(generator->upvalues);
+	}
+	bytes_allocated -= object->size;
+	free(object);
+}
+
+static void collect(void) {
+	mark_values(stack, top - stack);
+	mark_values(globals, global_count);
+	for (int i = 0; i < frame_count; i++) {
+		mark_object((Obj *)frames[i].closure);
+	}
+	for (size_t i = 0; i < open_count; i++) {
+		mark_object((Obj *)open_upvalues[i]);
+	}
+	mark_constants();
+	while (gray_count > 0) {
+		blacken(gray[--gray_count]);
+	}
+
+	Obj **object = &objects;
+	while (*object != NULL) {
+		if ((*object)->marked) {
+			(*object)->marked = false;
+			object = &(*object)->next;
+		} else {
+			Obj *unreached = *object;
+			*object = unreached->next;
+			free_object(unreached);
+		}
+	}
+	next_gc = bytes_allocated * 2 < 1024 * 1024 ? 1024 * 1024 : bytes_allocated * 2;
+}
+
+/// Everything an object refers to has to be reachable while allocating,
+/// as this can run the collector
+static Obj *allocate(size_t size, ObjType type) {
+	if (bytes_allocated > next_gc) {
+		collect();
+	}
+	Obj *object = reallocate(NULL, size);
+	object->type = type;
+	object->marked = false;
+	object->size = size;
+	object->next = objects;
+	objects = object;
+	bytes_allocated += size;
+	return object;
+}
+
+static ObjString *new_string(const char *chars, size_t length) {
+	ObjString *string = (ObjString *)allocate(sizeof(ObjString) + length + 1, OBJ_STRING);
+	string->length = length;
+	memcpy(string->chars, chars, length);
+	string->chars[length] = '\0';
+	return string;
+}
+
+static ObjList *new_list(size_t count) {
+	ObjList *list = (ObjList *)allocate(sizeof(ObjList) + count * sizeof(Value), OBJ_LIST);
+	list->count = count;
+	return list;
+}
+
+static ObjVariant *new_variant(const EnumDef *enumeration, int variant, size_t count) {
+	ObjVariant *value = (ObjVariant *)allocate(sizeof(ObjVariant) + count * sizeof(Value), OBJ_VARIANT);
+	value->enumeration = enumeration;
+	value->variant = variant;
+	value->count = count;
+	return value;
+}
+
+//#endregion
+
+//#region Values
+
+static Value number_from_bits(uint64_t bits) {
+	double number;
+	memcpy(&number, &bits, sizeof number);
+	return NUMBER_VAL(number);
+}
+
+static bool is_truthy(Value value) {
+	switch (value.type) {
+		case VAL_NIL: return false;
+		case VAL_BOOL: return value.as.boolean;
+		default: return true;
+	}
+}
+
+static bool is_constructor(ObjVariant *value) {
+	return value->count == 0 && value->enumeration->variants[value->variant].field_count > 0;
+}
+
+static Value *map_get(ObjMap *map, const char *key, size_t length) {
+	for (size_t i = 0; i < map->count; i++) {
+		ObjString *entry = map->entries[i].key;
+		if (entry->length == length && memcmp(entry->chars, key, length) == 0) {
+			return &map->entries[i].value;
+		}
+	}
+	return NULL;
+}
+
+static bool values_equal(Value a, Value b) {
+	if (a.type != b.type) {
+		return false;
+	}
+	switch (a.type) {
+		case VAL_NIL: case VAL_MISSING: return true;
+		case VAL_BOOL: return a.as.boolean == b.as.boolean;
+		case VAL_NUMBER: return a.as.number == b.as.number;
+		case VAL_CHAR: return a.as.character == b.as.character;
+		case VAL_ENUM: return a.as.enumeration == b.as.enumeration;
+		case VAL_NATIVE: return a.as.native == b.as.native;
+		case VAL_OBJ: break;
+	}
+	if (a.as.obj->type != b.as.obj->type) {
+		return false;
+	}
+	switch (a.as.obj->type) {
+		case OBJ_STRING: {
+			ObjString *x = AS_STRING(a), *y = AS_STRING(b);
+			return x->length == y->length && memcmp(x->chars, y->chars, x->length) == 0;
+		}
+		// Lists, maps and enum values are compared by their contents
+		case OBJ_LIST: {
+			ObjList *x = AS_LIST(a), *y = AS_LIST(b);
+			if (x->count != y->count) {
+				return false;
+			}
+			for (size_t i = 0; i < x->count; i++) {
+				if (!values_equal(x->items[i], y->items[i])) {
+					return false;
+				}
+			}
+			return true;
+		}
+		case OBJ_MAP: {
+			ObjMap *x = AS_MAP(a), *y = AS_MAP(b);
+			if (x->count != y->count) {
+				return false;
+			}
+			for (size_t i = 0; i < x->count; i++) {
+				Value *value = map_get(y, x->entries[i].key->chars, x->entries[i].key->length);
+				if (value == NULL || !values_equal(x->entries[i].value, *value)) {
+					return false;
+				}
+			}
+			return true;
+		}
+		case OBJ_VARIANT: {
+			ObjVariant *x = AS_VARIANT(a), *y = AS_VARIANT(b);
+			if (x->enumeration != y->enumeration || x->variant != y->variant || x->count != y->count) {
+				return false;
+			}
+			for (size_t i = 0; i < x->count; i++) {
+				if (!values_equal(x->payload[i], y->payload[i])) {
+					return false;
+				}
+			}
+			return true;
+		}
+		// Functions and generators are only equal to themselves
+		default:
+			return a.as.obj == b.as.obj;
+	}
+}
+
+typedef struct {
+	char *chars;
+	size_t length;
+	size_t capacity;
+} Buffer;
+
+static void append(Buffer *buffer, const char *chars, size_t length) {
+	if (buffer->length + length + 1 > buffer->capacity) {
+		while (buffer->length + length + 1 > buffer->capacity) {
+			buffer->capacity = buffer->capacity < 64 ? 64 : buffer->capacity * 2;
+		}
+		buffer->chars = reallocate(buffer->chars, buffer->capacity);
+	}
+	memcpy(buffer->chars + buffer->length, chars, length);
+	buffer->length += length;
+	buffer->chars[buffer->length] = '\0';
+}
+
+static void append_string(Buffer *buffer, const char *string) {
+	append(buffer, string, strlen(string));
+}
+
+/// Numbers are shown like rust shows them,
+/// the shortest digits that read back as the same number and never with an exponent
+static void append_number(Buffer *buffer, double number) {
+	if (isnan(number)) {
+		append_string(buffer, "NaN");
+		return;
+	}
+	if (isinf(number)) {
+		append_string(buffer, number > 0 ? "inf" : "-inf");
+		return;
+	}
+	if (number == 0) {
+		append_string(buffer, signbit(number) ? "-0" : "0");
+		return;
+	}
+	char scientific[32];
+	for (int precision = 1; precision <= 17; precision++) {
+		snprintf(scientific, sizeof scientific, "%.*e", precision - 1, number);
+		if (strtod(scientific, NULL) == number) {
+			break;
+		}
+	}
+	// Split something like -1.2345e+02 into its sign, digits and exponent
+	char digits[32];
+	int count = 0;
+	char *c = scientific;
+	if (*c == '-') {
+		append_string(buffer, "-");
+		c++;
+	}
+	for (; *c != 'e'; c++) {
+		if (*c != '.') {
+			digits[count++] = *c;
+		}
+	}
+	int point = atoi(c + 1) + 1;
+
+	if (point <= 0) {
+		append_string(buffer, "0.");
+		for (int i = 0; i < -point; i++) {
+			append_string(buffer, "0");
+		}
+		append(buffer, digits, count);
+	} else if (point >= count) {
+		append(buffer, digits, count);
+		for (int i = count; i < point; i++) {
+			append_string(buffer, "0");
+		}
+	} else {
+		append(buffer, digits, point);
+		append_string(buffer, ".");
+		append(buffer, digits + point, count - point);
+	}
+}
+
+static void append_char(Buffer *buffer, uint32_t character) {
+	char bytes[4];
+	size_t length;
+	if (character < 0x80) {
+		bytes[0] = character;
+		length = 1;
+	} else if (character < 0x800) {
+		bytes[0] = 0xc0 | character >> 6;
+		bytes[1] = 0x80 | (character & 0x3f);
+		length = 2;
+	} else if (character < 0x10000) {
+		bytes[0] = 0xe0 | character >> 12;
+		bytes[1] = 0x80 | (character >> 6 & 0x3f);
+		bytes[2] = 0x80 | (character & 0x3f);
+		length = 3;
+	} else {
+		bytes[0] = 0xf0 | character >> 18;
+		bytes[1] = 0x80 | (character >> 12 & 0x3f);
+		bytes[2] = 0x80 | (character >> 6 & 0x3f);
+		bytes[3] = 0x80 | (character & 0x3f);
+		length = 4;
+	}
+	append(buffer, bytes, length);
+}
+
+static void append_value(Buffer *buffer, Value value);
+
+static void append_function(Buffer *buffer, const char *kind, const Proto *proto) {
+	append_string(buffer, "<");
+	append_string(buffer, kind);
+	if (proto->name != NULL) {
+		append_string(buffer, " ");
+		append_string(buffer, proto->name);
+	}
+	append_string(buffer, ">");
+}
+
+static int compare_entries(const void *a, const void *b) {
+	ObjString *x = ((const Entry *)a)->key, *y = ((const Entry *)b)->key;
+	int order = memcmp(x->chars, y->chars, x->length < y->length ? x->length : y->length);
+	if (order != 0) {
+		return order;
+	}
+	return (x->length > y->length) - (x->length < y->length);
+}
+
+static void append_object(Buffer *buffer, Obj *object) {
+	switch (object->type) {
+		case OBJ_STRING: {
+			ObjString *string = (ObjString *)object;
+			append(buffer, string->chars, string->length);
+			break;
+		}
+		case OBJ_CLOSURE:
+			append_function(buffer, "fn", ((ObjClosure *)object)->proto);
+			break;
+		case OBJ_GENERATOR:
+			append_function(buffer, "generator", ((ObjGenerator *)object)->closure->proto);
+			break;
+		case OBJ_LIST: {
+			ObjList *list = (ObjList *)object;
+			append_string(buffer, "[");
+			for (size_t i = 0; i < list->count; i++) {
+				if (i > 0) {
+					append_string(buffer, ", ");
+				}
+				append_value(buffer, list->items[i]);
+			}
+			append_string(buffer, "]");
+			break;
+		}
+		case OBJ_MAP: {
+			// Sorted so the output doesn't depend on the order of the keys
+			ObjMap *map = (ObjMap *)object;
+			Entry *entries = reallocate(NULL, map->count * sizeof(Entry) + 1);
+			memcpy(entries, map->entries, map->count * sizeof(Entry));
+			qsort(entries, map->count, sizeof(Entry), compare_entries);
+			append_string(buffer, "{");
+			for (size_t i = 0; i < map->count; i++) {
+				if (i > 0) {
+					append_string(buffer, ", ");
+				}
+				append(buffer, entries[i].key->chars, entries[i].key->length);
+				append_string(buffer, ": ");
+				append_value(buffer, entries[i].value);
+			}
+			append_string(buffer, "}");
+			free(entries);
+			break;
+		}
+		case OBJ_VARIANT: {
+			ObjVariant *value = (ObjVariant *)object;
+			bool constructor = is_constructor(value);
+			if (constructor) {
+				append_string(buffer, "<constructor ");
+			}
+			append_string(buffer, value->enumeration->name);
+			append_string(buffer, ".");
+			append_string(buffer, value->enumeration->variants[value->variant].name);
+			if (constructor) {
+				append_string(buffer, ">");
+			} else if (value->count > 0) {
+				append_string(buffer, "(");
+				for (size_t i = 0; i < value->count; i++) {
+					if (i > 0) {
+						append_string(buffer, ", ");
+					}
+					append_value(buffer, value->payload[i]);
+				}
+				append_string(buffer, ")");
+			}
+			break;
+		}
+		case OBJ_UPVALUE:
+			break;
+	}
+}
+
+static void append_value(Buffer *buffer, Value value) {
+	switch (value.type) {
+		case VAL_NIL: append_string(buffer, "nil"); break;
+		case VAL_BOOL: append_string(buffer, value.as.boolean ? "true" : "false"); break;
+		case VAL_NUMBER: append_number(buffer, value.as.number); break;
+		case VAL_CHAR: append_char(buffer, value.as.character); break;
+		case VAL_NATIVE: append_string(buffer, "<native fn>"); break;
+		case VAL_ENUM:
+			append_string(buffer, "<enum ");
+			append_string(buffer, value.as.enumeration->name);
+			append_string(buffer, ">");
+			break;
+		case VAL_OBJ: append_object(buffer, value.as.obj); break;
+		case VAL_MISSING: append_string(buffer, "<missing>"); break;
+	}
+}
+
+/// The value as a string for error messages, this is never freed as the program stops
+static const char *to_string(Value value) {
+	Buffer buffer = {NULL, 0, 0};
+	append(&buffer, "", 0);
+	append_value(&buffer, value);
+	return buffer.chars;
+}
+
+static void print_value(Value value) {
+	static Buffer buffer = {NULL, 0, 0};
+	buffer.length = 0;
+	append_value(&buffer, value);
+	append_string(&buffer, "\n");
+	fwrite(buffer.chars, 1, buffer.length, stdout);
+}
+
+//#endregion
+
+//#region Errors
+
+_Noreturn static void runtime_error(int line, const char *format, ...) {
+	fflush(stdout);
+	va_list args;
+	va_start(args, format);
+	fprintf(stderr, "Error at line %d: ", line);
+	vfprintf(stderr, format, args);
+	fputc('\n', stderr);
+	va_end(args);
+
+	// Show where the functions we are in were called from
+	int elided = frames[frame_count - 1].elided;
+	for (int i = frame_count - 2; i >= 0; i--) {
+		if (elided > 0) {
+			fprintf(stderr, "  (%d calls elided by tail calls)\n", elided);
+		}
+		const char *name = frames[i].closure->proto->name;
+		fprintf(stderr, "  called at line %d in %s\n", frames[i].line, name != NULL ? name : "script");
+		elided = frames[i].elided;
+	}
+	exit(70);
+}
+
+//#endregion
+
+//#region Natives
+
+/// Seconds since the epoch, to time things with
+static Value native_clock(Value *args) {
+	(void)args;
+	struct timespec time;
+	timespec_get(&time, TIME_UTC);
+	return NUMBER_VAL(time.tv_sec + time.tv_nsec / 1e9);
+}
+
+static const Native natives[] = {
+	{"clock", 0, native_clock},
+};
+
+static const Native *find_native(const char *name) {
+	for (size_t i = 0; i < sizeof natives / sizeof natives[0]; i++) {
+		if (strcmp(natives[i].name, name) == 0) {
+			return &natives[i];
+		}
+	}
+	return NULL;
+}
+
+//#endregion
+
+//#region Operators
+
+static Value add_values(Value a, Value b, int line) {
+	if (IS_NUMBER(a) && IS_NUMBER(b)) {
+		return NUMBER_VAL(AS_NUMBER(a) + AS_NUMBER(b));
+	}
+	if (!IS_OBJ(a, OBJ_STRING) || !IS_OBJ(b, OBJ_STRING)) {
+		runtime_error(line, BINARY_OPERANDS);
+	}
+	// Both strings are still on the stack or in the constants while allocating
+	ObjString *x = AS_STRING(a), *y = AS_STRING(b);
+	ObjString *string = (ObjString *)allocate(sizeof(ObjString) + x->length + y->length + 1, OBJ_STRING);
+	string->length = x->length + y->length;
+	memcpy(string->chars, x->chars, x->length);
+	memcpy(string->chars + x->length, y->chars, y->length);
+	string->chars[string->length] = '\0';
+	return OBJ_VAL(string);
+}
+
+static bool less(Value a, Value b, int line) {
+	if (!IS_NUMBER(a) || !IS_NUMBER(b)) {
+		runtime_error(line, BINARY_OPERANDS);
+	}
+	return AS_NUMBER(a) < AS_NUMBER(b);
+}
+
+static void check_numbers(Value a, Value b, int line) {
+	if (!IS_NUMBER(a) || !IS_NUMBER(b)) {
+		runtime_error(line, BINARY_OPERANDS);
+	}
+}
+
+//#endregion
+
+//#region Variables
+
+static void define_global(size_t index, Value value) {
+	// Globals aren't always defined in order
+	if (global_count <= index) {
+		globals = reallocate(globals, (index + 1) * sizeof(Value));
+		defined_globals = reallocate(defined_globals, (index + 1) * sizeof(bool));
+		for (size_t i = global_count; i <= index; i++) {
+			globals[i] = NIL_VAL;
+			defined_globals[i] = false;
+		}
+		global_count = index + 1;
+	}
+	globals[index] = value;
+	defined_globals[index] = true;
+}
+
+static Value get_global(size_t index, int line) {
+	// A global can be read before it is defined inside a function
+	if (index >= global_count || !defined_globals[index]) {
+		runtime_error(line, "Undefined variable");
+	}
+	return globals[index];
+}
+
+static ObjUpvalue *capture_upvalue(size_t slot) {
+	for (size_t i = 0; i < open_count; i++) {
+		if (open_upvalues[i]->slot == slot) {
+			return open_upvalues[i];
+		}
+	}
+	ObjUpvalue *upvalue = (ObjUpvalue *)allocate(sizeof(ObjUpvalue), OBJ_UPVALUE);
+	upvalue->open = true;
+	upvalue->slot = slot;
+	upvalue->closed = NIL_VAL;
+	if (open_count == open_capacity) {
+		open_capacity = open_capacity < 8 ? 8 : open_capacity * 2;
+		open_upvalues = reallocate(open_upvalues, open_capacity * sizeof(ObjUpvalue *));
+	}
+	open_upvalues[open_count++] = upvalue;
+	return upvalue;
+}
+
+/// Move all values at or above a stack slot into their upvalues
+static void close_upvalues(size_t from) {
+	size_t kept = 0;
+	for (size_t i = 0; i < open_count; i++) {
+		ObjUpvalue *upvalue = open_upvalues[i];
+		if (upvalue->slot >= from) {
+			upvalue->closed = stack[upvalue->slot];
+			upvalue->open = false;
+		} else {
+			open_upvalues[kept++] = upvalue;
+		}
+	}
+	open_count = kept;
+}
+
+static Value get_upvalue(ObjUpvalue *upvalue) {
+	return upvalue->open ? stack[upvalue->slot] : upvalue->closed;
+}
+
+static void set_upvalue(ObjUpvalue *upvalue, Value value) {
+	if (upvalue->open) {
+		stack[upvalue->slot] = value;
+	} else {
+		upvalue->closed = value;
+	}
+}
+
+static void make_closure(const Proto *proto, const Capture *captures) {
+	Frame *frame = &frames[frame_count - 1];
+	ObjClosure *closure = (ObjClosure *)allocate(sizeof(ObjClosure) + proto->upvalue_count * sizeof(ObjUpvalue *), OBJ_CLOSURE);
+	closure->proto = proto;
+	for (int i = 0; i < proto->upvalue_count; i++) {
+		closure->upvalues[i] = NULL;
+	}
+	// On the stack before capturing, as that can allocate
+	*top++ = OBJ_VAL(closure);
+	for (int i = 0; i < proto->upvalue_count; i++) {
+		if (captures[i].is_local) {
+			closure->upvalues[i] = capture_upvalue(frame->slots + captures[i].index);
+		} else {
+			closure->upvalues[i] = frame->closure->upvalues[captures[i].index];
+		}
+	}
+}
+
+static void leave(size_t n) {
+	size_t length = top - stack - n;
+	close_upvalues(length);
+	top = stack + length;
+}
+
+//#endregion
+
+//#region Collections
+
+static void build_list(size_t n) {
+	ObjList *list = new_list(n);
+	memcpy(list->items, top - n, n * sizeof(Value));
+	top -= n;
+	*top++ = OBJ_VAL(list);
+}
+
+static void build_map(size_t n) {
+	ObjMap *map = (ObjMap *)allocate(sizeof(ObjMap) + n * sizeof(Entry), OBJ_MAP);
+	map->count = 0;
+	Value *items = top - n * 2;
+	for (size_t i = 0; i < n; i++) {
+		ObjString *key = AS_STRING(items[i * 2]);
+		Value *existing = map_get(map, key->chars, key->length);
+		if (existing != NULL) {
+			*existing = items[i * 2 + 1];
+		} else {
+			map->entries[map->count++] = (Entry){key, items[i * 2 + 1]};
+		}
+	}
+	top = items;
+	*top++ = OBJ_VAL(map);
+}
+
+static void unpack_list(size_t n, bool rest, int line) {
+	Value value = top[-1];
+	if (!IS_OBJ(value, OBJ_LIST)) {
+		runtime_error(line, "Can only destructure a list");
+	}
+	ObjList *list = AS_LIST(value);
+	if (list->count < n || (!rest && list->count > n)) {
+		runtime_error(line, "Expected a list of %s%zu items but got %zu", rest ? "at least " : "", n, list->count);
+	}
+	// The list stays on the stack while the rest is allocated
+	ObjList *remaining = rest ? new_list(list->count - n) : NULL;
+	top--;
+	memcpy(top, list->items, n * sizeof(Value));
+	top += n;
+	if (rest) {
+		memcpy(remaining->items, list->items + n, remaining->count * sizeof(Value));
+		*top++ = OBJ_VAL(remaining);
+	}
+}
+
+static void unpack_map(size_t n, const uint16_t *keys, Value *constants, int line) {
+	Value value = *--top;
+	if (!IS_OBJ(value, OBJ_MAP)) {
+		runtime_error(line, "Can only destructure a map");
+	}
+	for (size_t i = 0; i < n; i++) {
+		ObjString *key = AS_STRING(constants[keys[i]]);
+		Value *found = map_get(AS_MAP(value), key->chars, key->length);
+		if (found == NULL) {
+			runtime_error(line, "Map has no key '%s'", key->chars);
+		}
+		*top++ = *found;
+	}
+}
+
+static void get_property(Value name, int line) {
+	ObjString *key = AS_STRING(name);
+	Value value = *--top;
+	if (IS_OBJ(value, OBJ_MAP)) {
+		Value *found = map_get(AS_MAP(value), key->chars, key->length);
+		if (found == NULL) {
+			runtime_error(line, "Map has no key '%s'", key->chars);
+		}
+		*top++ = *found;
+	} else if (value.type == VAL_ENUM) {
+		const EnumDef *enumeration = value.as.enumeration;
+		for (int i = 0; i < enumeration->variant_count; i++) {
+			if (strcmp(enumeration->variants[i].name, key->chars) == 0) {
+				ObjVariant *variant = new_variant(enumeration, i, 0);
+				*top++ = OBJ_VAL(variant);
+				return;
+			}
+		}
+		runtime_error(line, "Enum %s has no variant '%s'", enumeration->name, key->chars);
+	} else if (IS_OBJ(value, OBJ_VARIANT) && !is_constructor(AS_VARIANT(value))) {
+		ObjVariant *variant = AS_VARIANT(value);
+		const VariantDef *definition = &variant->enumeration->variants[variant->variant];
+		for (int i = 0; i < definition->field_count; i++) {
+			if (strcmp(definition->fields[i], key->chars) == 0) {
+				*top++ = variant->payload[i];
+				return;
+			}
+		}
+		runtime_error(line, "%s has no field '%s'", to_string(value), key->chars);
+	} else {
+		runtime_error(line, "Only maps, enums and enum values have properties");
+	}
+}
+
+static void match(void) {
+	Value pattern = *--top;
+	Value value = *--top;
+	bool matched;
+	// A constructor pattern matches every value of its variant
+	if (IS_OBJ(value, OBJ_VARIANT) && IS_OBJ(pattern, OBJ_VARIANT) && is_constructor(AS_VARIANT(pattern))) {
+		matched = AS_VARIANT(value)->enumeration == AS_VARIANT(pattern)->enumeration
+			&& AS_VARIANT(value)->variant == AS_VARIANT(pattern)->variant;
+	} else {
+		matched = values_equal(value, pattern);
+	}
+	*top++ = BOOL_VAL(matched);
+}
+
+static void unpack_variant(size_t n, int line) {
+	Value value = *--top;
+	if (!IS_OBJ(value, OBJ_VARIANT) || is_constructor(AS_VARIANT(value))) {
+		runtime_error(line, "Can only destructure an enum value");
+	}
+	ObjVariant *variant = AS_VARIANT(value);
+	if (variant->count != n) {
+		runtime_error(line, "Expected %zu fields but %s has %zu", n, to_string(value), variant->count);
+	}
+	memcpy(top, variant->payload, n * sizeof(Value));
+	top += n;
+}
+
+//#endregion
+
+//#region Calls
+
+/// Run the function of the frame on top until it returns
+static void run_frame(void) {
+	while (frames[frame_count - 1].closure->proto->code() == TAIL) {}
+}
+
+static void push_frame(ObjClosure *closure, size_t slots, int ip) {
+	frames[frame_count++] = (Frame){closure, slots, ip, 0, 0};
+}
+
+/// The amount of arguments a function takes, for error messages
+static const char *expected_arguments(const Proto *proto) {
+	static char expected[32];
+	if (proto->rest) {
+		snprintf(expected, sizeof expected, "at least %d", proto->required);
+	} else if (proto->required == proto->arity) {
+		snprintf(expected, sizeof expected, "%d", proto->arity);
+	} else {
+		snprintf(expected, sizeof expected, "%d to %d", proto->required, proto->arity);
+	}
+	return expected;
+}
+
+/// Put the arguments in the order of the parameters.
+/// Missing arguments are marked so their default is used, even when nil is passed,
+/// and extra arguments are collected for the rest parameter.
+static void bind_arguments(const Proto *proto, Value *callee, int argc, int named, const uint16_t *names, Value *constants, int line) {
+	Value *args = callee + 1;
+	Value params[256];
+	bool bound[256] = {false};
+	for (int i = 0; i < argc && i < proto->arity; i++) {
+		params[i] = args[i];
+		bound[i] = true;
+	}
+
+	if (argc > proto->arity && !proto->rest) {
+		runtime_error(line, "Expected %s arguments but got %d", expected_arguments(proto), argc);
+	}
+	for (int i = 0; i < named; i++) {
+		ObjString *name = AS_STRING(constants[names[i]]);
+		int index = -1;
+		for (int j = 0; j < proto->arity; j++) {
+			if (strcmp(proto->params[j], name->chars) == 0) {
+				index = j;
+				break;
+			}
+		}
+		if (index == -1) {
+			runtime_error(line, "Unknown parameter '%s'", name->chars);
+		}
+		if (bound[index]) {
+			runtime_error(line, "Got multiple values for parameter '%s'", name->chars);
+		}
+		params[index] = args[argc + i];
+		bound[index] = true;
+	}
+	for (int i = 0; i < proto->required; i++) {
+		if (!bound[i]) {
+			if (named == 0) {
+				runtime_error(line, "Expected %s arguments but got %d", expected_arguments(proto), argc);
+			}
+			runtime_error(line, "Missing argument for parameter '%s'", proto->params[i]);
+		}
+	}
+
+	// The extra arguments are still on the stack while the list is allocated
+	ObjList *rest = NULL;
+	if (proto->rest) {
+		int extra = argc > proto->arity ? argc - proto->arity : 0;
+		rest = new_list(extra);
+		memcpy(rest->items, args + proto->arity, extra * sizeof(Value));
+	}
+	for (int i = 0; i < proto->arity; i++) {
+		args[i] = bound[i] ? params[i] : MISSING_VAL;
+	}
+	top = args + proto->arity;
+	if (rest != NULL) {
+		*top++ = OBJ_VAL(rest);
+	}
+}
+
+/// Create a value of an enum variant with the arguments as payload
+static void construct(ObjVariant *constructor, Value *callee, int argc, int line) {
+	int fields = constructor->enumeration->variants[constructor->variant].field_count;
+	if (argc != fields) {
+		runtime_error(line, "Expected %d arguments but got %d", fields, argc);
+	}
+	ObjVariant *value = new_variant(constructor->enumeration, constructor->variant, argc);
+	memcpy(value->payload, callee + 1, argc * sizeof(Value));
+	top = callee;
+	*top++ = OBJ_VAL(value);
+}
+
+/// Call a function built into the runtime, it replaces the callee and arguments with its result
+static void call_native(const Native *native, Value *callee, int argc, int line) {
+	if (argc != native->arity) {
+		runtime_error(line, "Expected %d arguments but got %d", native->arity, argc);
+	}
+	Value result = native->function(callee + 1);
+	top = callee;
+	*top++ = result;
+}
+
+/// Continue a generator where it last yielded.
+/// The argument becomes the value of the yield expression.
+static void resume(ObjGenerator *generator, Value *callee, int argc, int line) {
+	if (argc > 1) {
+		runtime_error(line, "Expected 0 to 1 arguments but got %d", argc);
+	}
+	Value sent = argc == 1 ? *--top : NIL_VAL;
+
+	switch (generator->state) {
+		// A finished generator keeps returning nil
+		case GENERATOR_DONE:
+			top = callee;
+			*top++ = NIL_VAL;
+			return;
+		case GENERATOR_RUNNING:
+			runtime_error(line, "Generator is already running");
+			break;
+		default:
+			break;
+	}
+	if (frame_count >= FRAMES_MAX) {
+		runtime_error(line, "Stack overflow");
+	}
+
+	// The generator stays in the place of the callee
+	size_t slots = callee + 1 - stack;
+	memcpy(top, generator->stack, generator->stack_count * sizeof(Value));
+	top += generator->stack_count;
+	generator->stack_count = 0;
+	for (size_t i = 0; i < generator->upvalue_count; i++) {
+		ObjUpvalue *upvalue = generator->upvalues[i].upvalue;
+		size_t slot = slots + generator->upvalues[i].offset;
+		if (!upvalue->open) {
+			stack[slot] = upvalue->closed;
+		}
+		upvalue->open = true;
+		upvalue->slot = slot;
+		if (open_count == open_capacity) {
+			open_capacity = open_capacity < 8 ? 8 : open_capacity * 2;
+			open_upvalues = reallocate(open_upvalues, open_capacity * sizeof(ObjUpvalue *));
+		}
+		open_upvalues[open_count++] = upvalue;
+	}
+	generator->upvalue_count = 0;
+	if (generator->state == GENERATOR_SUSPENDED) {
+		*top++ = sent;
+	}
+	generator->state = GENERATOR_RUNNING;
+
+	push_frame(generator->closure, slots, generator->ip);
+	run_frame();
+}
+
+/// Call the value below the arguments,
+/// the named arguments are above the positional ones
+static void call(int argc, int named, const uint16_t *names, Value *constants, int line) {
+	Value *callee = top - argc - named - 1;
+	if (IS_OBJ(*callee, OBJ_GENERATOR) && named == 0) {
+		resume(AS_GENERATOR(*callee), callee, argc, line);
+		return;
+	}
+	if (IS_OBJ(*callee, OBJ_VARIANT) && named == 0 && is_constructor(AS_VARIANT(*callee))) {
+		construct(AS_VARIANT(*callee), callee, argc, line);
+		return;
+	}
+	if (callee->type == VAL_NATIVE && named == 0) {
+		call_native(callee->as.native, callee, argc, line);
+		return;
+	}
+	if (!IS_OBJ(*callee, OBJ_CLOSURE)) {
+		runtime_error(line, "Can only call functions");
+	}
+
+	ObjClosure *closure = AS_CLOSURE(*callee);
+	const Proto *proto = closure->proto;
+	if (named > 0 || argc != proto->arity || proto->rest) {
+		bind_arguments(proto, callee, argc, named, names, constants, line);
+	}
+
+	// The arguments are kept until the generator is first resumed
+	if (proto->generator) {
+		ObjGenerator *generator = (ObjGenerator *)allocate(sizeof(ObjGenerator), OBJ_GENERATOR);
+		size_t count = top - callee - 1;
+		generator->closure = closure;
+		generator->ip = 0;
+		generator->state = GENERATOR_CREATED;
+		generator->stack = reallocate(NULL, count * sizeof(Value) + 1);
+		memcpy(generator->stack, callee + 1, count * sizeof(Value));
+		generator->stack_count = count;
+		generator->upvalues = NULL;
+		generator->upvalue_count = 0;
+		top = callee;
+		*top++ = OBJ_VAL(generator);
+		return;
+	}
+
+	if (frame_count >= FRAMES_MAX) {
+		runtime_error(line, "Stack overflow");
+	}
+	push_frame(closure, callee + 1 - stack, 0);
+	run_frame();
+}
+
+/// Call a function in place of the current one, returns false if it was called normally.
+/// Anything that isn't a plain function is called normally,
+/// the compiler puts a return after the tail call for this.
+static bool tail_call(int argc, int line) {
+	Value *callee = top - argc - 1;
+	if (!IS_OBJ(*callee, OBJ_CLOSURE) || AS_CLOSURE(*callee)->proto->generator) {
+		call(argc, 0, NULL, NULL, line);
+		return false;
+	}
+
+	ObjClosure *closure = AS_CLOSURE(*callee);
+	if (argc != closure->proto->arity || closure->proto->rest) {
+		bind_arguments(closure->proto, callee, argc, 0, NULL, NULL, line);
+	}
+
+	// Replace the locals of the current call with the callee and arguments
+	Frame *frame = &frames[frame_count - 1];
+	close_upvalues(frame->slots);
+	size_t count = top - callee;
+	memmove(stack + frame->slots - 1, callee, count * sizeof(Value));
+	top = stack + frame->slots - 1 + count;
+	frame->closure = closure;
+	frame->ip = 0;
+	frame->elided++;
+	return true;
+}
+
+static int do_return(void) {
+	// Returning from the script itself
+	if (frame_count == 1) {
+		return RETURNED;
+	}
+	Frame *frame = &frames[frame_count - 1];
+	Value result = *--top;
+	if (frame->closure->proto->generator && IS_OBJ(stack[frame->slots - 1], OBJ_GENERATOR)) {
+		AS_GENERATOR(stack[frame->slots - 1])->state = GENERATOR_DONE;
+	}
+	close_upvalues(frame->slots);
+	// Remove the locals, arguments and the callee
+	top = stack + frame->slots - 1;
+	*top++ = result;
+	frame_count--;
+	return RETURNED;
+}
+
+/// Suspend the current generator, it continues at a resume point of the generated code
+static int yield(int ip) {
+	Frame *frame = &frames[frame_count - 1];
+	Value value = *--top;
+	// The generator being resumed is in the place of the callee
+	ObjGenerator *generator = AS_GENERATOR(stack[frame->slots - 1]);
+
+	// Close the upvalues into the stack of the call, with their offset so they can be opened again
+	generator->upvalues = reallocate(generator->upvalues, open_count * sizeof(Suspended) + 1);
+	generator->upvalue_count = 0;
+	size_t kept = 0;
+	for (size_t i = 0; i < open_count; i++) {
+		ObjUpvalue *upvalue = open_upvalues[i];
+		if (upvalue->slot >= frame->slots) {
+			upvalue->closed = stack[upvalue->slot];
+			upvalue->open = false;
+			generator->upvalues[generator->upvalue_count++] = (Suspended){upvalue, upvalue->slot - frame->slots};
+		} else {
+			open_upvalues[kept++] = upvalue;
+		}
+	}
+	open_count = kept;
+
+	size_t count = top - stack - frame->slots;
+	generator->stack = reallocate(generator->stack, count * sizeof(Value) + 1);
+	memcpy(generator->stack, stack + frame->slots, count * sizeof(Value));
+	generator->stack_count = count;
+	generator->ip = ip;
+	generator->state = GENERATOR_SUSPENDED;
+
+	// Return to the caller
+	top = stack + frame->slots - 1;
+	*top++ = value;
+	frame_count--;
+	return RETURNED;
+}
+
+static void run(const Proto *script) {
+	// The script itself is run as a function without arguments
+	ObjClosure *closure = (ObjClosure *)allocate(sizeof(ObjClosure), OBJ_CLOSURE);
+	closure->proto = script;
+	push_frame(closure, 0, 0);
+	run_frame();
+}
+
+//#endregion
+
+//#region Ops
+// Used by the generated code, every function starts with ENTER
+
+#define ENTER(constants) \
+	Frame *const frame = &frames[frame_count - 1]; \
+	Value *const slots = stack + frame->slots; \
+	Value *const k = (constants); \
+	(void)frame; (void)slots; (void)k
+
+// The value is computed before the stack changes, as computing it can run the collector
+#define PUSH(value) do {Value pushed_ = (value); *top++ = pushed_;} while (0)
+#define PEEK(distance) (top[-1 - (distance)])
+
+#define NEGATE(line) do { \
+	if (!IS_NUMBER(PEEK(0))) runtime_error(line, "Operand must be a number"); \
+	PEEK(0) = NUMBER_VAL(-AS_NUMBER(PEEK(0))); \
+} while (0)
+#define ADD(line) do {Value sum_ = add_values(PEEK(1), PEEK(0), line); top--; PEEK(0) = sum_;} while (0)
+// Store the result of an operator on two numbers
+#define ARITHMETIC_TO(target, first, second, op, line) do { \
+	Value first_ = (first), second_ = (second); \
+	check_numbers(first_, second_, line); \
+	(target) = NUMBER_VAL(AS_NUMBER(first_) op AS_NUMBER(second_)); \
+} while (0)
+#define ARITHMETIC(op, line) do {ARITHMETIC_TO(PEEK(1), PEEK(1), PEEK(0), op, line); top--;} while (0)
+// The negated forms are for >= and <=, which are the negation of the opposite
+#define COMPARE(op, negated, line) do { \
+	check_numbers(PEEK(1), PEEK(0), line); \
+	bool compared_ = AS_NUMBER(PEEK(1)) op AS_NUMBER(PEEK(0)); \
+	top--; \
+	PEEK(0) = BOOL_VAL(compared_ != (negated)); \
+} while (0)
+#define SUBTRACTLOCALCONST(local, constant, line) do { \
+	Value difference_; \
+	ARITHMETIC_TO(difference_, slots[local], k[constant], -, line); \
+	PUSH(difference_); \
+} while (0)
+#define NOT() (PEEK(0) = BOOL_VAL(!is_truthy(PEEK(0))))
+// Store the result of a register op, the registers above the one it is stored in are freed
+#define TO_REGISTER(register, value) do {Value stored_ = (value); top = slots + (register); *top++ = stored_;} while (0)
+#define EQUAL(equal) do {bool equal_ = values_equal(PEEK(1), PEEK(0)); top--; PEEK(0) = BOOL_VAL(equal_ == (equal));} while (0)
+
+#define CALL(argc, at) do {frame->line = (at); call(argc, 0, NULL, k, at);} while (0)
+#define CALLNAMED(argc, named, names, at) do {frame->line = (at); call(argc, named, names, k, at);} while (0)
+#define TAILCALL(argc, at) do {frame->line = (at); if (tail_call(argc, at)) return TAIL;} while (0)
+#define RETURN() return do_return()
+#define YIELD(ip) return yield(ip)
+
+//#endregion
@@ -0,0 +1,621 @@
+/*
+The assembler reads chunks back from the text Chunk::disassemble writes,
+so programs for the VM can be written and changed by hand.
+
+	== script ==
+	.globals
+	0000 count
+	.const
+	0000 number 3
+	0001 fun count(n) required 1 upvalues 0
+	.code
+	   1 0000 CONSTANT 0000 (3)
+	   | 0002 POP
+	loop:
+	   2 0003 JUMP loop
+	== count(n) ==
+	...
+
+A chunk starts with a header, which is optional for the script.
+The names of the globals are listed after .globals, they are only used in errors.
+Constants are listed after .const, with their kind and value.
+The code comes after .code, or right after the header when there are no globals or constants.
+An instruction can start with its line and offset, otherwise its line in the text is used.
+The offset is only there to read jumps, it isn't checked.
+Operands are written like the disassembly, the part in parentheses can be left out.
+A jump goes to a label instead of a relative offset when it is given a name.
+The chunks of the functions in the constants come after the code, in the order of the constants.
+Lines that start with // are comments.
+*/
+
+use std::rc::Rc;
+use std::cell::OnceCell;
+use crate::vm::chunk::Chunk;
+use crate::vm::op_codes::*;
+use crate::vm::value::Value;
+use crate::vm::function::{Function, Parameter};
+use crate::vm::enums::{Enum, Variant};
+use crate::vm::native;
+
+/// Assemble a chunk and the functions in it, the errors have the line they are on
+pub fn assemble(source: &str) -> Result<Chunk, String> {
+	let mut assembler = Assembler {
+		lines: source.lines().collect(),
+		current: 0,
+	};
+	let chunk = assembler.chunk(true)?;
+	assembler.skip_comments();
+	if assembler.current < assembler.lines.len() {
+		return Err(assembler.error("a chunk that isn't used by a function"));
+	}
+	Ok(chunk)
+}
+
+struct Assembler<'a> {
+	lines: Vec<&'a str>,
+	/// Index of the line being read
+	current: usize,
+}
+
+/// An instruction before the labels are known
+struct Pending {
+	op: OpCode,
+	/// Operands, for a jump these are the ones before the offset
+	operands: Vec<u8>,
+	jump: Option<Jump>,
+	line: u32,
+	/// Line in the text, for errors
+	source_line: usize,
+}
+
+enum Jump {
+	Offset(i64),
+	Label(String),
+}
+
+impl<'a> Assembler<'a> {
+	fn error(&self, msg: impl AsRef<str>) -> String {
+		format!("line {}: {}", self.current + 1, msg.as_ref())
+	}
+
+	fn skip_comments(&mut self) {
+		while let Some(line) = self.lines.get(self.current) {
+			let line = line.trim();
+			if !line.is_empty() && !line.starts_with("//") {
+				break;
+			}
+			self.current += 1;
+		}
+	}
+
+	/// The next line that isn't empty or a comment
+	fn peek(&mut self) -> Option<&'a str> {
+		self.skip_comments();
+		self.lines.get(self.current).map(|line| line.trim())
+	}
+
+	fn chunk(&mut self, script: bool) -> Result<Chunk, String> {
+		match self.peek() {
+			Some(line) if line.starts_with("==") => self.current += 1,
+			_ if script => {},
+			_ => return Err(self.error("expected the '== name ==' header of a function")),
+		}
+
+		let mut globals = vec![];
+		if self.peek() == Some(".globals") {
+			self.current += 1;
+			loop {
+				match self.peek() {
+					Some(".const" | ".code") => break,
+					Some(line) => {
+						let global = self.global(line, globals.len()).map_err(|msg| self.error(msg))?;
+						globals.push(global);
+						self.current += 1;
+					},
+					None => return Err(self.error("expected .code after the globals")),
+				}
+			}
+		}
+
+		let mut constants = vec![];
+		if self.peek() == Some(".const") {
+			self.current += 1;
+			loop {
+				match self.peek() {
+					Some(".code") => break,
+					Some(line) => {
+						let constant = self.constant(line, constants.len()).map_err(|msg| self.error(msg))?;
+						constants.push(constant);
+						self.current += 1;
+					},
+					None => return Err(self.error("expected .code after the constants")),
+				}
+			}
+		}
+		if self.peek() == Some(".code") {
+			self.current += 1;
+		}
+
+		let mut instructions = vec![];
+		let mut labels = vec![];
+		while let Some(line) = self.peek() {
+			if line.starts_with("==") {
+				break;
+			}
+			if let Some(label) = line.strip_suffix(':').filter(|label| !label.contains(char::is_whitespace)) {
+				if labels.iter().any(|(name, _)| name == label) {
+					return Err(self.error(format!("duplicate label '{}'", label)));
+				}
+				labels.push((label.to_owned(), instructions.len()));
+			} else {
+				let previous = instructions.last().map(|instruction: &Pending| instruction.line);
+				let instruction = self.instruction(line, &constants, previous).map_err(|msg| self.error(msg))?;
+				instructions.push(instruction);
+			}
+			self.current += 1;
+		}
+		let mut chunk = self.encode(instructions, &labels)?;
+
+		// The chunks of functions follow in the order of the constants
+		for constant in &mut constants {
+			if let Value::FUNCTION(function) = constant {
+				Rc::get_mut(function).unwrap().chunk = self.chunk(false)?;
+			}
+		}
+		chunk.constants = constants;
+		chunk.globals = globals;
+		Ok(chunk)
+	}
+
+	/// The name of a global like `0002 count`, the index is optional
+	fn global(&self, line: &str, index: usize) -> Result<String, String> {
+		let mut cursor = Cursor {rest: line};
+		let mut name = cursor.word()?;
+		if name.chars().all(|char| char.is_ascii_digit()) {
+			if name.parse::<usize>() != Ok(index) {
+				return Err(format!("expected global {} but got {}", index, name));
+			}
+			name = cursor.word()?;
+		}
+		cursor.end()?;
+		Ok(name.to_owned())
+	}
+
+	/// A constant like `0003 string "a"`, the index is optional
+	fn constant(&self, line: &str, index: usize) -> Result<Value, String> {
+		let mut cursor = Cursor {rest: line};
+		let mut kind = cursor.word()?;
+		if kind.chars().all(|char| char.is_ascii_digit()) {
+			if kind.parse::<usize>() != Ok(index) {
+				return Err(format!("expected constant {} but got {}", index, kind));
+			}
+			kind = cursor.word()?;
+		}
+		let value = match kind {
+			"nil" => Value::NIL,
+			"true" => Value::BOOL(true),
+			"false" => Value::BOOL(false),
+			"number" => {
+				let word = cursor.word()?;
+				let number = match word.strip_prefix("0x") {
+					Some(bits) => u64::from_str_radix(bits, 16).ok().map(f64::from_bits),
+					None => word.parse().ok(),
+				};
+				Value::NUMBER(number.ok_or_else(|| format!("invalid number '{}'", word))?)
+			},
+			"string" => Value::STRING(cursor.quoted('"')?),
+			"char" => {
+				let string = cursor.quoted('\'')?;
+				let mut chars = string.chars();
+				match (chars.next(), chars.next()) {
+					(Some(char), None) => Value::CHAR(char),
+					_ => return Err(format!("'{}' isn't a single char", string)),
+				}
+			},
+			"native" => {
+				let name = cursor.word()?;
+				Value::NATIVE(native::find(name).ok_or_else(|| format!("there is no native function {}", name))?)
+			},
+			"enum" => {
+				let name = cursor.word()?.to_owned();
+				cursor.expect("{")?;
+				let mut variants = vec![];
+				while !cursor.eat("}") {
+					if !variants.is_empty() {
+						cursor.expect(",")?;
+					}
+					let name = cursor.word()?.to_owned();
+					let mut fields = vec![];
+					if cursor.eat("(") {
+						while !cursor.eat(")") {
+							if !fields.is_empty() {
+								cursor.expect(",")?;
+							}
+							fields.push(cursor.word()?.to_owned());
+						}
+					}
+					variants.push(Variant {name, fields});
+				}
+				Value::ENUM(Rc::new(Enum {name, variants}))
+			},
+			"fun" => self.function(&mut cursor)?,
+			_ => return Err(format!("unknown constant '{}'", kind)),
+		};
+		cursor.end()?;
+		Ok(value)
+	}
+
+	/// The header of a function, like `f(a, b = "1", ...rest) required 1 upvalues 0 generator`.
+	/// Its chunk is read after the code it is a constant of.
+	fn function(&self, cursor: &mut Cursor) -> Result<Value, String> {
+		let name = if cursor.eat("(") {
+			None
+		} else {
+			let name = cursor.word()?.to_owned();
+			cursor.expect("(")?;
+			Some(name)
+		};
+		let mut params = vec![];
+		let mut rest = None;
+		while !cursor.eat(")") {
+			if !params.is_empty() || rest.is_some() {
+				cursor.expect(",")?;
+			}
+			if rest.is_some() {
+				return Err("the rest parameter has to be the last".to_owned());
+			}
+			if cursor.eat("...") {
+				rest = Some(cursor.word()?.to_owned());
+				continue;
+			}
+			let name = cursor.word()?.to_owned();
+			let default = if cursor.eat("=") {Some(cursor.quoted('"')?)} else {None};
+			params.push(Parameter {name, default});
+		}
+		let arity = u8::try_from(params.len()).map_err(|_| "a function can't have more than 255 parameters")?;
+
+		cursor.expect("required")?;
+		let required = cursor.number()?;
+		if required > arity as i64 {
+			return Err(format!("{} parameters can't be required of {}", required, arity));
+		}
+		cursor.expect("upvalues")?;
+		let upvalue_count = cursor.number()?;
+		if upvalue_count < 0 {
+			return Err(format!("invalid upvalue count {}", upvalue_count));
+		}
+		let generator = cursor.eat("generator");
+		Ok(Value::FUNCTION(Rc::new(Function {
+			arity,
+			required: required as u8,
+			params,
+			rest,
+			chunk: Chunk::new(),
+			name,
+			upvalue_count: upvalue_count as usize,
+			generator,
+			decoded: OnceCell::new(),
+		})))
+	}
+
+	/// An instruction like `   1 0000 CONSTANT 0000 (1)` or `JUMP loop`
+	fn instruction(&self, line: &str, constants: &[Value], previous: Option<u32>) -> Result<Pending, String> {
+		let mut cursor = Cursor {rest: line};
+		let mut number = self.current as u32 + 1;
+		let mut word = cursor.word()?;
+		if word == "|" || word.parse::<u32>().is_ok() {
+			// The line is left out when it is the same as for the previous instruction
+			if word != "|" {
+				number = word.parse().unwrap();
+			} else if let Some(previous) = previous {
+				number = previous;
+			}
+			let offset = cursor.word()?;
+			if offset.parse::<usize>().is_err() {
+				return Err(format!("expected the offset after the line but got '{}'", offset));
+			}
+			word = cursor.word()?;
+		}
+		let operator = |word: &str| (0..=OpCode::MAX).find(|op| name(*op) == Some(word)).ok_or_else(|| format!("unknown op '{}'", word));
+		let op = operator(word)?;
+
+		// Skips what the disassembly shows of a constant
+		let skip_shown = |cursor: &mut Cursor, index: u16| {
+			if let Some(constant) = constants.get(index as usize) {
+				cursor.eat(&format!("({})", describe(constant)));
+			}
+		};
+		let mut operands = vec![];
+		let mut jump = None;
+		match op {
+			CONSTANT | GETPROPERTY => {
+				let index = cursor.byte()?;
+				operands.push(index);
+				skip_shown(&mut cursor, index as u16);
+			},
+			CONSTANTLONG | GETPROPERTYLONG => {
+				let index = cursor.word_operand()?;
+				operands.extend_from_slice(&index.to_be_bytes());
+				skip_shown(&mut cursor, index);
+			},
+			DEFGLOBALLONG | GETGLOBALLONG | SETGLOBALLONG | GETLOCALLONG | SETLOCALLONG => {
+				operands.extend_from_slice(&cursor.word_operand()?.to_be_bytes());
+			},
+			DEFGLOBAL | GETGLOBAL | SETGLOBAL | GETLOCAL | SETLOCAL | LEAVE | CALL | TAILCALL |
+			GETUPVALUE | SETUPVALUE | BUILDLIST | BUILDMAP | UNPACKVARIANT => operands.push(cursor.byte()?),
+			MOVEREG | ADDREG | SUBTRACTREG | MULTIPLYREG | DIVIDEREG => {
+				let count = if op == MOVEREG {2} else {3};
+				for _ in 0..count {
+					operands.push(cursor.byte()?);
+				}
+			},
+			INCRLOCAL | ADDLOCALCONST | SUBTRACTLOCALCONST | LOADREG |
+			ADDREGCONST | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST |
+			LESSLOCALCONSTJUMP | LESSLOCALCONSTJUMPLONG => {
+				let count = match op {
+					ADDREGCONST | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST => 3,
+					_ => 2,
+				};
+				for _ in 0..count {
+					operands.push(cursor.byte()?);
+				}
+				skip_shown(&mut cursor, operands[count - 1] as u16);
+			},
+			LESSLOCALSJUMP | LESSLOCALSJUMPLONG => {
+				operands.push(cursor.byte()?);
+				operands.push(cursor.byte()?);
+			},
+			BINARYREG | UNARYREG | RETURNREG => {
+				if op != RETURNREG {
+					operands.push(operator(cursor.word()?)?);
+					operands.push(cursor.byte()?);
+				}
+				let mut kinds = 0;
+				let mut indexes = vec![];
+				for source in 0..sources(op) {
+					let word = cursor.word()?;
+					let (constant, index) = match word.split_at_checked(1) {
+						Some(("r", index)) => (false, index),
+						Some(("k", index)) => (true, index),
+						_ => return Err(format!("expected a register or constant but got '{}'", word)),
+					};
+					let index: u8 = index.parse().map_err(|_| format!("expected a register or constant but got '{}'", word))?;
+					if constant {
+						kinds |= 1 << source;
+						skip_shown(&mut cursor, index as u16);
+					}
+					indexes.push(index);
+				}
+				operands.push(kinds);
+				operands.extend(indexes);
+			},
+			JUMPIFFALSEREG | JUMPIFFALSEREGLONG => operands.push(cursor.byte()?),
+			JUMPUNLESSREG | JUMPUNLESSREGLONG | JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG => {
+				operands.push(operator(cursor.word()?)?);
+				operands.push(cursor.byte()?);
+				operands.push(cursor.byte()?);
+				if let JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG = op {
+					skip_shown(&mut cursor, operands[2] as u16);
+				}
+			},
+			UNPACKLIST => {
+				operands.push(cursor.byte()?);
+				operands.push(cursor.eat("rest") as u8);
+			},
+			CALLNAMED | CALLNAMEDLONG | UNPACKMAP | UNPACKMAPLONG => {
+				if let CALLNAMED | CALLNAMEDLONG = op {
+					operands.push(cursor.byte()?);
+				}
+				let long = matches!(op, CALLNAMEDLONG | UNPACKMAPLONG);
+				let mut names = vec![];
+				while cursor.peek_number() {
+					names.push(if long {cursor.word_operand()?} else {cursor.byte()? as u16});
+				}
+				operands.push(names.len() as u8);
+				let shown: Vec<String> = names.iter()
+					.filter_map(|index| constants.get(*index as usize).map(describe))
+					.collect();
+				cursor.eat(&format!("({})", shown.join(", ")));
+				for index in names {
+					if long {
+						operands.extend_from_slice(&index.to_be_bytes());
+					} else {
+						operands.push(index as u8);
+					}
+				}
+			},
+			CLOSURE | CLOSURELONG => {
+				let index = if op == CLOSURE {cursor.byte()? as u16} else {cursor.word_operand()?};
+				if op == CLOSURE {
+					operands.push(index as u8);
+				} else {
+					operands.extend_from_slice(&index.to_be_bytes());
+				}
+				skip_shown(&mut cursor, index);
+				let Some(Value::FUNCTION(function)) = constants.get(index as usize) else {
+					return Err(format!("constant {} of {} isn't a function", index, word));
+				};
+				for _ in 0..function.upvalue_count {
+					let is_local = match cursor.word()? {
+						"local" => 1,
+						"upvalue" => 0,
+						kind => return Err(format!("expected local or upvalue but got '{}'", kind)),
+					};
+					operands.push(is_local);
+					operands.extend_from_slice(&cursor.word_operand()?.to_be_bytes());
+				}
+			},
+			_ => {},
+		}
+		if long_jump(op) != op || short_jump(op) != op {
+			jump = Some(if cursor.peek_number() {
+				let offset = cursor.number()?;
+				// The target the disassembly shows is worked out from the offset
+				if cursor.eat("(") {
+					cursor.number()?;
+					cursor.expect(")")?;
+				}
+				Jump::Offset(offset)
+			} else {
+				Jump::Label(cursor.word()?.to_owned())
+			});
+		}
+		cursor.end()?;
+		Ok(Pending {op, operands, jump, line: number, source_line: self.current})
+	}
+
+	/// Write the instructions, jumps keep the form they were written in
+	fn encode(&self, instructions: Vec<Pending>, labels: &[(String, usize)]) -> Result<Chunk, String> {
+		let mut offsets = Vec::with_capacity(instructions.len() + 1);
+		let mut offset = 0;
+		for instruction in &instructions {
+			offsets.push(offset);
+			offset += 1 + instruction.operands.len();
+			if instruction.jump.is_some() {
+				offset += if short_jump(instruction.op) != instruction.op {4} else {2};
+			}
+		}
+		offsets.push(offset);
+
+		let mut chunk = Chunk::new();
+		for (i, instruction) in instructions.iter().enumerate() {
+			let error = |msg: String| format!("line {}: {}", instruction.source_line + 1, msg);
+			chunk.push_op(instruction.op, instruction.line);
+			for operand in &instruction.operands {
+				chunk.push_op(*operand, instruction.line);
+			}
+			let jump = match &instruction.jump {
+				Some(Jump::Offset(offset)) => *offset,
+				Some(Jump::Label(label)) => {
+					let Some((_, target)) = labels.iter().find(|(name, _)| name == label) else {
+						return Err(error(format!("there is no label '{}'", label)));
+					};
+					offsets[*target] as i64 - offsets[i + 1] as i64
+				},
+				None => continue,
+			};
+			let bytes = if short_jump(instruction.op) != instruction.op {
+				i32::try_from(jump).map(|jump| jump.to_be_bytes().to_vec())
+			} else {
+				i16::try_from(jump).map(|jump| jump.to_be_bytes().to_vec())
+			};
+			let bytes = bytes.map_err(|_| error(format!("jump of {} doesn't fit in {}", jump, name(instruction.op).unwrap())))?;
+			for byte in bytes {
+				chunk.push_op(byte, instruction.line);
+			}
+		}
+		Ok(chunk)
+	}
+}
+
+/// Reads the parts of a line
+struct Cursor<'a> {
+	rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+	/// Consume some text if the line continues with it
+	fn eat(&mut self, text: &str) -> bool {
+		self.rest = self.rest.trim_start();
+		match self.rest.strip_prefix(text) {
+			Some(rest) => {
+				self.rest = rest;
+				true
+			},
+			None => false,
+		}
+	}
+
+	fn expect(&mut self, text: &str) -> Result<(), String> {
+		if self.eat(text) {
+			Ok(())
+		} else {
+			Err(format!("expected '{}' but got '{}'", text, self.rest))
+		}
+	}
+
+	/// The line has to end here, or with a comment
+	fn end(&mut self) -> Result<(), String> {
+		self.rest = self.rest.trim_start();
+		if self.rest.is_empty() || self.rest.starts_with("//") {
+			Ok(())
+		} else {
+			Err(format!("unexpected '{}'", self.rest))
+		}
+	}
+
+	/// A name or number, up to a space or punctuation
+	fn word(&mut self) -> Result<&'a str, String> {
+		self.rest = self.rest.trim_start();
+		let end = self.rest.find(|char: char| char.is_whitespace() || "(){},=".contains(char)).unwrap_or(self.rest.len());
+		if end == 0 {
+			return Err(match self.rest.is_empty() {
+				true => "unexpected end of the line".to_owned(),
+				false => format!("unexpected '{}'", self.rest),
+			});
+		}
+		let (word, rest) = self.rest.split_at(end);
+		self.rest = rest;
+		Ok(word)
+	}
+
+	fn peek_number(&mut self) -> bool {
+		self.rest = self.rest.trim_start();
+		let digits = self.rest.strip_prefix('-').unwrap_or(self.rest);
+		digits.starts_with(|char: char| char.is_ascii_digit())
+	}
+
+	fn number(&mut self) -> Result<i64, String> {
+		let word = self.word()?;
+		word.parse().map_err(|_| format!("expected a number but got '{}'", word))
+	}
+
+	fn byte(&mut self) -> Result<u8, String> {
+		let number = self.number()?;
+		u8::try_from(number).map_err(|_| format!("operand {} doesn't fit in a byte", number))
+	}
+
+	/// An operand in two bytes
+	fn word_operand(&mut self) -> Result<u16, String> {
+		let number = self.number()?;
+		u16::try_from(number).map_err(|_| format!("operand {} doesn't fit in two bytes", number))
+	}
+
+	/// A string or char with escapes, as Rust debug prints it
+	fn quoted(&mut self, quote: char) -> Result<String, String> {
+		self.rest = self.rest.trim_start();
+		let mut chars = self.rest.char_indices();
+		if chars.next().map(|(_, char)| char) != Some(quote) {
+			return Err(format!("expected {} but got '{}'", quote, self.rest));
+		}
+		let mut string = String::new();
+		while let Some((i, char)) = chars.next() {
+			if char == quote {
+				self.rest = &self.rest[i + 1..];
+				return Ok(string);
+			}
+			if char != '\\' {
+				string.push(char);
+				continue;
+			}
+			let escaped = match chars.next().map(|(_, char)| char) {
+				Some('n') => '\n',
+				Some('r') => '\r',
+				Some('t') => '\t',
+				Some('0') => '\0',
+				Some(char @ ('\\' | '\'' | '"')) => char,
+				Some('u') => {
+					let hex: String = chars.by_ref().map(|(_, char)| char).take_while(|char| *char != '}').collect();
+					hex.strip_prefix('{')
+						.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+						.and_then(char::from_u32)
+						.ok_or_else(|| format!("invalid escape \\u{}}}", hex))?
+				},
+				Some(char) => return Err(format!("invalid escape \\{}", char)),
+				None => break,
+			};
+			string.push(escaped);
+		}
+		Err(format!("missing closing {}", quote))
+	}
+}
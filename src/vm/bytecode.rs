@@ -0,0 +1,302 @@
+/*
+The .loxc format, a compiled chunk saved so it can be run without the source.
+A file starts with a header:
+	magic     4 bytes  "LOXC"
+	version   2 bytes
+	checksum  4 bytes  CRC-32 of everything after the header
+Then comes the chunk. Numbers are big endian like the operands in the code,
+lengths and counts take 4 bytes and strings are a length with UTF-8 bytes.
+	chunk     code length, code, line count, (number, length) per line,
+	          global count, global names, constant count, constants
+	constant  a tag byte with the value, a function has its fields and its own chunk
+The version has to change whenever the ops or this layout change,
+as old files would be read wrong otherwise.
+The broken files in test/bytecode then have to be saved again for the new version.
+*/
+
+use std::rc::Rc;
+use std::cell::OnceCell;
+use crate::vm::chunk::{Chunk, Line};
+use crate::vm::value::Value;
+use crate::vm::function::{Function, Parameter};
+use crate::vm::enums::{Enum, Variant};
+use crate::vm::native;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 5;
+const HEADER_LENGTH: usize = 10;
+
+// Tags of the constants
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const CHAR: u8 = 5;
+const FUNCTION: u8 = 6;
+const ENUM: u8 = 7;
+const NATIVE: u8 = 8;
+
+/// Serialize a chunk, with the functions in its constants
+pub fn save(chunk: &Chunk) -> Result<Vec<u8>, String> {
+	let mut payload = vec![];
+	write_chunk(&mut payload, chunk)?;
+
+	let mut bytes = Vec::with_capacity(HEADER_LENGTH + payload.len());
+	bytes.extend_from_slice(MAGIC);
+	bytes.extend_from_slice(&VERSION.to_be_bytes());
+	bytes.extend_from_slice(&crc32(&payload).to_be_bytes());
+	bytes.append(&mut payload);
+	Ok(bytes)
+}
+
+/// Read a chunk back, this fails for anything that wasn't saved by this version
+pub fn load(bytes: &[u8]) -> Result<Chunk, String> {
+	if bytes.len() < HEADER_LENGTH || &bytes[..4] != MAGIC {
+		return Err("not a loxc file".to_owned());
+	}
+	let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+	if version != VERSION {
+		return Err(format!("made for version {} but this is version {}", version, VERSION));
+	}
+	let checksum = u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+	let payload = &bytes[HEADER_LENGTH..];
+	if crc32(payload) != checksum {
+		return Err("the checksum doesn't match, the file is corrupted".to_owned());
+	}
+
+	let mut reader = Reader {bytes: payload, offset: 0};
+	let chunk = reader.chunk()?;
+	if reader.offset != payload.len() {
+		return Err("unexpected bytes after the chunk".to_owned());
+	}
+	Ok(chunk)
+}
+
+fn write_length(bytes: &mut Vec<u8>, length: usize) {
+	bytes.extend_from_slice(&(length as u32).to_be_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+	write_length(bytes, string.len());
+	bytes.extend_from_slice(string.as_bytes());
+}
+
+/// Strings that can be missing have a byte before them to say if they are there
+fn write_option(bytes: &mut Vec<u8>, string: &Option<String>) {
+	match string {
+		Some(string) => {
+			bytes.push(1);
+			write_string(bytes, string);
+		},
+		None => bytes.push(0),
+	}
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) -> Result<(), String> {
+	write_length(bytes, chunk.code.len());
+	bytes.extend_from_slice(&chunk.code);
+	write_length(bytes, chunk.lines.len());
+	for line in &chunk.lines {
+		bytes.extend_from_slice(&line.number.to_be_bytes());
+		write_length(bytes, line.length);
+	}
+	write_length(bytes, chunk.globals.len());
+	for global in &chunk.globals {
+		write_string(bytes, global);
+	}
+	write_length(bytes, chunk.constants.len());
+	for constant in &chunk.constants {
+		write_constant(bytes, constant)?;
+	}
+	Ok(())
+}
+
+fn write_constant(bytes: &mut Vec<u8>, constant: &Value) -> Result<(), String> {
+	match constant {
+		Value::NIL => bytes.push(NIL),
+		Value::BOOL(bool) => bytes.push(if *bool {TRUE} else {FALSE}),
+		Value::NUMBER(number) => {
+			bytes.push(NUMBER);
+			bytes.extend_from_slice(&number.to_bits().to_be_bytes());
+		},
+		Value::STRING(string) => {
+			bytes.push(STRING);
+			write_string(bytes, string);
+		},
+		Value::CHAR(char) => {
+			bytes.push(CHAR);
+			bytes.extend_from_slice(&(*char as u32).to_be_bytes());
+		},
+		Value::FUNCTION(function) => {
+			bytes.push(FUNCTION);
+			bytes.push(function.arity);
+			bytes.push(function.required);
+			write_length(bytes, function.params.len());
+			for param in &function.params {
+				write_string(bytes, &param.name);
+				write_option(bytes, &param.default);
+			}
+			write_option(bytes, &function.rest);
+			write_option(bytes, &function.name);
+			write_length(bytes, function.upvalue_count);
+			bytes.push(function.generator as u8);
+			write_chunk(bytes, &function.chunk)?;
+		},
+		Value::ENUM(enumeration) => {
+			bytes.push(ENUM);
+			write_string(bytes, &enumeration.name);
+			write_length(bytes, enumeration.variants.len());
+			for variant in &enumeration.variants {
+				write_string(bytes, &variant.name);
+				write_length(bytes, variant.fields.len());
+				for field in &variant.fields {
+					write_string(bytes, field);
+				}
+			}
+		},
+		// Natives are found by name again when loading
+		Value::NATIVE(native) => {
+			bytes.push(NATIVE);
+			write_string(bytes, native.name);
+		},
+		// The compiler doesn't make constants of these
+		_ => return Err(format!("can't save constant {}", constant)),
+	}
+	Ok(())
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+	offset: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+		if self.bytes.len() - self.offset < length {
+			return Err("unexpected end of the file".to_owned());
+		}
+		self.offset += length;
+		Ok(&self.bytes[self.offset - length..self.offset])
+	}
+
+	fn byte(&mut self) -> Result<u8, String> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u32(&mut self) -> Result<u32, String> {
+		let bytes = self.take(4)?;
+		Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+
+	fn length(&mut self) -> Result<usize, String> {
+		Ok(self.u32()? as usize)
+	}
+
+	fn bool(&mut self) -> Result<bool, String> {
+		match self.byte()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			byte => Err(format!("invalid boolean {}", byte)),
+		}
+	}
+
+	fn string(&mut self) -> Result<String, String> {
+		let length = self.length()?;
+		let bytes = self.take(length)?;
+		String::from_utf8(bytes.to_vec()).map_err(|_| "a string isn't valid UTF-8".to_owned())
+	}
+
+	fn option(&mut self) -> Result<Option<String>, String> {
+		if self.bool()? {
+			Ok(Some(self.string()?))
+		} else {
+			Ok(None)
+		}
+	}
+
+	fn chunk(&mut self) -> Result<Chunk, String> {
+		let length = self.length()?;
+		let code = self.take(length)?.to_vec();
+		let mut lines = vec![];
+		for _ in 0..self.length()? {
+			let number = self.u32()?;
+			lines.push(Line {number, length: self.length()?});
+		}
+		let mut globals = vec![];
+		for _ in 0..self.length()? {
+			globals.push(self.string()?);
+		}
+		// Counts come from the file, so nothing is allocated for them up front
+		let mut constants = vec![];
+		for _ in 0..self.length()? {
+			constants.push(self.constant()?);
+		}
+		Ok(Chunk {code, constants, lines, globals})
+	}
+
+	fn constant(&mut self) -> Result<Value, String> {
+		Ok(match self.byte()? {
+			NIL => Value::NIL,
+			FALSE => Value::BOOL(false),
+			TRUE => Value::BOOL(true),
+			NUMBER => {
+				let bytes = self.take(8)?;
+				Value::NUMBER(f64::from_bits(u64::from_be_bytes(bytes.try_into().unwrap())))
+			},
+			STRING => Value::STRING(self.string()?),
+			CHAR => {
+				let code = self.u32()?;
+				Value::CHAR(char::from_u32(code).ok_or_else(|| format!("invalid char {:#x}", code))?)
+			},
+			FUNCTION => {
+				let arity = self.byte()?;
+				let required = self.byte()?;
+				let mut params = vec![];
+				for _ in 0..self.length()? {
+					let name = self.string()?;
+					params.push(Parameter {name, default: self.option()?});
+				}
+				if params.len() != arity as usize || required > arity {
+					return Err("the parameters of a function don't match its arity".to_owned());
+				}
+				let rest = self.option()?;
+				let name = self.option()?;
+				let upvalue_count = self.length()?;
+				let generator = self.bool()?;
+				let chunk = self.chunk()?;
+				Value::FUNCTION(Rc::new(Function {arity, required, params, rest, chunk, name, upvalue_count, generator, decoded: OnceCell::new()}))
+			},
+			ENUM => {
+				let name = self.string()?;
+				let mut variants = vec![];
+				for _ in 0..self.length()? {
+					let name = self.string()?;
+					let mut fields = vec![];
+					for _ in 0..self.length()? {
+						fields.push(self.string()?);
+					}
+					variants.push(Variant {name, fields});
+				}
+				Value::ENUM(Rc::new(Enum {name, variants}))
+			},
+			NATIVE => {
+				let name = self.string()?;
+				Value::NATIVE(native::find(&name).ok_or_else(|| format!("there is no native function {}", name))?)
+			},
+			tag => return Err(format!("unknown constant tag {}", tag)),
+		})
+	}
+}
+
+/// CRC-32 as used by zip and png
+fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for byte in bytes {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 == 1 {crc >> 1 ^ 0xedb8_8320} else {crc >> 1};
+		}
+	}
+	!crc
+}
@@ -0,0 +1,150 @@
+use std::fmt::Write;
+use crate::vm::chunk::Chunk;
+use crate::vm::op_codes::*;
+use crate::vm::value::Value;
+use crate::vm::verifier;
+
+/*
+The control flow graph of a chunk, written as Graphviz DOT to see where the jumps go.
+The code is split into basic blocks, which only start at the first op or a jump target
+and only end with a jump, a return or right before another block.
+Every function gets its own cluster, with the script first.
+The two edges of a conditional jump are labelled with the outcome of its test,
+green for true, less, not nil or passed and red for the opposite. The other edges are black.
+*/
+
+/// Write the graphs of a chunk and the functions in it
+pub fn to_dot(chunk: &Chunk, name: &str) -> Result<String, String> {
+	// Jumps have to land on an instruction to split the code at them.
+	// The rest isn't verified, as code that goes wrong is what the graph is drawn for.
+	verifier::verify_code(chunk).map_err(|error| format!("Invalid bytecode, {}", error))?;
+
+	let mut dot = String::from("digraph cfg {\n");
+	dot.push_str("\tnode [shape=box, fontname=\"monospace\"];\n");
+	let mut count = 0;
+	write_chunk(&mut dot, chunk, name, &mut count);
+	dot.push_str("}\n");
+	Ok(dot)
+}
+
+fn write_chunk(dot: &mut String, chunk: &Chunk, name: &str, count: &mut usize) {
+	let id = *count;
+	*count += 1;
+	writeln!(dot, "\tsubgraph cluster_{} {{", id).unwrap();
+	writeln!(dot, "\t\tlabel=\"{}\";", escape(name)).unwrap();
+
+	let blocks = blocks(chunk);
+	for (i, &(start, end)) in blocks.iter().enumerate() {
+		let mut label = String::new();
+		let mut line = None;
+		let mut offset = start;
+		while offset < end {
+			let (text, next) = disassemble(chunk, offset);
+			// The line is shown at the start of the block and when it changes
+			let number = chunk.line_at(offset);
+			let column = if number == line {"   |".to_owned()} else {format!("{:>4}", number.unwrap_or(0))};
+			line = number;
+			let instruction = text.trim_start().split_once(' ').map_or("", |(_, rest)| rest.trim_end());
+			label.push_str(&escape(&format!("{} {}", column, instruction)));
+			label.push_str("\\l");
+			offset = next;
+		}
+		writeln!(dot, "\t\tf{}_{} [label=\"{}\"];", id, i, label).unwrap();
+	}
+
+	for (i, &(start, end)) in blocks.iter().enumerate() {
+		let last = last_instruction(chunk, start, end);
+		let op = chunk.code[last];
+		let block_at = |offset: usize| blocks.iter().position(|&(start, _)| start == offset);
+		let next = block_at(end);
+		let target = read_jump(chunk, last).and_then(|(jump, length)| block_at((last as i64 + length as i64 + jump) as usize));
+		let edge = |dot: &mut String, to: Option<usize>, attributes: &str| {
+			if let Some(to) = to {
+				writeln!(dot, "\t\tf{}_{} -> f{}_{}{};", id, i, id, to, attributes).unwrap();
+			}
+		};
+		match branch(op) {
+			Some((jumps_when, falls_through_when)) => {
+				edge(dot, target, &outcome(jumps_when));
+				edge(dot, next, &outcome(falls_through_when));
+			},
+			None if op == JUMP || op == JUMPLONG => edge(dot, target, ""),
+			None if op == RETURN || op == RETURNREG || op == NOMATCH => {},
+			None => edge(dot, next, ""),
+		}
+	}
+	dot.push_str("\t}\n");
+
+	for constant in &chunk.constants {
+		if let Value::FUNCTION(function) = constant {
+			write_chunk(dot, &function.chunk, &function.signature(), count);
+		}
+	}
+}
+
+/// The attributes of an edge for an outcome of a test
+fn outcome(label: &str) -> String {
+	let color = match label {
+		"false" | "not less" | "nil" | "missing" => "red",
+		_ => "green",
+	};
+	format!(" [label=\"{}\", color={}, fontcolor={}]", label, color, color)
+}
+
+/// The outcomes of the test of a conditional jump, for when it jumps and when it doesn't
+fn branch(op: OpCode) -> Option<(&'static str, &'static str)> {
+	match short_jump(op) {
+		JUMPIFFALSE | JUMPIFFALSEPOP | JUMPIFFALSEREG | JUMPUNLESSREG | JUMPUNLESSREGCONST => Some(("false", "true")),
+		LESSLOCALSJUMP | LESSLOCALCONSTJUMP => Some(("not less", "less")),
+		JUMPIFNIL => Some(("nil", "not nil")),
+		JUMPIFNOTNIL => Some(("not nil", "nil")),
+		JUMPIFPASSED => Some(("passed", "missing")),
+		_ => None,
+	}
+}
+
+/// The start and end offset of every basic block
+fn blocks(chunk: &Chunk) -> Vec<(usize, usize)> {
+	let code = &chunk.code;
+	let mut starts = vec![false; code.len() + 1];
+	starts[0] = true;
+	let mut offset = 0;
+	while offset < code.len() {
+		let next = offset + length(chunk, offset);
+		if let Some((jump, length)) = read_jump(chunk, offset) {
+			starts[(offset as i64 + length as i64 + jump) as usize] = true;
+			starts[next] = true;
+		}
+		if let RETURN | RETURNREG | NOMATCH = code[offset] {
+			starts[next] = true;
+		}
+		offset = next;
+	}
+
+	let mut blocks = vec![];
+	let mut start = 0;
+	for (offset, &starts_block) in starts.iter().enumerate().skip(1) {
+		if starts_block {
+			blocks.push((start, offset));
+			start = offset;
+		}
+	}
+	blocks
+}
+
+/// The offset of the last instruction of a block
+fn last_instruction(chunk: &Chunk, start: usize, end: usize) -> usize {
+	let mut offset = start;
+	loop {
+		let next = offset + length(chunk, offset);
+		if next >= end {
+			return offset;
+		}
+		offset = next;
+	}
+}
+
+/// Make text fit in a quoted DOT string
+fn escape(text: &str) -> String {
+	text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -5,13 +5,31 @@ use crate::vm::op_codes::OpCode;
 //Note, the current implementation in rust makes a vector double capacity when full
 /// A Chunk is compiled by the compiler and used by the VM
 /// It holds everything necessary to run the code and produce runtime error messages
+#[derive(Debug)]
 pub struct Chunk {
 	pub code: Vec<OpCode>,
 	pub constants: Vec<Value>,
-	pub lines: Vec<Line>
+	pub lines: Vec<Line>,
+	/// Names of the globals by index, only the script has them
+	pub globals: Vec<String>,
+}
+
+/// An instruction taken out of the code, so the code can be rewritten.
+/// Jumps point to an instruction instead of having an offset.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+	/// Jumps always have their short form here
+	pub op: OpCode,
+	/// Operands, for a jump these are the ones before the offset
+	pub operands: Vec<OpCode>,
+	/// Index of the instruction a jump goes to,
+	/// the amount of instructions for a jump to the end
+	pub target: Option<usize>,
+	pub line: u32,
 }
 
 /// The linenumber and the index of the last op of this line
+#[derive(Debug)]
 pub struct Line {
 	pub number: u32,
 	pub length: usize
@@ -22,20 +40,30 @@ impl Chunk {
 		Chunk{
 			code: Vec::<OpCode>::new(),
 			constants: Vec::<Value>::new(),
-			lines: Vec::<Line>::new()
+			lines: Vec::<Line>::new(),
+			globals: Vec::<String>::new(),
 		}
 	}
 	pub fn disassemble(&self, name: &str) -> String {
 		//header
 		let mut str = format!("== {} ==\n", name);
 
-		// Uncomment this to show constant table
-		// let mut i = 0;
-		// while i < self.constants.len() {
-		// 	let constant = &self.constants[i];
-		// 	str.push_str(&format!("constant {}: {}\n", i, constant.to_string()));
-		// 	i += 1;
-		// }
+		// The globals and constants are written so they can be read back by the assembler
+		if !self.globals.is_empty() {
+			str.push_str(".globals\n");
+			for (i, global) in self.globals.iter().enumerate() {
+				str.push_str(&format!("{:04} {}\n", i, global));
+			}
+		}
+		if !self.constants.is_empty() {
+			str.push_str(".const\n");
+			for (i, constant) in self.constants.iter().enumerate() {
+				str.push_str(&format!("{:04} {}\n", i, constant_entry(constant)));
+			}
+		}
+		if !self.globals.is_empty() || !self.constants.is_empty() {
+			str.push_str(".code\n");
+		}
 
 		let mut offset = 0;
 		while offset < self.code.len() {
@@ -44,6 +72,13 @@ impl Chunk {
 			str.push_str(line.as_str());
 		}
 
+		// Functions defined in this chunk have their own chunk
+		for constant in &self.constants {
+			if let Value::FUNCTION(function) = constant {
+				str.push_str(&function.chunk.disassemble(&function.signature()));
+			}
+		}
+
 		str
 	}
 
@@ -54,6 +89,121 @@ impl Chunk {
 		self.constants.len() -1
 	}
 
+	/// Find a constant equal to a value.
+	/// Only numbers, strings and chars are looked for,
+	/// other constants like functions have to stay separate.
+	pub fn find_constant(&self, value: &Value) -> Option<usize> {
+		self.constants.iter().position(|constant| match (constant, value) {
+			// Compared by bits so a NaN is found, and 0 and -0 are kept apart
+			(Value::NUMBER(a), Value::NUMBER(b)) => a.to_bits() == b.to_bits(),
+			(Value::STRING(a), Value::STRING(b)) => a == b,
+			(Value::CHAR(a), Value::CHAR(b)) => a == b,
+			_ => false,
+		})
+	}
+
+	/// Remove the ops from an offset on, with their lines
+	pub fn truncate(&mut self, len: usize) {
+		self.code.truncate(len);
+		while self.lines.len() > 1 && self.lines[self.lines.len() - 2].length + 1 >= len {
+			self.lines.pop();
+		}
+		if len == 0 {
+			self.lines.clear();
+		} else if let Some(line) = self.lines.last_mut() {
+			line.length = line.length.min(len - 1);
+		}
+	}
+
+	/// Split the code into instructions
+	pub fn decode(&self) -> Vec<Instruction> {
+		let mut instructions = vec![];
+		// Offsets of every instruction and jump destination
+		let mut offsets = vec![];
+		let mut destinations = vec![];
+		let mut offset = 0;
+		while offset < self.code.len() {
+			let length = op_codes::length(self, offset);
+			let op = self.code[offset];
+			let jump = op_codes::read_jump(self, offset);
+			instructions.push(Instruction {
+				op: op_codes::short_jump(op),
+				operands: match jump {
+					Some(_) => self.code[offset + 1..offset + 1 + op_codes::jump_operands(op)].to_vec(),
+					None => self.code[offset + 1..offset + length].to_vec(),
+				},
+				target: None,
+				line: self.line_at(offset).unwrap_or(0),
+			});
+			offsets.push(offset);
+			destinations.push(jump.map(|(jump, _)| (offset + length) as i64 + jump));
+			offset += length;
+		}
+		offsets.push(offset);
+
+		for (instruction, destination) in instructions.iter_mut().zip(destinations) {
+			instruction.target = destination.map(|destination| {
+				offsets.binary_search(&(destination as usize)).expect("jump into the middle of an instruction")
+			});
+		}
+		instructions
+	}
+
+	/// Replace the code with instructions,
+	/// jumps get the long form only if the short form can't reach
+	pub fn encode(&mut self, instructions: &[Instruction]) {
+		let mut long = vec![false; instructions.len()];
+		let offsets = loop {
+			let mut offsets = Vec::with_capacity(instructions.len() + 1);
+			let mut offset = 0;
+			for (i, instruction) in instructions.iter().enumerate() {
+				offsets.push(offset);
+				offset += 1 + instruction.operands.len();
+				if instruction.target.is_some() {
+					offset += if long[i] {4} else {2};
+				}
+			}
+			offsets.push(offset);
+
+			// Making a jump longer can push other jumps out of reach
+			let mut changed = false;
+			for (i, instruction) in instructions.iter().enumerate() {
+				if let Some(target) = instruction.target {
+					let jump = offsets[target] as i64 - offsets[i + 1] as i64;
+					if !long[i] && i16::try_from(jump).is_err() {
+						long[i] = true;
+						changed = true;
+					}
+				}
+			}
+			if !changed {
+				break offsets;
+			}
+		};
+
+		self.code.clear();
+		self.lines.clear();
+		for (i, instruction) in instructions.iter().enumerate() {
+			let op = if long[i] {op_codes::long_jump(instruction.op)} else {instruction.op};
+			self.push_op(op, instruction.line);
+			for operand in &instruction.operands {
+				self.push_op(*operand, instruction.line);
+			}
+			if let Some(target) = instruction.target {
+				let jump = offsets[target] as i64 - offsets[i + 1] as i64;
+				let bytes = if long[i] {(jump as i32).to_be_bytes().to_vec()} else {(jump as i16).to_be_bytes().to_vec()};
+				for byte in bytes {
+					self.push_op(byte, instruction.line);
+				}
+			}
+		}
+	}
+
+	/// Find the line an op was compiled from
+	pub fn line_at(&self, offset: usize) -> Option<u32> {
+		self.lines.iter().find(|line| line.length >= offset).map(|line| line.number)
+	}
+
 	pub fn push_op(&mut self, op: OpCode, line: u32) -> usize {
 		self.code.push(op);
 
@@ -76,6 +226,43 @@ impl Chunk {
 
 }
 
+/// A constant in the .const section of the disassembly, like `string "a"` or `fun f(a, b = "1") required 1 upvalues 0`
+fn constant_entry(constant: &Value) -> String {
+	match constant {
+		Value::NIL => "nil".to_owned(),
+		Value::BOOL(bool) => bool.to_string(),
+		// There are many NaNs, the bits are kept for the ones that don't print as NaN
+		Value::NUMBER(number) if number.is_nan() && number.to_bits() != f64::NAN.to_bits() => {
+			format!("number {:#x}", number.to_bits())
+		},
+		Value::NUMBER(number) => format!("number {}", number),
+		Value::STRING(string) => format!("string {:?}", string),
+		Value::CHAR(char) => format!("char {:?}", char),
+		Value::FUNCTION(function) => {
+			let mut params: Vec<String> = function.params.iter().map(|param| match &param.default {
+				Some(default) => format!("{} = {:?}", param.name, default),
+				None => param.name.clone(),
+			}).collect();
+			if let Some(rest) = &function.rest {
+				params.push(format!("...{}", rest));
+			}
+			let generator = if function.generator {" generator"} else {""};
+			format!("fun {}({}) required {} upvalues {}{}",
+				function.name.as_deref().unwrap_or(""), params.join(", "), function.required, function.upvalue_count, generator)
+		},
+		Value::ENUM(enumeration) => {
+			let variants: Vec<String> = enumeration.variants.iter().map(|variant| match variant.fields.len() {
+				0 => variant.name.clone(),
+				_ => format!("{}({})", variant.name, variant.fields.join(", ")),
+			}).collect();
+			format!("enum {} {{ {} }}", enumeration.name, variants.join(", "))
+		},
+		Value::NATIVE(native) => format!("native {}", native.name),
+		// The compiler doesn't make constants of other values
+		_ => constant.to_string(),
+	}
+}
+
 //pub type Chunk = Vec<OpCode>;
 
 /* //I can't impl a method directly because this is an alias type
@@ -96,6 +283,13 @@ impl DisassembleChunk for Chunk {
 			str.push_str(line.as_str());
 		}
 
+		// Functions defined in this chunk have their own chunk
+		for constant in &self.constants {
+			if let Value::FUNCTION(function) = constant {
+				str.push_str(&function.chunk.disassemble(&function.to_string()));
+			}
+		}
+
 		str
 	}
 } */
@@ -0,0 +1,251 @@
+use crate::vm::chunk::Chunk;
+use crate::vm::op_codes::*;
+
+/*
+The code of a function as the VM runs it.
+Every instruction is decoded once, the first time its function runs,
+so the VM doesn't read operands byte by byte or follow relative offsets while it runs.
+Long and short forms become the same instruction and jumps go to the index of an instruction.
+Only verified chunks are decoded, which is what lets the VM skip the bounds checks.
+*/
+
+/// An instruction with its operands, see op_codes for what each one does.
+/// Slots and upvalues are indexes like in the bytecode, the u16 operands are constants,
+/// globals or locals and the u32 operands are the index of the instruction a jump goes to.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+	Return,
+	Yield,
+	Constant(u16),
+	Negate,
+	Add,
+	Subtract,
+	Multiply,
+	Divide,
+	Greater,
+	Less,
+	GreaterEqual,
+	LessEqual,
+	Nil,
+	True,
+	False,
+	Not,
+	Equal,
+	NotEqual,
+	Print,
+	Pop,
+	Dup,
+	/// The slot and the constant
+	IncrLocal(u8, u8),
+	AddLocalConst(u8, u8),
+	SubtractLocalConst(u8, u8),
+	/// The target and source slot
+	MoveReg(u8, u8),
+	LoadReg(u8, u8),
+	/// The target slot and the slots or constant of the operands
+	AddReg(u8, u8, u8),
+	AddRegConst(u8, u8, u8),
+	SubtractReg(u8, u8, u8),
+	SubtractRegConst(u8, u8, u8),
+	MultiplyReg(u8, u8, u8),
+	MultiplyRegConst(u8, u8, u8),
+	DivideReg(u8, u8, u8),
+	DivideRegConst(u8, u8, u8),
+	LessLocalsJump(u8, u8, u32),
+	LessLocalConstJump(u8, u8, u32),
+	BuildList(u8),
+	BuildMap(u8),
+	/// The amount of items before the rest and if there is a rest
+	UnpackList(u8, bool),
+	/// The amount of keys and where their constants start in the names of the code
+	UnpackMap(u8, u32),
+	UnpackVariant(u8),
+	DefGlobal(u16),
+	GetGlobal(u16),
+	SetGlobal(u16),
+	GetLocal(u16),
+	SetLocal(u16),
+	Jump(u32),
+	JumpIfFalse(u32),
+	JumpIfFalsePop(u32),
+	JumpIfNil(u32),
+	JumpIfNotNil(u32),
+	JumpIfPassed(u32),
+	GetProperty(u16),
+	Match,
+	NoMatch,
+	Leave(u8),
+	Call(u8),
+	TailCall(u8),
+	/// The positional and named argument count and where the names start
+	CallNamed(u8, u8, u32),
+	/// The function constant and where its captures start in the captures of the code
+	Closure(u16, u32),
+	GetUpvalue(u8),
+	SetUpvalue(u8),
+	/// The operator, the register to store in and the sources
+	Binary(OpCode, u8, Source, Source),
+	Unary(OpCode, u8, Source),
+	ReturnReg(Source),
+	JumpIfFalseReg(u8, u32),
+	/// The comparison, the registers or the register and constant, and the target
+	JumpUnlessReg(OpCode, u8, u8, u32),
+	JumpUnlessRegConst(OpCode, u8, u8, u32),
+}
+
+/// A source of a register op
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+	Register(u8),
+	Constant(u8),
+}
+
+// Small instructions keep more of the code in the cache
+const _: () = assert!(std::mem::size_of::<Op>() == 8);
+
+/// A capture of a closure, from the locals of the enclosing function or from its upvalues
+#[derive(Debug, Clone, Copy)]
+pub struct Capture {
+	pub is_local: bool,
+	pub index: u16,
+}
+
+#[derive(Debug)]
+pub struct Code {
+	pub ops: Vec<Op>,
+	/// The line of every instruction, for errors
+	pub lines: Vec<u32>,
+	/// Constant indexes of map keys and named arguments
+	pub names: Vec<u16>,
+	pub captures: Vec<Capture>,
+}
+
+impl Code {
+	/// Decode a verified chunk
+	pub fn new(chunk: &Chunk) -> Code {
+		let instructions = chunk.decode();
+		let mut code = Code {
+			ops: Vec::with_capacity(instructions.len()),
+			lines: Vec::with_capacity(instructions.len()),
+			names: vec![],
+			captures: vec![],
+		};
+		for instruction in &instructions {
+			let operands = &instruction.operands;
+			let byte = |n: usize| operands[n];
+			// Operands in two bytes in the long forms
+			let word = |n: usize| u16::from_be_bytes([operands[n], operands[n + 1]]);
+			let index = || if operands.len() == 2 {word(0)} else {byte(0) as u16};
+			let target = || instruction.target.unwrap() as u32;
+			// The kinds come right before the sources
+			let source = |n: usize| {
+				let (kinds, sources) = operands[operands.len() - sources(instruction.op) - 1..].split_first().unwrap();
+				if is_constant(*kinds, n) {Source::Constant(sources[n])} else {Source::Register(sources[n])}
+			};
+			let op = match instruction.op {
+				RETURN => Op::Return,
+				YIELD => Op::Yield,
+				CONSTANT | CONSTANTLONG => Op::Constant(index()),
+				NEGATE => Op::Negate,
+				ADD => Op::Add,
+				SUBTRACT => Op::Subtract,
+				MULTIPLY => Op::Multiply,
+				DIVIDE => Op::Divide,
+				GREATER => Op::Greater,
+				LESS => Op::Less,
+				GREATEREQUAL => Op::GreaterEqual,
+				LESSEQUAL => Op::LessEqual,
+				NIL => Op::Nil,
+				TRUE => Op::True,
+				FALSE => Op::False,
+				NOT => Op::Not,
+				EQUAL => Op::Equal,
+				NOTEQUAL => Op::NotEqual,
+				PRINT => Op::Print,
+				POP => Op::Pop,
+				DUP => Op::Dup,
+				INCRLOCAL => Op::IncrLocal(byte(0), byte(1)),
+				ADDLOCALCONST => Op::AddLocalConst(byte(0), byte(1)),
+				SUBTRACTLOCALCONST => Op::SubtractLocalConst(byte(0), byte(1)),
+				MOVEREG => Op::MoveReg(byte(0), byte(1)),
+				LOADREG => Op::LoadReg(byte(0), byte(1)),
+				ADDREG => Op::AddReg(byte(0), byte(1), byte(2)),
+				ADDREGCONST => Op::AddRegConst(byte(0), byte(1), byte(2)),
+				SUBTRACTREG => Op::SubtractReg(byte(0), byte(1), byte(2)),
+				SUBTRACTREGCONST => Op::SubtractRegConst(byte(0), byte(1), byte(2)),
+				MULTIPLYREG => Op::MultiplyReg(byte(0), byte(1), byte(2)),
+				MULTIPLYREGCONST => Op::MultiplyRegConst(byte(0), byte(1), byte(2)),
+				DIVIDEREG => Op::DivideReg(byte(0), byte(1), byte(2)),
+				DIVIDEREGCONST => Op::DivideRegConst(byte(0), byte(1), byte(2)),
+				LESSLOCALSJUMP => Op::LessLocalsJump(byte(0), byte(1), target()),
+				LESSLOCALCONSTJUMP => Op::LessLocalConstJump(byte(0), byte(1), target()),
+				BUILDLIST => Op::BuildList(byte(0)),
+				BUILDMAP => Op::BuildMap(byte(0)),
+				UNPACKLIST => Op::UnpackList(byte(0), byte(1) == 1),
+				UNPACKVARIANT => Op::UnpackVariant(byte(0)),
+				DEFGLOBAL | DEFGLOBALLONG => Op::DefGlobal(index()),
+				GETGLOBAL | GETGLOBALLONG => Op::GetGlobal(index()),
+				SETGLOBAL | SETGLOBALLONG => Op::SetGlobal(index()),
+				GETLOCAL | GETLOCALLONG => Op::GetLocal(index()),
+				SETLOCAL | SETLOCALLONG => Op::SetLocal(index()),
+				JUMP => Op::Jump(target()),
+				JUMPIFFALSE => Op::JumpIfFalse(target()),
+				JUMPIFFALSEPOP => Op::JumpIfFalsePop(target()),
+				JUMPIFNIL => Op::JumpIfNil(target()),
+				JUMPIFNOTNIL => Op::JumpIfNotNil(target()),
+				JUMPIFPASSED => Op::JumpIfPassed(target()),
+				GETPROPERTY | GETPROPERTYLONG => Op::GetProperty(index()),
+				MATCH => Op::Match,
+				NOMATCH => Op::NoMatch,
+				LEAVE => Op::Leave(byte(0)),
+				CALL => Op::Call(byte(0)),
+				TAILCALL => Op::TailCall(byte(0)),
+				UNPACKMAP | UNPACKMAPLONG => {
+					let start = code.names.len() as u32;
+					code.push_names(&operands[1..], instruction.op == UNPACKMAPLONG);
+					Op::UnpackMap(byte(0), start)
+				},
+				CALLNAMED | CALLNAMEDLONG => {
+					let start = code.names.len() as u32;
+					code.push_names(&operands[2..], instruction.op == CALLNAMEDLONG);
+					Op::CallNamed(byte(0), byte(1), start)
+				},
+				CLOSURE | CLOSURELONG => {
+					let (index, captures) = if instruction.op == CLOSURELONG {
+						(word(0), &operands[2..])
+					} else {
+						(byte(0) as u16, &operands[1..])
+					};
+					let start = code.captures.len() as u32;
+					for capture in captures.chunks(3) {
+						code.captures.push(Capture {
+							is_local: capture[0] == 1,
+							index: u16::from_be_bytes([capture[1], capture[2]]),
+						});
+					}
+					Op::Closure(index, start)
+				},
+				GETUPVALUE => Op::GetUpvalue(byte(0)),
+				SETUPVALUE => Op::SetUpvalue(byte(0)),
+				BINARYREG => Op::Binary(byte(0), byte(1), source(0), source(1)),
+				UNARYREG => Op::Unary(byte(0), byte(1), source(0)),
+				RETURNREG => Op::ReturnReg(source(0)),
+				JUMPIFFALSEREG => Op::JumpIfFalseReg(byte(0), target()),
+				JUMPUNLESSREG => Op::JumpUnlessReg(byte(0), byte(1), byte(2), target()),
+				JUMPUNLESSREGCONST => Op::JumpUnlessRegConst(byte(0), byte(1), byte(2), target()),
+				op => unreachable!("op {:#x} got past the verifier", op),
+			};
+			code.ops.push(op);
+			code.lines.push(instruction.line);
+		}
+		code
+	}
+
+	fn push_names(&mut self, operands: &[u8], long: bool) {
+		if long {
+			self.names.extend(operands.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])));
+		} else {
+			self.names.extend(operands.iter().map(|index| *index as u16));
+		}
+	}
+}
@@ -0,0 +1,65 @@
+use std::rc::Rc;
+use crate::vm::value::Value;
+
+/// An enum declaration, this is a constant made by the compiler
+#[derive(Debug)]
+pub struct Enum {
+	pub name: String,
+	pub variants: Vec<Variant>,
+}
+
+/// A variant of an enum, with the names of its payload
+#[derive(Debug)]
+pub struct Variant {
+	pub name: String,
+	pub fields: Vec<String>,
+}
+
+/// A variant of an enum at runtime.
+/// A variant with fields but without a payload is the constructor of that variant.
+#[derive(Debug)]
+pub struct EnumValue {
+	pub enumeration: Rc<Enum>,
+	pub variant: usize,
+	pub payload: Vec<Value>,
+}
+
+// Enums are only equal to themselves
+impl PartialEq for Enum {
+	fn eq(&self, other: &Enum) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+impl PartialEq for EnumValue {
+	fn eq(&self, other: &EnumValue) -> bool {
+		Rc::ptr_eq(&self.enumeration, &other.enumeration)
+			&& self.variant == other.variant
+			&& self.payload == other.payload
+	}
+}
+
+impl EnumValue {
+	pub fn definition(&self) -> &Variant {
+		&self.enumeration.variants[self.variant]
+	}
+
+	/// If calling this creates a variant with a payload
+	pub fn is_constructor(&self) -> bool {
+		self.payload.is_empty() && !self.definition().fields.is_empty()
+	}
+}
+
+impl std::fmt::Display for EnumValue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let name = format!("{}.{}", self.enumeration.name, self.definition().name);
+		if self.is_constructor() {
+			write!(f, "<constructor {}>", name)
+		} else if self.payload.is_empty() {
+			write!(f, "{}", name)
+		} else {
+			let payload: Vec<String> = self.payload.iter().map(|value| value.to_string()).collect();
+			write!(f, "{}({})", name, payload.join(", "))
+		}
+	}
+}
@@ -0,0 +1,130 @@
+use std::rc::Rc;
+use std::cell::{RefCell, OnceCell};
+use crate::vm::chunk::Chunk;
+use crate::vm::code::Code;
+use crate::vm::value::Slot;
+
+/// A compiled function, this lives in the constants of the enclosing chunk
+#[derive(Debug)]
+pub struct Function {
+	/// Number of parameters, not counting the rest parameter
+	pub arity: u8,
+	/// Number of parameters without a default value
+	pub required: u8,
+	pub params: Vec<Parameter>,
+	/// Name of the parameter collecting extra arguments
+	pub rest: Option<String>,
+	pub chunk: Chunk,
+	/// Anonymous functions don't have a name
+	pub name: Option<String>,
+	pub upvalue_count: usize,
+	/// If the function yields, calling it creates a generator
+	pub generator: bool,
+	/// The chunk decoded for the VM, made the first time the function runs
+	pub decoded: OnceCell<Code>,
+}
+
+/// A parameter and the source of its default value
+#[derive(Debug)]
+pub struct Parameter {
+	pub name: String,
+	pub default: Option<String>,
+}
+
+/// A function together with the variables it captured.
+/// This is what actually gets called at runtime.
+#[derive(Debug)]
+pub struct Closure {
+	pub function: Rc<Function>,
+	pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A captured variable.
+/// While the variable is still on the stack the upvalue points to it,
+/// when it goes out of scope the value is moved into the upvalue.
+#[derive(Debug)]
+pub enum Upvalue {
+	Open(usize),
+	Closed(Slot),
+}
+
+/// A suspended call of a function that yields.
+/// Calling the generator resumes it until it yields again.
+#[derive(Debug)]
+pub struct Generator {
+	pub closure: Rc<Closure>,
+	pub ip: usize,
+	pub state: GeneratorState,
+	/// The locals and temporaries of the call while it is suspended
+	pub stack: Vec<Slot>,
+	/// Upvalues pointing into the stack of the call,
+	/// with their offset from the first local
+	pub upvalues: Vec<(Rc<RefCell<Upvalue>>, usize)>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GeneratorState {
+	/// Not started yet
+	Created,
+	Suspended,
+	Running,
+	Done,
+}
+
+// Functions and closures are only equal to themselves
+impl PartialEq for Function {
+	fn eq(&self, other: &Function) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+impl PartialEq for Closure {
+	fn eq(&self, other: &Closure) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+impl PartialEq for Generator {
+	fn eq(&self, other: &Generator) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+impl Function {
+	/// The code the VM runs, the chunk has to be verified before this
+	pub fn code(&self) -> &Code {
+		self.decoded.get_or_init(|| Code::new(&self.chunk))
+	}
+
+	/// The amount of arguments this function takes, for error messages
+	pub fn expected_arguments(&self) -> String {
+		if self.rest.is_some() {
+			format!("at least {}", self.required)
+		} else if self.required == self.arity {
+			self.arity.to_string()
+		} else {
+			format!("{} to {}", self.required, self.arity)
+		}
+	}
+
+	/// The name with the parameters, like `f(a, b = 2, ...rest)`
+	pub fn signature(&self) -> String {
+		let mut params: Vec<String> = self.params.iter().map(|param| match &param.default {
+			Some(default) => format!("{} = {}", param.name, default),
+			None => param.name.clone(),
+		}).collect();
+		if let Some(rest) = &self.rest {
+			params.push(format!("...{}", rest));
+		}
+		format!("{}({})", self.name.as_deref().unwrap_or("fun"), params.join(", "))
+	}
+}
+
+impl std::fmt::Display for Function {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.name {
+			Some(name) => write!(f, "<fn {}>", name),
+			None => write!(f, "<fn>"),
+		}
+	}
+}
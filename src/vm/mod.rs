@@ -1,18 +1,112 @@
 pub mod chunk;
 pub mod op_codes;
 pub mod value;
+pub mod function;
+pub mod enums;
+pub mod native;
+pub mod verifier;
+pub mod bytecode;
+pub mod assembler;
+pub mod cfg;
+pub mod code;
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing;
 
+use std::rc::Rc;
+use std::cell::{RefCell, OnceCell};
+use std::collections::HashMap;
+use std::cmp::Ordering;
 use self::chunk::Chunk;
 use self::op_codes::*;
-use self::value::{Value, number};
+use self::code::{Op, Source};
+use self::value::{Value, Slot, number};
+use self::function::{Function, Closure, Upvalue, Generator, GeneratorState};
+use self::enums::EnumValue;
+use self::native::Native;
 
 pub const STACK_SIZE: usize = 1024;
 
+const BINARY_OPERANDS: &str = "Binary operands must both be numbers or both be strings";
+pub const FRAMES_MAX: usize = 64;
+
+/// + on two values, numbers are added and strings are joined
+fn add(a: &Slot, b: &Slot) -> std::result::Result<Slot, &'static str> {
+	if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+		return Ok(Slot::from(a + b));
+	}
+	match (a.to_value(), b.to_value()) {
+		(Value::STRING(a), Value::STRING(b)) => Ok(Slot::from(Value::from(a + &b))),
+		_ => Err(BINARY_OPERANDS)
+	}
+}
+
+/// The operator of a register op on two numbers
+fn numbers(operator: OpCode, a: number, b: number) -> Slot {
+	match operator {
+		ADD => Slot::from(a + b),
+		SUBTRACT => Slot::from(a - b),
+		MULTIPLY => Slot::from(a * b),
+		DIVIDE => Slot::from(a / b),
+		_ => Slot::from(Value::from(compare_numbers(operator, a, b))),
+	}
+}
+
+/// A comparison of a register op on two numbers
+fn compare_numbers(operator: OpCode, a: number, b: number) -> bool {
+	let ordering = a.partial_cmp(&b);
+	match operator {
+		EQUAL => a == b,
+		NOTEQUAL => a != b,
+		GREATER => ordering == Some(Ordering::Greater),
+		LESS => ordering == Some(Ordering::Less),
+		// The negation of the opposite like the stack ops, so these are true for NaN
+		GREATEREQUAL => ordering != Some(Ordering::Less),
+		_ => ordering != Some(Ordering::Greater),
+	}
+}
+
+/// The operator of a register op when the values aren't both numbers,
+/// with the same result and errors as its stack op
+fn binary(operator: OpCode, a: &Slot, b: &Slot) -> std::result::Result<Slot, &'static str> {
+	match operator {
+		ADD => add(a, b),
+		_ => compare(operator, a, b).map(|result| Slot::from(Value::from(result))),
+	}
+}
+
+/// A comparison of a register op when the values aren't both numbers
+fn compare(operator: OpCode, a: &Slot, b: &Slot) -> std::result::Result<bool, &'static str> {
+	match operator {
+		EQUAL => Ok(a.equal(b.clone())),
+		NOTEQUAL => Ok(!a.equal(b.clone())),
+		_ => Err(BINARY_OPERANDS),
+	}
+}
+
+/// A function call that is waiting for its callee to return
+struct CallFrame {
+	closure: Rc<Closure>,
+	ip: usize,
+	slots: usize,
+	elided: usize,
+}
+
 struct VM {
-	chunk: Chunk,
+	/// The closure currently being executed
+	closure: Rc<Closure>,
+	/// The decoded code of the current closure, this is what the ip points into
+	code: *const Op,
 	ip: usize,
-	stack: Vec<Value>,
-	globals: Vec<Value>,
+	/// Stack index of the first local of the current function
+	slots: usize,
+	/// Calls replaced by tail calls since the current function was called
+	elided: usize,
+	frames: Vec<CallFrame>,
+	stack: Vec<Slot>,
+	/// Globals that haven't been defined yet are None
+	globals: Vec<Option<Slot>>,
+	/// Upvalues that still point into the stack
+	open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
 }
 
 #[derive(Debug)]
@@ -23,171 +117,810 @@ pub enum Result {
 }
 
 pub fn interpret(chunk: Chunk) -> Result {
-	let mut vm = VM{
+	// The VM trusts the code it runs, so make sure the code can be trusted
+	if let Err(error) = verifier::verify(&chunk) {
+		eprintln!("Error: Invalid bytecode, {}", error);
+		return Result::RUNTIME_ERROR(error.to_string());
+	}
+
+	// The script itself is run as a function without arguments
+	let function = Function {
+		arity: 0,
+		required: 0,
+		params: vec![],
+		rest: None,
 		chunk,
+		name: None,
+		upvalue_count: 0,
+		generator: false,
+		decoded: OnceCell::new(),
+	};
+	let closure = Rc::new(Closure {
+		function: Rc::new(function),
+		upvalues: vec![],
+	});
+	let mut vm = VM{
+		code: closure.function.code().ops.as_ptr(),
+		closure,
 		ip: 0,
+		slots: 0,
+		elided: 0,
+		frames: Vec::with_capacity(FRAMES_MAX),
 		stack: Vec::with_capacity(STACK_SIZE),
 		globals: vec![],
+		open_upvalues: vec![],
 	};
 	vm.run()
 }
 
+// Converting between Value and Slot does nothing without the nan-boxing feature
+#[allow(clippy::useless_conversion)]
 impl VM {
 
 	fn run(&mut self) -> Result {
+		// Kept in locals while running, as that is a lot faster than going through self.
+		// The fields are updated before anything else uses them.
+		let mut ip = self.ip;
+		let mut code = self.code;
 
 		//#region
 		//Defining these macro's outside this function would
 		//make them need "self" as an argument
 		macro_rules! peek { ($distance:expr) => {self.stack[self.stack.len()-1 - $distance]}}
 		macro_rules! pop { () => {self.stack.pop().unwrap()};}
-		macro_rules! push {($value:expr) => {self.stack.push($value)};}
-		macro_rules! binary_op {($op:tt) => {{
-			if !matches!(peek!(0), Value::NUMBER(_)) || !matches!(peek!(1), Value::NUMBER(_)) {
-				return self.runtime_error("Binary operands must both be numbers or both be strings");
+		macro_rules! push {($value:expr) => {self.stack.push(Slot::from($value))};}
+		macro_rules! store {() => {self.ip = ip};}
+		macro_rules! load {() => {{
+			ip = self.ip;
+			code = self.code;
+		}};}
+		macro_rules! runtime_error {($msg:expr) => {{
+			store!();
+			return self.runtime_error($msg);
+		}};}
+		// Calls change the ip and code
+		macro_rules! call {($call:expr) => {{
+			store!();
+			let result = $call;
+			load!();
+			if let Err(msg) = result {
+				runtime_error!(msg);
 			}
-			let b = number::from(pop!());
-			let a = number::from(pop!());
-			push!(Value::from(a $op b));
 		}};}
-		macro_rules! read_byte {() => {{
-			self.ip += 1;
-			self.chunk.code[self.ip-1]
+		macro_rules! binary_op {($op:tt) => {{
+			let (Some(a), Some(b)) = (peek!(1).as_number(), peek!(0).as_number()) else {
+				runtime_error!(BINARY_OPERANDS);
+			};
+			pop!();
+			pop!();
+			push!(a $op b);
+		}};}
+		// The verifier checked that the index is in the constants
+		macro_rules! constant {($index:expr) => {{
+			let index = $index as usize;
+			unsafe {self.closure.function.chunk.constants.get_unchecked(index)}
 		}};}
-		macro_rules! read_word {() => {{
-			self.ip += 2;
-			((self.chunk.code[self.ip-2] as u16) << 8 | self.chunk.code[self.ip-1] as u16)
+		// A register or constant of a register op
+		macro_rules! source {($source:expr) => {
+			match $source {
+				Source::Register(register) => self.stack[self.slots + register as usize].clone(),
+				Source::Constant(index) => Slot::from(constant!(index).clone()),
+			}
+		};}
+		// A register or constant of a register op, if it is a number
+		macro_rules! number {($source:expr) => {
+			match $source {
+				Source::Register(register) => self.stack[self.slots + register as usize].as_number(),
+				Source::Constant(index) => constant!(index).as_number(),
+			}
+		};}
+		// The result of a register op replaces the temporaries it used
+		macro_rules! store_register {($register:expr, $value:expr) => {{
+			let value = $value;
+			self.stack.truncate(self.slots + $register as usize);
+			push!(value);
+		}};}
+		// The constants of the names of a map pattern or named arguments
+		macro_rules! names {($start:expr, $count:expr) => {{
+			let names = &self.closure.function.code().names[$start as usize..$start as usize + $count as usize];
+			names.iter().map(|index| String::from(constant!(*index).clone())).collect::<Vec<String>>()
 		}};}
-		macro_rules! read_constant {() => {&self.chunk.constants[read_byte!() as usize]};}
 		//#endregion
 
 		return loop {
 			
-			#[cfg(debug_assertions)]
+			#[cfg(feature = "trace")]
 			self.print_stack();
-			#[cfg(debug_assertions)]
-			let (dis_str, _) = disassemble(&self.chunk, self.ip);
-			#[cfg(debug_assertions)]
-			eprint!("{}", dis_str);
+			#[cfg(feature = "trace")]
+			eprintln!("{:04} {:?}", ip, unsafe {*code.add(ip)});
 			
-			let instruction = read_byte!();
+			// This reads without a bounds check.
+			// The verifier made sure that jumps land on an instruction and that the code can't run past its end.
+			let instruction = unsafe {*code.add(ip)};
+			ip += 1;
 			match instruction {
-				RETURN => {
-					//println!("{}\n", pop!());
-					break Result::OK;
-				},
-				CONSTANT => push!(read_constant!().clone()),
-				NEGATE => {
-					if matches!(peek!(0), Value::NUMBER(_)) {
-						//Grab the number, negate and convert back
-						let new = Value::from(-(number::from(pop!())));
-						push!(new);
-					} else {
-						return self.runtime_error("Operand must be a number");
-					}
-				},
-				SUBTRACT => binary_op!(-),
-				MULTIPLY => binary_op!(*),
-				DIVIDE => binary_op!(/),
-				GREATER => binary_op!(>),
-				LESS => binary_op!(<),
-				ADD => {
-					if matches!(peek!(0), Value::STRING(_)) && matches!(peek!(1), Value::STRING(_)) {
-						let b = String::from(pop!());
-						let a = String::from(pop!());
+				Op::Return | Op::ReturnReg(_) => {
+					if let Op::ReturnReg(value) = instruction {
+						let value = source!(value);
+						push!(value);
+					}
+					// Returning from the script itself
+					let Some(frame) = self.frames.pop() else {
+						break Result::OK;
+					};
+					let result = pop!();
+					if self.closure.function.generator {
+						if let Value::GENERATOR(generator) = self.stack[self.slots - 1].to_value() {
+							generator.borrow_mut().state = GeneratorState::Done;
+						}
+					}
+					self.close_upvalues(self.slots);
+					// Remove the locals, arguments and the callee
+					self.stack.truncate(self.slots - 1);
+					push!(result);
+					self.enter(frame.closure);
+					self.ip = frame.ip;
+					load!();
+					self.slots = frame.slots;
+					self.elided = frame.elided;
+				},
+				Op::Yield => {
+					let value = pop!();
+					// The generator being resumed is in the place of the callee
+					let generator = match self.stack[self.slots - 1].to_value() {
+						Value::GENERATOR(generator) => generator,
+						_ => unreachable!()
+					};
+					let mut generator = generator.borrow_mut();
+					generator.upvalues = self.suspend_upvalues(self.slots);
+					generator.stack = self.stack.split_off(self.slots);
+					generator.ip = ip;
+					generator.state = GeneratorState::Suspended;
+
+					// Return to the caller
+					let frame = self.frames.pop().unwrap();
+					self.stack.pop();
+					push!(value);
+					self.enter(frame.closure);
+					self.ip = frame.ip;
+					load!();
+					self.slots = frame.slots;
+					self.elided = frame.elided;
+				},
+				Op::Constant(index) => push!(constant!(index).clone()),
+				Op::Negate => {
+					match peek!(0).as_number() {
+						Some(number) => {
+							pop!();
+							push!(-number);
+						},
+						None => runtime_error!("Operand must be a number")
+					}
+				},
+				Op::Subtract => binary_op!(-),
+				Op::Multiply => binary_op!(*),
+				Op::Divide => binary_op!(/),
+				Op::Greater => binary_op!(>),
+				Op::Less => binary_op!(<),
+				// The negation of the opposite, like the LESS NOT these replace
+				Op::GreaterEqual => {
+					binary_op!(<);
+					let new = !pop!().is_truthy();
+					push!(Value::from(new));
+				},
+				Op::LessEqual => {
+					binary_op!(>);
+					let new = !pop!().is_truthy();
+					push!(Value::from(new));
+				},
+				Op::Add => {
+					if peek!(0).is_string() && peek!(1).is_string() {
+						let b = String::from(Value::from(pop!()));
+						let a = String::from(Value::from(pop!()));
 						push!(Value::from(format!("{}{}",a,b)));
 					} else {
 						binary_op!(+);
 					}
 				},
-				NIL => push!(Value::NIL),
-				TRUE => push!(Value::BOOL(true)),
-				FALSE => push!(Value::BOOL(false)),
-				NOT => {
+				Op::IncrLocal(slot, index) | Op::AddLocalConst(slot, index) => {
+					let slot = self.slots + slot as usize;
+					let constant = Slot::from(constant!(index).clone());
+					let value = match add(&self.stack[slot], &constant) {
+						Ok(value) => value,
+						Err(msg) => runtime_error!(msg)
+					};
+					if let Op::IncrLocal(..) = instruction {
+						self.stack[slot] = value;
+					} else {
+						push!(value);
+					}
+				},
+				Op::MoveReg(target, source) => {
+					self.stack[self.slots + target as usize] = self.stack[self.slots + source as usize].clone();
+				},
+				Op::LoadReg(target, index) => {
+					self.stack[self.slots + target as usize] = Slot::from(constant!(index).clone());
+				},
+				Op::AddReg(target, a, b) | Op::AddRegConst(target, a, b) => {
+					let b = match instruction {
+						Op::AddReg(..) => self.stack[self.slots + b as usize].clone(),
+						_ => Slot::from(constant!(b).clone()),
+					};
+					match add(&self.stack[self.slots + a as usize], &b) {
+						Ok(value) => self.stack[self.slots + target as usize] = value,
+						Err(msg) => runtime_error!(msg)
+					}
+				},
+				Op::SubtractReg(target, a, b) | Op::SubtractRegConst(target, a, b) |
+				Op::MultiplyReg(target, a, b) | Op::MultiplyRegConst(target, a, b) |
+				Op::DivideReg(target, a, b) | Op::DivideRegConst(target, a, b) => {
+					let a = self.stack[self.slots + a as usize].as_number();
+					let b = match instruction {
+						Op::SubtractReg(..) | Op::MultiplyReg(..) | Op::DivideReg(..) => self.stack[self.slots + b as usize].as_number(),
+						_ => constant!(b).as_number(),
+					};
+					let (Some(a), Some(b)) = (a, b) else {
+						runtime_error!(BINARY_OPERANDS);
+					};
+					self.stack[self.slots + target as usize] = Slot::from(match instruction {
+						Op::SubtractReg(..) | Op::SubtractRegConst(..) => a - b,
+						Op::MultiplyReg(..) | Op::MultiplyRegConst(..) => a * b,
+						_ => a / b,
+					});
+				},
+				Op::SubtractLocalConst(slot, index) => {
+					match (self.stack[self.slots + slot as usize].as_number(), constant!(index).as_number()) {
+						(Some(a), Some(b)) => push!(a - b),
+						_ => runtime_error!(BINARY_OPERANDS)
+					}
+				},
+				Op::LessLocalsJump(a, b, target) | Op::LessLocalConstJump(a, b, target) => {
+					let a = self.stack[self.slots + a as usize].as_number();
+					let b = match instruction {
+						Op::LessLocalsJump(..) => self.stack[self.slots + b as usize].as_number(),
+						_ => constant!(b).as_number(),
+					};
+					match (a, b) {
+						// Jumps for NaN too, like LESS followed by JUMPIFFALSEPOP
+						(Some(a), Some(b)) => if a.partial_cmp(&b) != Some(Ordering::Less) {
+							ip = target as usize;
+						},
+						_ => runtime_error!(BINARY_OPERANDS)
+					}
+				},
+				Op::Nil => push!(Value::NIL),
+				Op::True => push!(Value::BOOL(true)),
+				Op::False => push!(Value::BOOL(false)),
+				Op::Not => {
 					let new = !pop!().is_truthy();
 					push!(Value::from(new));
 				},
-				EQUAL => {
+				Op::Equal => {
 					let b = pop!();
 					let a = pop!();
 					push!(Value::BOOL(a.equal(b)));
 				},
-				PRINT => {
+				Op::NotEqual => {
+					let b = pop!();
+					let a = pop!();
+					push!(Value::BOOL(!a.equal(b)));
+				},
+				Op::Print => {
 					println!("{}",pop!());
 				},
-				POP => {
+				Op::Pop => {
 					pop!();
 				},
-				DEFGLOBAL => {
-					let index = read_byte!() as usize;
+				Op::Dup => push!(peek!(0).clone()),
+				Op::BuildList(n) => {
+					let items: Vec<Value> = self.stack.split_off(self.stack.len() - n as usize).into_iter().map(Value::from).collect();
+					push!(Value::from(items));
+				},
+				Op::BuildMap(n) => {
+					let n = n as usize;
+					let mut items = self.stack.split_off(self.stack.len() - n * 2).into_iter();
+					let mut map = HashMap::with_capacity(n);
+					while let (Some(key), Some(value)) = (items.next(), items.next()) {
+						map.insert(String::from(Value::from(key)), Value::from(value));
+					}
+					push!(Value::from(map));
+				},
+				Op::UnpackList(n, rest) => {
+					let n = n as usize;
+					let list = match Value::from(pop!()) {
+						Value::LIST(list) => list,
+						_ => runtime_error!("Can only destructure a list")
+					};
+					let items = list.borrow();
+					if items.len() < n || !rest && items.len() > n {
+						let at_least = if rest {"at least "} else {""};
+						runtime_error!(format!("Expected a list of {}{} items but got {}", at_least, n, items.len()));
+					}
+					self.stack.extend(items[..n].iter().cloned().map(Slot::from));
+					if rest {
+						push!(Value::from(items[n..].to_vec()));
+					}
+				},
+				Op::UnpackMap(n, start) => {
+					let map = match Value::from(pop!()) {
+						Value::MAP(map) => map,
+						_ => runtime_error!("Can only destructure a map")
+					};
+					for key in names!(start, n) {
+						let value = map.borrow().get(&key).cloned();
+						match value {
+							Some(value) => push!(value),
+							None => runtime_error!(format!("Map has no key '{}'", key))
+						}
+					}
+				},
+				Op::DefGlobal(index) => {
+					let index = index as usize;
+					// Globals aren't always defined in order
 					if self.globals.len() <= index {
-						self.globals.push(pop!());
-					} else {
-						self.globals[index] = pop!();
+						self.globals.resize(index + 1, None);
 					}
+					self.globals[index] = Some(pop!());
 				},
-				GETGLOBAL => {
-					let index = read_byte!() as usize;
-					push!(self.globals[index].clone());
+				Op::GetGlobal(index) => {
+					// A global can be read before it is defined inside a function
+					match self.globals.get(index as usize) {
+						Some(Some(value)) => push!(value.clone()),
+						_ => runtime_error!("Undefined variable"),
+					}
 				},
-				SETGLOBAL => {
-					let index = read_byte!() as usize;
+				Op::SetGlobal(index) => {
+					let index = index as usize;
 					let value = peek!(0).clone();
 					if self.globals.len() <= index {
-						self.globals.push(value);
-					} else {
-						self.globals[index] = value;
+						self.globals.resize(index + 1, None);
 					}
+					self.globals[index] = Some(value);
 				},
-				GETLOCAL => {
-					let index = read_byte!();
+				Op::GetLocal(index) => {
 					// I could handle errors here but the compiler should make them impossible
-					push!(self.stack[index as usize].clone());
+					push!(self.stack[self.slots + index as usize].clone());
 				},
-				SETLOCAL => {
-					let index = read_byte!();
+				Op::SetLocal(index) => {
 					// Don't pop, as an assignment is also an expression
-					self.stack[index as usize] = peek!(0).clone();
+					self.stack[self.slots + index as usize] = peek!(0).clone();
 				},
-				JUMPIFFALSE => {
-					let offset = read_word!() as i16;
+				Op::JumpIfFalse(target) => {
 					if !peek!(0).is_truthy() {
-						self.ip = (self.ip as i64 +  offset as i64) as usize;
+						ip = target as usize;
+					}
+				},
+				Op::JumpIfFalsePop(target) => {
+					if !pop!().is_truthy() {
+						ip = target as usize;
+					}
+				},
+				Op::JumpIfNil(target) => {
+					if peek!(0).is_nil() {
+						ip = target as usize;
+					}
+				},
+				Op::GetProperty(index) => {
+					let key = String::from(constant!(index).clone());
+					match Value::from(pop!()) {
+						Value::MAP(map) => {
+							let value = map.borrow().get(&key).cloned();
+							match value {
+								Some(value) => push!(value),
+								None => runtime_error!(format!("Map has no key '{}'", key))
+							}
+						},
+						Value::ENUM(enumeration) => {
+							match enumeration.variants.iter().position(|variant| variant.name == key) {
+								Some(variant) => push!(Value::VARIANT(Rc::new(EnumValue {
+									enumeration,
+									variant,
+									payload: vec![],
+								}))),
+								None => runtime_error!(format!("Enum {} has no variant '{}'", enumeration.name, key))
+							}
+						},
+						Value::VARIANT(value) if !value.is_constructor() => {
+							match value.definition().fields.iter().position(|field| *field == key) {
+								Some(field) => push!(value.payload[field].clone()),
+								None => runtime_error!(format!("{} has no field '{}'", value, key))
+							}
+						},
+						_ => runtime_error!("Only maps, enums and enum values have properties")
+					}
+				},
+				Op::Match => {
+					let pattern = Value::from(pop!());
+					let value = Value::from(pop!());
+					let matched = match (&value, &pattern) {
+						(Value::VARIANT(value), Value::VARIANT(constructor)) if constructor.is_constructor() => {
+							Rc::ptr_eq(&value.enumeration, &constructor.enumeration) && value.variant == constructor.variant
+						},
+						_ => value == pattern,
+					};
+					push!(Value::BOOL(matched));
+				},
+				Op::UnpackVariant(n) => {
+					let n = n as usize;
+					let value = match Value::from(pop!()) {
+						Value::VARIANT(value) if !value.is_constructor() => value,
+						_ => runtime_error!("Can only destructure an enum value")
+					};
+					if value.payload.len() != n {
+						runtime_error!(format!("Expected {} fields but {} has {}", n, value, value.payload.len()));
+					}
+					self.stack.extend(value.payload.iter().cloned().map(Slot::from));
+				},
+				Op::NoMatch => {
+					let value = pop!();
+					runtime_error!(format!("No match arm for {}", value));
+				},
+				Op::JumpIfNotNil(target) => {
+					if !peek!(0).is_nil() {
+						ip = target as usize;
+					}
+				},
+				Op::JumpIfPassed(target) => {
+					if !peek!(0).is_missing() {
+						ip = target as usize;
 					}
 				},
-				JUMP => {
-					let offset = read_word!() as i16;
-					self.ip = (self.ip as i64 +  offset as i64) as usize;
+				Op::Jump(target) => ip = target as usize,
+				Op::Leave(n) => {
+					let len = self.stack.len() - n as usize;
+					self.close_upvalues(len);
+					self.stack.truncate(len);
 				},
-				LEAVE => {
-					let n = read_byte!();
-					self.stack.truncate(self.stack.len() - n as usize);
+				Op::Call(argc) => call!(self.call(argc as usize, vec![])),
+				Op::TailCall(argc) => call!(self.tail_call(argc as usize)),
+				Op::CallNamed(argc, named, start) => {
+					let names = names!(start, named);
+					call!(self.call(argc as usize, names));
+				},
+				Op::Closure(index, start) => {
+					let function = match constant!(index) {
+						Value::FUNCTION(function) => function.clone(),
+						_ => unreachable!()
+					};
+					let mut upvalues = Vec::with_capacity(function.upvalue_count);
+					for i in 0..function.upvalue_count {
+						let capture = self.closure.function.code().captures[start as usize + i];
+						let index = capture.index as usize;
+						if capture.is_local {
+							upvalues.push(self.capture_upvalue(self.slots + index));
+						} else {
+							upvalues.push(self.closure.upvalues[index].clone());
+						}
+					}
+					push!(Value::CLOSURE(Rc::new(Closure {function, upvalues})));
+				},
+				Op::GetUpvalue(index) => {
+					let value = match &*self.closure.upvalues[index as usize].borrow() {
+						Upvalue::Open(slot) => self.stack[*slot].clone(),
+						Upvalue::Closed(value) => value.clone(),
+					};
+					push!(value);
+				},
+				Op::SetUpvalue(index) => {
+					// Don't pop, as an assignment is also an expression
+					let value = peek!(0).clone();
+					match &mut *self.closure.upvalues[index as usize].borrow_mut() {
+						Upvalue::Open(slot) => self.stack[*slot] = value,
+						Upvalue::Closed(closed) => *closed = value,
+					}
+				},
+				Op::Binary(operator, register, a, b) => {
+					let value = match (number!(a), number!(b)) {
+						(Some(a), Some(b)) => numbers(operator, a, b),
+						_ => match binary(operator, &source!(a), &source!(b)) {
+							Ok(value) => value,
+							Err(msg) => runtime_error!(msg)
+						}
+					};
+					store_register!(register, value);
+				},
+				Op::Unary(operator, register, value) => {
+					let value = match (operator, number!(value)) {
+						(NOT, _) => Value::from(!source!(value).is_truthy()),
+						(_, Some(number)) => Value::from(-number),
+						(_, None) => runtime_error!("Operand must be a number")
+					};
+					store_register!(register, value);
+				},
+				Op::JumpIfFalseReg(register, target) => {
+					if !self.stack[self.slots + register as usize].is_truthy() {
+						ip = target as usize;
+					}
+				},
+				Op::JumpUnlessReg(operator, a, b, target) | Op::JumpUnlessRegConst(operator, a, b, target) => {
+					let (a, b) = match instruction {
+						Op::JumpUnlessReg(..) => (Source::Register(a), Source::Register(b)),
+						_ => (Source::Register(a), Source::Constant(b)),
+					};
+					let result = match (number!(a), number!(b)) {
+						(Some(a), Some(b)) => Ok(compare_numbers(operator, a, b)),
+						_ => compare(operator, &source!(a), &source!(b)),
+					};
+					match result {
+						Ok(true) => {},
+						Ok(false) => ip = target as usize,
+						Err(msg) => runtime_error!(msg)
+					}
 				},
-				_ => return self.runtime_error(format!("Unknown opcode: {instruction:#x}"))
 			}
 		}
 	}
 
+	/// Make a closure the one being executed, returning the previous one
+	fn enter(&mut self, closure: Rc<Closure>) -> Rc<Closure> {
+		self.code = closure.function.code().ops.as_ptr();
+		std::mem::replace(&mut self.closure, closure)
+	}
+
 	fn runtime_error(&self, msg: impl AsRef<str>) -> Result {
 		let msg = msg.as_ref();
-		let stack_length = self.chunk.code.len();
-		if stack_length < 1 {
-			eprintln!("Error: {}",msg);
-		} else {
-			let  mut i = 0;
-			let offset = stack_length-1;
-			let line = loop {
-				let line = &self.chunk.lines[i];
-				if line.length >= offset {
-					break line.number;
-				}
-				i += 1;
-			};
-			eprintln!("Error at line {}: {}",line,msg);
+		match self.ip.checked_sub(1).and_then(|ip| self.closure.function.code().lines.get(ip)) {
+			Some(line) => eprintln!("Error at line {}: {}",line,msg),
+			None => eprintln!("Error: {}",msg),
+		}
+
+		// Show where the functions we are in were called from
+		let mut elided = self.elided;
+		for frame in self.frames.iter().rev() {
+			if elided > 0 {
+				eprintln!("  ({} calls elided by tail calls)", elided);
+			}
+			let function = &frame.closure.function;
+			if let Some(line) = frame.ip.checked_sub(1).and_then(|ip| function.code().lines.get(ip)) {
+				eprintln!("  called at line {} in {}", line, function.name.as_deref().unwrap_or("script"));
+			}
+			elided = frame.elided;
 		}
 		return Result::RUNTIME_ERROR(msg.to_owned());
 	}
 
+	/// Create a value of an enum variant with the arguments as payload
+	fn construct(&mut self, constructor: Rc<EnumValue>, callee: usize, argc: usize) -> std::result::Result<(), String> {
+		let fields = constructor.definition().fields.len();
+		if argc != fields {
+			return Err(format!("Expected {} arguments but got {}", fields, argc));
+		}
+		let payload = self.stack.split_off(callee + 1).into_iter().map(Value::from).collect();
+		self.stack.pop();
+		self.stack.push(Slot::from(Value::VARIANT(Rc::new(EnumValue {
+			enumeration: constructor.enumeration.clone(),
+			variant: constructor.variant,
+			payload,
+		}))));
+		Ok(())
+	}
+
+	/// Call a function built into the VM, it replaces the callee and arguments with its result
+	fn call_native(&mut self, native: &Native, callee: usize, argc: usize) -> std::result::Result<(), String> {
+		if argc != native.arity as usize {
+			return Err(format!("Expected {} arguments but got {}", native.arity, argc));
+		}
+		let args: Vec<Value> = self.stack.split_off(callee + 1).into_iter().map(Value::from).collect();
+		self.stack.pop();
+		self.stack.push(Slot::from((native.function)(&args)));
+		Ok(())
+	}
+
+	/// Call the value below the arguments,
+	/// the named arguments are above the positional ones
+	fn call(&mut self, argc: usize, names: Vec<String>) -> std::result::Result<(), String> {
+		let callee = self.stack.len() - argc - names.len() - 1;
+		let closure = match self.stack[callee].to_value() {
+			Value::CLOSURE(closure) => closure,
+			Value::GENERATOR(generator) if names.is_empty() => return self.resume(generator, callee, argc),
+			Value::VARIANT(constructor) if names.is_empty() && constructor.is_constructor() => {
+				return self.construct(constructor, callee, argc)
+			},
+			Value::NATIVE(native) if names.is_empty() => return self.call_native(native, callee, argc),
+			_ => return Err("Can only call functions".to_owned()),
+		};
+
+		let function = &closure.function;
+		if !names.is_empty() || argc != function.arity as usize || function.rest.is_some() {
+			self.bind_arguments(function, callee, argc, names)?;
+		}
+
+		// The arguments are kept until the generator is first resumed
+		if function.generator {
+			let stack = self.stack.split_off(callee + 1);
+			self.stack.pop();
+			self.stack.push(Slot::from(Value::GENERATOR(Rc::new(RefCell::new(Generator {
+				closure,
+				ip: 0,
+				state: GeneratorState::Created,
+				stack,
+				upvalues: vec![],
+			})))));
+			return Ok(());
+		}
+
+		if self.frames.len() + 1 >= FRAMES_MAX {
+			return Err("Stack overflow".to_owned());
+		}
+		let caller = self.enter(closure);
+		self.frames.push(CallFrame {
+			closure: caller,
+			ip: self.ip,
+			slots: self.slots,
+			elided: self.elided,
+		});
+		self.ip = 0;
+		self.slots = callee + 1;
+		self.elided = 0;
+		Ok(())
+	}
+
+	/// Call a function in place of the current one.
+	/// Anything that isn't a plain function is called normally,
+	/// the compiler puts a return after the tail call for this.
+	fn tail_call(&mut self, argc: usize) -> std::result::Result<(), String> {
+		let callee = self.stack.len() - argc - 1;
+		let closure = match self.stack[callee].to_value() {
+			Value::CLOSURE(closure) if !closure.function.generator => closure,
+			_ => return self.call(argc, vec![]),
+		};
+
+		let function = &closure.function;
+		if argc != function.arity as usize || function.rest.is_some() {
+			self.bind_arguments(function, callee, argc, vec![])?;
+		}
+
+		// Replace the locals of the current call with the callee and arguments
+		self.close_upvalues(self.slots);
+		self.stack.drain(self.slots - 1..callee);
+		self.enter(closure);
+		self.ip = 0;
+		self.elided += 1;
+		Ok(())
+	}
+
+	/// Continue a generator where it last yielded.
+	/// The argument becomes the value of the yield expression.
+	fn resume(&mut self, generator: Rc<RefCell<Generator>>, callee: usize, argc: usize) -> std::result::Result<(), String> {
+		if argc > 1 {
+			return Err(format!("Expected 0 to 1 arguments but got {}", argc));
+		}
+		let sent = if argc == 1 {self.stack.pop().unwrap()} else {Slot::from(Value::NIL)};
+
+		let mut state = generator.borrow_mut();
+		match state.state {
+			// A finished generator keeps returning nil
+			GeneratorState::Done => {
+				self.stack.truncate(callee);
+				self.stack.push(Slot::from(Value::NIL));
+				return Ok(());
+			},
+			GeneratorState::Running => return Err("Generator is already running".to_owned()),
+			GeneratorState::Created | GeneratorState::Suspended => {}
+		}
+		if self.frames.len() + 1 >= FRAMES_MAX {
+			return Err("Stack overflow".to_owned());
+		}
+
+		// The generator stays in the place of the callee
+		let slots = callee + 1;
+		self.stack.append(&mut state.stack);
+		for (upvalue, offset) in state.upvalues.drain(..) {
+			let slot = slots + offset;
+			let value = std::mem::replace(&mut *upvalue.borrow_mut(), Upvalue::Open(slot));
+			if let Upvalue::Closed(value) = value {
+				self.stack[slot] = value;
+			}
+			self.open_upvalues.push(upvalue);
+		}
+		if state.state == GeneratorState::Suspended {
+			self.stack.push(sent);
+		}
+		state.state = GeneratorState::Running;
+
+		let caller = self.enter(state.closure.clone());
+		self.frames.push(CallFrame {
+			closure: caller,
+			ip: self.ip,
+			slots: self.slots,
+			elided: self.elided,
+		});
+		self.ip = state.ip;
+		self.slots = slots;
+		self.elided = 0;
+		Ok(())
+	}
+
+	/// Put the arguments in the order of the parameters.
+	/// Missing arguments are marked so their default is used, even when nil is passed,
+	/// and extra arguments are collected for the rest parameter.
+	fn bind_arguments(&mut self, function: &Function, callee: usize, argc: usize, names: Vec<String>) -> std::result::Result<(), String> {
+		let arity = function.arity as usize;
+		let mut args = self.stack.split_off(callee + 1);
+		let named = args.split_off(argc);
+
+		if argc > arity && function.rest.is_none() {
+			return Err(format!("Expected {} arguments but got {}", function.expected_arguments(), argc));
+		}
+		let rest = if argc > arity {args.split_off(arity)} else {vec![]};
+
+		let mut params: Vec<Option<Slot>> = args.into_iter().map(Some).collect();
+		params.resize(arity, None);
+		for (name, value) in names.iter().zip(named) {
+			let Some(index) = function.params.iter().position(|param| &param.name == name) else {
+				return Err(format!("Unknown parameter '{}'", name));
+			};
+			if params[index].is_some() {
+				return Err(format!("Got multiple values for parameter '{}'", name));
+			}
+			params[index] = Some(value);
+		}
+
+		if let Some(index) = params[..function.required as usize].iter().position(Option::is_none) {
+			return Err(if names.is_empty() {
+				format!("Expected {} arguments but got {}", function.expected_arguments(), argc)
+			} else {
+				format!("Missing argument for parameter '{}'", function.params[index].name)
+			});
+		}
+
+		self.stack.extend(params.into_iter().map(|param| param.unwrap_or_else(|| Slot::from(Value::MISSING))));
+		if function.rest.is_some() {
+			let rest: Vec<Value> = rest.into_iter().map(Value::from).collect();
+			self.stack.push(Slot::from(Value::from(rest)));
+		}
+		Ok(())
+	}
+
+	/// Get the upvalue pointing to a stack slot, or create one
+	fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+		for upvalue in &self.open_upvalues {
+			if let Upvalue::Open(open) = *upvalue.borrow() {
+				if open == slot {
+					return upvalue.clone();
+				}
+			}
+		}
+		let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+		self.open_upvalues.push(upvalue.clone());
+		upvalue
+	}
+
+	/// Close the upvalues of a generator that is being suspended,
+	/// returning them with their offset so they can be opened again
+	fn suspend_upvalues(&mut self, from: usize) -> Vec<(Rc<RefCell<Upvalue>>, usize)> {
+		let mut suspended = vec![];
+		let stack = &self.stack;
+		self.open_upvalues.retain(|upvalue| {
+			let mut value = upvalue.borrow_mut();
+			match *value {
+				Upvalue::Open(slot) if slot >= from => {
+					*value = Upvalue::Closed(stack[slot].clone());
+					suspended.push((upvalue.clone(), slot - from));
+					false
+				},
+				_ => true
+			}
+		});
+		suspended
+	}
+
+	/// Move all values at or above a stack slot into their upvalues
+	fn close_upvalues(&mut self, from: usize) {
+		let stack = &self.stack;
+		self.open_upvalues.retain(|upvalue| {
+			let mut upvalue = upvalue.borrow_mut();
+			match *upvalue {
+				Upvalue::Open(slot) if slot >= from => {
+					*upvalue = Upvalue::Closed(stack[slot].clone());
+					false
+				},
+				_ => true
+			}
+		});
+	}
+
+	#[cfg(feature = "trace")]
 	pub fn print_stack(&self) {
 		let mut str = String::with_capacity(self.stack.len() * 4);
 		for value in &self.stack {
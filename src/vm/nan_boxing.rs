@@ -0,0 +1,246 @@
+/*
+A Value is 32 bytes, mostly because a String is kept inline.
+A Slot packs a value into the 8 bytes of a double instead.
+Numbers are stored as they are,
+everything else is hidden in the bits of a quiet NaN that no calculation produces.
+Objects are kept as a raw Rc pointer with the type in the low bits,
+which are always zero as the pointers are aligned to 8 bytes.
+Pointers fit in 48 bits on the platforms we run on.
+*/
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::vm::value::{Value, number};
+use crate::vm::function::{Function, Closure, Generator};
+use crate::vm::enums::{Enum, EnumValue};
+use crate::vm::native::Native;
+
+/// Set in every packed value that isn't a number
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+/// Set together with QNAN for objects
+const SIGN: u64 = 0x8000_0000_0000_0000;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+const MISSING: u64 = QNAN | 4;
+/// Chars have the code point in the low bits
+const CHAR: u64 = QNAN | 1 << 48;
+/// Natives are static, so their pointer is kept without reference counting
+const NATIVE: u64 = QNAN | 2 << 48;
+const TAG: u64 = QNAN | 3 << 48;
+const PAYLOAD: u64 = (1 << 48) - 1;
+
+/// The type of an object, in the low 3 bits of the pointer
+const KIND: u64 = 0b111;
+const STRING: u64 = 0;
+const FUNCTION: u64 = 1;
+const CLOSURE: u64 = 2;
+const GENERATOR: u64 = 3;
+const LIST: u64 = 4;
+const MAP: u64 = 5;
+const ENUM: u64 = 6;
+const VARIANT: u64 = 7;
+
+/// A value packed in 8 bytes, this owns a reference if it is an object
+pub struct Slot(u64);
+
+/// Run a function on the pointer of an object, with the type it points to
+macro_rules! with_object {
+	($bits:expr, $function:ident) => {{
+		let pointer = ($bits & PAYLOAD & !KIND) as usize;
+		match $bits & KIND {
+			STRING => $function(pointer as *const String),
+			FUNCTION => $function(pointer as *const Function),
+			CLOSURE => $function(pointer as *const Closure),
+			GENERATOR => $function(pointer as *const RefCell<Generator>),
+			LIST => $function(pointer as *const RefCell<Vec<Value>>),
+			MAP => $function(pointer as *const RefCell<HashMap<String, Value>>),
+			ENUM => $function(pointer as *const Enum),
+			_ => $function(pointer as *const EnumValue),
+		}
+	}};
+}
+
+/// Get another reference to an object without taking the one of the slot
+unsafe fn borrow_rc<T>(pointer: *const T) -> Rc<T> {
+	Rc::increment_strong_count(pointer);
+	Rc::from_raw(pointer)
+}
+
+unsafe fn increment<T>(pointer: *const T) {
+	Rc::increment_strong_count(pointer);
+}
+
+unsafe fn decrement<T>(pointer: *const T) {
+	Rc::decrement_strong_count(pointer);
+}
+
+impl Slot {
+	fn object<T>(object: Rc<T>, kind: u64) -> Slot {
+		let pointer = Rc::into_raw(object) as u64;
+		debug_assert!(pointer & !PAYLOAD == 0 && pointer & KIND == 0, "pointer can't be packed");
+		Slot(SIGN | QNAN | pointer | kind)
+	}
+
+	fn is_object(&self) -> bool {
+		self.0 & (SIGN | QNAN) == SIGN | QNAN
+	}
+
+	fn kind(&self) -> Option<u64> {
+		if self.is_object() {Some(self.0 & KIND)} else {None}
+	}
+
+	fn string(&self) -> Option<&String> {
+		match self.kind() {
+			// The slot holds a reference, so the string lives as long as the slot
+			Some(STRING) => Some(unsafe {&*((self.0 & PAYLOAD) as *const String)}),
+			_ => None
+		}
+	}
+
+	pub fn is_number(&self) -> bool {
+		self.0 & QNAN != QNAN
+	}
+
+	pub fn as_number(&self) -> Option<number> {
+		if self.is_number() {Some(f64::from_bits(self.0))} else {None}
+	}
+
+	pub fn is_nil(&self) -> bool {
+		self.0 == NIL
+	}
+
+	pub fn is_missing(&self) -> bool {
+		self.0 == MISSING
+	}
+
+	pub fn is_string(&self) -> bool {
+		self.kind() == Some(STRING)
+	}
+
+	pub fn is_truthy(&self) -> bool {
+		self.0 != NIL && self.0 != FALSE
+	}
+
+	pub fn equal(&self, second: Slot) -> bool {
+		if let (Some(a), Some(b)) = (self.as_number(), second.as_number()) {
+			return a == b;
+		}
+		if self.0 == second.0 {
+			return true;
+		}
+		if let (Some(a), Some(b)) = (self.string(), second.string()) {
+			return a == b;
+		}
+		// Other objects are compared by their contents, like Value does
+		self.is_object() && second.is_object() && self.to_value() == second.to_value()
+	}
+
+	/// Unpack a copy of the value
+	pub fn to_value(&self) -> Value {
+		if let Some(number) = self.as_number() {
+			return Value::NUMBER(number);
+		}
+		let pointer = self.0 & PAYLOAD;
+		unsafe {
+			match self.kind() {
+				Some(STRING) => Value::STRING((*(pointer as *const String)).clone()),
+				Some(FUNCTION) => Value::FUNCTION(borrow_rc((pointer & !KIND) as *const Function)),
+				Some(CLOSURE) => Value::CLOSURE(borrow_rc((pointer & !KIND) as *const Closure)),
+				Some(GENERATOR) => Value::GENERATOR(borrow_rc((pointer & !KIND) as *const RefCell<Generator>)),
+				Some(LIST) => Value::LIST(borrow_rc((pointer & !KIND) as *const RefCell<Vec<Value>>)),
+				Some(MAP) => Value::MAP(borrow_rc((pointer & !KIND) as *const RefCell<HashMap<String, Value>>)),
+				Some(ENUM) => Value::ENUM(borrow_rc((pointer & !KIND) as *const Enum)),
+				Some(_) => Value::VARIANT(borrow_rc((pointer & !KIND) as *const EnumValue)),
+				None => match self.0 & TAG {
+					CHAR => Value::CHAR(char::from_u32(pointer as u32).unwrap()),
+					NATIVE => Value::NATIVE(&*(pointer as *const Native)),
+					_ => match self.0 {
+						TRUE => Value::BOOL(true),
+						FALSE => Value::BOOL(false),
+						MISSING => Value::MISSING,
+						_ => Value::NIL,
+					}
+				}
+			}
+		}
+	}
+}
+
+impl From<number> for Slot {
+	fn from(value: number) -> Slot {
+		// Any NaN calculated is stored as the one NaN that isn't used for packing
+		if value.is_nan() {
+			Slot(number::NAN.to_bits())
+		} else {
+			Slot(value.to_bits())
+		}
+	}
+}
+
+impl From<bool> for Slot {
+	fn from(value: bool) -> Slot {
+		Slot(if value {TRUE} else {FALSE})
+	}
+}
+
+impl From<Value> for Slot {
+	fn from(value: Value) -> Slot {
+		match value {
+			Value::NUMBER(number) => Slot::from(number),
+			Value::BOOL(bool) => Slot::from(bool),
+			Value::NIL => Slot(NIL),
+			Value::MISSING => Slot(MISSING),
+			Value::CHAR(char) => Slot(CHAR | char as u64),
+			Value::NATIVE(native) => Slot(NATIVE | native as *const Native as u64),
+			Value::STRING(string) => Slot::object(Rc::new(string), STRING),
+			Value::FUNCTION(function) => Slot::object(function, FUNCTION),
+			Value::CLOSURE(closure) => Slot::object(closure, CLOSURE),
+			Value::GENERATOR(generator) => Slot::object(generator, GENERATOR),
+			Value::LIST(list) => Slot::object(list, LIST),
+			Value::MAP(map) => Slot::object(map, MAP),
+			Value::ENUM(enumeration) => Slot::object(enumeration, ENUM),
+			Value::VARIANT(variant) => Slot::object(variant, VARIANT),
+		}
+	}
+}
+
+impl From<Slot> for Value {
+	fn from(slot: Slot) -> Value {
+		slot.to_value()
+	}
+}
+
+impl Clone for Slot {
+	fn clone(&self) -> Slot {
+		if self.is_object() {
+			unsafe {with_object!(self.0, increment)}
+		}
+		Slot(self.0)
+	}
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		if self.is_object() {
+			unsafe {with_object!(self.0, decrement)}
+		}
+	}
+}
+
+impl std::fmt::Display for Slot {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.string() {
+			Some(string) => write!(f, "{}", string),
+			None => write!(f, "{}", self.to_value()),
+		}
+	}
+}
+
+impl std::fmt::Debug for Slot {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{:?}", self.to_value())
+	}
+}
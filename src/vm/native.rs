@@ -0,0 +1,32 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::vm::value::{Value, number};
+
+/// A function that is built into the VM.
+/// These are found by name when no variable has that name.
+#[derive(Debug)]
+pub struct Native {
+	pub name: &'static str,
+	pub arity: u8,
+	pub function: fn(&[Value]) -> Value,
+}
+
+// Natives are only equal to themselves
+impl PartialEq for Native {
+	fn eq(&self, other: &Native) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+pub static NATIVES: [Native; 1] = [
+	Native {name: "clock", arity: 0, function: clock},
+];
+
+pub fn find(name: &str) -> Option<&'static Native> {
+	NATIVES.iter().find(|native| native.name == name)
+}
+
+/// Seconds since the epoch, to time things with
+fn clock(_args: &[Value]) -> Value {
+	let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	Value::from(time.as_secs_f64() as number)
+}
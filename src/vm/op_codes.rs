@@ -1,6 +1,7 @@
 //https://doc.rust-lang.org/reference/items/enumerations.html#custom-discriminant-values-for-fieldless-enumerations
 
 use crate::vm::chunk::Chunk;
+use crate::vm::value::Value;
 
 ///Code for an operator or the value of an operand
 pub type OpCode = u8;
@@ -10,7 +11,8 @@ pub type OpCode = u8;
 //but it was hard to convert the type to a u8.
 //#region
 
-/// Currently stops the process
+/// Return from the current function with the value on the stack,
+/// at top level this stops the process
 pub const RETURN: OpCode = 0x1;
 
 /// Push a constant onto the stack, takes index of constant
@@ -81,8 +83,485 @@ pub const JUMP: OpCode = 0x18;
 
 /// Leave, takes a number of locals to pop
 pub const LEAVE: OpCode = 0x19;
+
+/// Call the value below the arguments, takes the argument count
+pub const CALL: OpCode = 0x1a;
+
+/// Wrap a function constant in a closure, takes index of constant
+/// followed by an is_local byte and an index (in two bytes) for every upvalue
+pub const CLOSURE: OpCode = 0x1b;
+
+/// Push a captured variable onto the stack, takes upvalue index
+pub const GETUPVALUE: OpCode = 0x1c;
+
+/// Update a captured variable, takes upvalue index
+pub const SETUPVALUE: OpCode = 0x1d;
+
+/// Jump if not nil, takes offset (in two bytes)
+pub const JUMPIFNOTNIL: OpCode = 0x1e;
+
+/// Call with named arguments above the positional ones,
+/// takes the positional argument count, the named argument count
+/// and the constant index of each name
+pub const CALLNAMED: OpCode = 0x1f;
+
+/// Push a copy of the value on top of the stack
+pub const DUP: OpCode = 0x20;
+
+/// Create a list from values on the stack, takes the amount of items
+pub const BUILDLIST: OpCode = 0x21;
+
+/// Create a map from keys and values on the stack, takes the amount of pairs
+pub const BUILDMAP: OpCode = 0x22;
+
+/// Push the items of a list, takes the amount of items
+/// and if the remaining items should be pushed as a list
+pub const UNPACKLIST: OpCode = 0x23;
+
+/// Push values of a map, takes the amount of keys
+/// followed by the constant index of each key
+pub const UNPACKMAP: OpCode = 0x24;
+
+/// Jump if nil, takes offset (in two bytes)
+pub const JUMPIFNIL: OpCode = 0x25;
+
+/// Replace a map with the value of one of its keys, takes index of the key constant
+pub const GETPROPERTY: OpCode = 0x26;
+
+/// Suspend the current generator, giving the value on the stack to the caller
+pub const YIELD: OpCode = 0x27;
+
+/// Call that replaces the current call, takes the argument count
+pub const TAILCALL: OpCode = 0x28;
+
+/// Compare a value with a match pattern, pushes if they match.
+/// A constructor pattern matches every value of its variant
+pub const MATCH: OpCode = 0x29;
+
+/// Push the payload of an enum variant, takes the amount of fields
+pub const UNPACKVARIANT: OpCode = 0x2a;
+
+/// Error that no arm of a match matched the value on the stack
+pub const NOMATCH: OpCode = 0x2b;
+
+/// CONSTANT with the index in two bytes
+pub const CONSTANTLONG: OpCode = 0x2c;
+
+/// DEFGLOBAL with the index in two bytes
+pub const DEFGLOBALLONG: OpCode = 0x2d;
+
+/// GETGLOBAL with the index in two bytes
+pub const GETGLOBALLONG: OpCode = 0x2e;
+
+/// SETGLOBAL with the index in two bytes
+pub const SETGLOBALLONG: OpCode = 0x2f;
+
+/// GETLOCAL with the index in two bytes
+pub const GETLOCALLONG: OpCode = 0x30;
+
+/// SETLOCAL with the index in two bytes
+pub const SETLOCALLONG: OpCode = 0x31;
+
+/// CLOSURE with the index of the constant in two bytes
+pub const CLOSURELONG: OpCode = 0x32;
+
+/// JUMP with the offset in four bytes
+pub const JUMPLONG: OpCode = 0x33;
+
+/// JUMPIFFALSE with the offset in four bytes
+pub const JUMPIFFALSELONG: OpCode = 0x34;
+
+/// JUMPIFNIL with the offset in four bytes
+pub const JUMPIFNILLONG: OpCode = 0x35;
+
+/// JUMPIFNOTNIL with the offset in four bytes
+pub const JUMPIFNOTNILLONG: OpCode = 0x36;
+
+/// != operation, made from EQUAL NOT by the peephole pass
+pub const NOTEQUAL: OpCode = 0x37;
+
+/// \>= operation, made from LESS NOT by the peephole pass
+pub const GREATEREQUAL: OpCode = 0x38;
+
+/// \<= operation, made from GREATER NOT by the peephole pass
+pub const LESSEQUAL: OpCode = 0x39;
+
+/// Pop the value on the stack and jump if it is false, takes offset (in two bytes)
+pub const JUMPIFFALSEPOP: OpCode = 0x3a;
+
+/// JUMPIFFALSEPOP with the offset in four bytes
+pub const JUMPIFFALSEPOPLONG: OpCode = 0x3b;
+
+// These superinstructions replace common sequences of ops, they are made by the peephole pass
+
+/// Add a constant to a local without pushing anything, takes local index and constant index.
+/// This is GETLOCAL CONSTANT ADD SETLOCAL POP.
+pub const INCRLOCAL: OpCode = 0x3c;
+
+/// Push a local plus a constant, takes local index and constant index.
+/// This is GETLOCAL CONSTANT ADD.
+pub const ADDLOCALCONST: OpCode = 0x3d;
+
+/// Push a local minus a constant, takes local index and constant index.
+/// This is GETLOCAL CONSTANT SUBTRACT.
+pub const SUBTRACTLOCALCONST: OpCode = 0x3e;
+
+/// Jump unless a local is less than another local, takes two local indexes and offset (in two bytes).
+/// This is GETLOCAL GETLOCAL LESS JUMPIFFALSEPOP.
+pub const LESSLOCALSJUMP: OpCode = 0x3f;
+
+/// LESSLOCALSJUMP with the offset in four bytes
+pub const LESSLOCALSJUMPLONG: OpCode = 0x40;
+
+/// Jump unless a local is less than a constant, takes local index, constant index and offset (in two bytes).
+/// This is GETLOCAL CONSTANT LESS JUMPIFFALSEPOP.
+pub const LESSLOCALCONSTJUMP: OpCode = 0x41;
+
+/// LESSLOCALCONSTJUMP with the offset in four bytes
+pub const LESSLOCALCONSTJUMPLONG: OpCode = 0x42;
+
+// Register ops work on locals without going through the stack.
+// These are made with the registers option, the first operand is the local to store in.
+
+/// Copy a local into another local, takes two local indexes
+pub const MOVEREG: OpCode = 0x43;
+
+/// Store a constant in a local, takes local index and constant index
+pub const LOADREG: OpCode = 0x44;
+
+/// Store the sum of two locals in a local, takes three local indexes
+pub const ADDREG: OpCode = 0x45;
+
+/// Store a local plus a constant in a local, takes two local indexes and constant index
+pub const ADDREGCONST: OpCode = 0x46;
+
+/// SUBTRACT with ADDREG operands
+pub const SUBTRACTREG: OpCode = 0x47;
+
+/// SUBTRACT with ADDREGCONST operands
+pub const SUBTRACTREGCONST: OpCode = 0x48;
+
+/// MULTIPLY with ADDREG operands
+pub const MULTIPLYREG: OpCode = 0x49;
+
+/// MULTIPLY with ADDREGCONST operands
+pub const MULTIPLYREGCONST: OpCode = 0x4a;
+
+/// DIVIDE with ADDREG operands
+pub const DIVIDEREG: OpCode = 0x4b;
+
+/// DIVIDE with ADDREGCONST operands
+pub const DIVIDEREGCONST: OpCode = 0x4c;
+
+/// Jump if the value on the stack is an argument that was passed,
+/// the default value of a parameter is only used otherwise. Takes offset (in two bytes)
+pub const JUMPIFPASSED: OpCode = 0x4d;
+
+/// JUMPIFPASSED with the offset in four bytes
+pub const JUMPIFPASSEDLONG: OpCode = 0x4e;
+
+/// GETPROPERTY with the index of the name in two bytes
+pub const GETPROPERTYLONG: OpCode = 0x4f;
+
+/// UNPACKMAP with the index of every key in two bytes
+pub const UNPACKMAPLONG: OpCode = 0x50;
+
+/// CALLNAMED with the index of every name in two bytes
+pub const CALLNAMEDLONG: OpCode = 0x51;
+
+// Register ops for expressions, also made with the registers option.
+// Temporaries get the registers above the locals, in the order the stack ops would push them.
+// A source is a register or a constant, the kinds operand tells which:
+// bit 0 is set when the first source is a constant and bit 1 when the second one is.
+
+/// Store the result of an operator in a register, takes the op of the operator,
+/// the register, the kinds and two sources.
+/// The registers above the one stored in are freed, these are the temporaries the operator used
+pub const BINARYREG: OpCode = 0x52;
+
+/// BINARYREG for NEGATE or NOT, with one source
+pub const UNARYREG: OpCode = 0x53;
+
+/// Return a source from the current function, takes the kinds and the source
+pub const RETURNREG: OpCode = 0x54;
+
+/// Jump if a register is false, takes the register and offset (in two bytes)
+pub const JUMPIFFALSEREG: OpCode = 0x55;
+
+/// JUMPIFFALSEREG with the offset in four bytes
+pub const JUMPIFFALSEREGLONG: OpCode = 0x56;
+
+/// Jump unless a comparison of two registers is true,
+/// takes the op of the comparison, two registers and offset (in two bytes)
+pub const JUMPUNLESSREG: OpCode = 0x57;
+
+/// JUMPUNLESSREG with the offset in four bytes
+pub const JUMPUNLESSREGLONG: OpCode = 0x58;
+
+/// JUMPUNLESSREG with a constant index as the second operand
+pub const JUMPUNLESSREGCONST: OpCode = 0x59;
+
+/// JUMPUNLESSREGCONST with the offset in four bytes
+pub const JUMPUNLESSREGCONSTLONG: OpCode = 0x5a;
 //#endregion
 
+/// The long form of a jump
+pub fn long_jump(op: OpCode) -> OpCode {
+	match op {
+		JUMP => JUMPLONG,
+		JUMPIFFALSE => JUMPIFFALSELONG,
+		JUMPIFNIL => JUMPIFNILLONG,
+		JUMPIFNOTNIL => JUMPIFNOTNILLONG,
+		JUMPIFPASSED => JUMPIFPASSEDLONG,
+		JUMPIFFALSEPOP => JUMPIFFALSEPOPLONG,
+		LESSLOCALSJUMP => LESSLOCALSJUMPLONG,
+		LESSLOCALCONSTJUMP => LESSLOCALCONSTJUMPLONG,
+		JUMPIFFALSEREG => JUMPIFFALSEREGLONG,
+		JUMPUNLESSREG => JUMPUNLESSREGLONG,
+		JUMPUNLESSREGCONST => JUMPUNLESSREGCONSTLONG,
+		_ => op,
+	}
+}
+
+/// The short form of a jump
+pub fn short_jump(op: OpCode) -> OpCode {
+	match op {
+		JUMPLONG => JUMP,
+		JUMPIFFALSELONG => JUMPIFFALSE,
+		JUMPIFNILLONG => JUMPIFNIL,
+		JUMPIFNOTNILLONG => JUMPIFNOTNIL,
+		JUMPIFPASSEDLONG => JUMPIFPASSED,
+		JUMPIFFALSEPOPLONG => JUMPIFFALSEPOP,
+		LESSLOCALSJUMPLONG => LESSLOCALSJUMP,
+		LESSLOCALCONSTJUMPLONG => LESSLOCALCONSTJUMP,
+		JUMPIFFALSEREGLONG => JUMPIFFALSEREG,
+		JUMPUNLESSREGLONG => JUMPUNLESSREG,
+		JUMPUNLESSREGCONSTLONG => JUMPUNLESSREGCONST,
+		_ => op,
+	}
+}
+
+/// The name of an op, as it is shown in the disassembly
+pub fn name(op: OpCode) -> Option<&'static str> {
+	Some(match op {
+		RETURN => "RETURN",
+		CONSTANT => "CONSTANT",
+		NEGATE => "NEGATE",
+		ADD => "ADD",
+		SUBTRACT => "SUBTRACT",
+		MULTIPLY => "MULTIPLY",
+		DIVIDE => "DIVIDE",
+		NIL => "NIL",
+		TRUE => "TRUE",
+		FALSE => "FALSE",
+		NOT => "NOT",
+		EQUAL => "EQUAL",
+		GREATER => "GREATER",
+		LESS => "LESS",
+		PRINT => "PRINT",
+		POP => "POP",
+		DEFGLOBAL => "DEFGLOBAL",
+		GETGLOBAL => "GETGLOBAL",
+		SETGLOBAL => "SETGLOBAL",
+		GETLOCAL => "GETLOCAL",
+		SETLOCAL => "SETLOCAL",
+		JUMPIFFALSE => "JUMPIFFALSE",
+		JUMP => "JUMP",
+		LEAVE => "LEAVE",
+		CALL => "CALL",
+		CLOSURE => "CLOSURE",
+		GETUPVALUE => "GETUPVALUE",
+		SETUPVALUE => "SETUPVALUE",
+		JUMPIFNOTNIL => "JUMPIFNOTNIL",
+		CALLNAMED => "CALLNAMED",
+		DUP => "DUP",
+		BUILDLIST => "BUILDLIST",
+		BUILDMAP => "BUILDMAP",
+		UNPACKLIST => "UNPACKLIST",
+		UNPACKMAP => "UNPACKMAP",
+		JUMPIFNIL => "JUMPIFNIL",
+		GETPROPERTY => "GETPROPERTY",
+		YIELD => "YIELD",
+		TAILCALL => "TAILCALL",
+		MATCH => "MATCH",
+		UNPACKVARIANT => "UNPACKVARIANT",
+		NOMATCH => "NOMATCH",
+		CONSTANTLONG => "CONSTANTLONG",
+		DEFGLOBALLONG => "DEFGLOBALLONG",
+		GETGLOBALLONG => "GETGLOBALLONG",
+		SETGLOBALLONG => "SETGLOBALLONG",
+		GETLOCALLONG => "GETLOCALLONG",
+		SETLOCALLONG => "SETLOCALLONG",
+		CLOSURELONG => "CLOSURELONG",
+		JUMPLONG => "JUMPLONG",
+		JUMPIFFALSELONG => "JUMPIFFALSELONG",
+		JUMPIFNILLONG => "JUMPIFNILLONG",
+		JUMPIFNOTNILLONG => "JUMPIFNOTNILLONG",
+		NOTEQUAL => "NOTEQUAL",
+		GREATEREQUAL => "GREATEREQUAL",
+		LESSEQUAL => "LESSEQUAL",
+		JUMPIFFALSEPOP => "JUMPIFFALSEPOP",
+		JUMPIFFALSEPOPLONG => "JUMPIFFALSEPOPLONG",
+		INCRLOCAL => "INCRLOCAL",
+		ADDLOCALCONST => "ADDLOCALCONST",
+		SUBTRACTLOCALCONST => "SUBTRACTLOCALCONST",
+		LESSLOCALSJUMP => "LESSLOCALSJUMP",
+		LESSLOCALSJUMPLONG => "LESSLOCALSJUMPLONG",
+		LESSLOCALCONSTJUMP => "LESSLOCALCONSTJUMP",
+		LESSLOCALCONSTJUMPLONG => "LESSLOCALCONSTJUMPLONG",
+		MOVEREG => "MOVEREG",
+		LOADREG => "LOADREG",
+		ADDREG => "ADDREG",
+		ADDREGCONST => "ADDREGCONST",
+		SUBTRACTREG => "SUBTRACTREG",
+		SUBTRACTREGCONST => "SUBTRACTREGCONST",
+		MULTIPLYREG => "MULTIPLYREG",
+		MULTIPLYREGCONST => "MULTIPLYREGCONST",
+		DIVIDEREG => "DIVIDEREG",
+		DIVIDEREGCONST => "DIVIDEREGCONST",
+		JUMPIFPASSED => "JUMPIFPASSED",
+		JUMPIFPASSEDLONG => "JUMPIFPASSEDLONG",
+		GETPROPERTYLONG => "GETPROPERTYLONG",
+		UNPACKMAPLONG => "UNPACKMAPLONG",
+		CALLNAMEDLONG => "CALLNAMEDLONG",
+		BINARYREG => "BINARYREG",
+		UNARYREG => "UNARYREG",
+		RETURNREG => "RETURNREG",
+		JUMPIFFALSEREG => "JUMPIFFALSEREG",
+		JUMPIFFALSEREGLONG => "JUMPIFFALSEREGLONG",
+		JUMPUNLESSREG => "JUMPUNLESSREG",
+		JUMPUNLESSREGLONG => "JUMPUNLESSREGLONG",
+		JUMPUNLESSREGCONST => "JUMPUNLESSREGCONST",
+		JUMPUNLESSREGCONSTLONG => "JUMPUNLESSREGCONSTLONG",
+		_ => return None,
+	})
+}
+
+/// The offset of the jump at an offset and the length of the jump instruction.
+/// The offset is relative to the end of the instruction.
+pub fn read_jump(chunk: &Chunk, offset: usize) -> Option<(i64, usize)> {
+	let code = &chunk.code;
+	let op = code[offset];
+	// The offset comes after the other operands
+	let start = offset + 1 + jump_operands(op);
+	match op {
+		JUMP | JUMPIFFALSE | JUMPIFNIL | JUMPIFNOTNIL | JUMPIFPASSED | JUMPIFFALSEPOP | LESSLOCALSJUMP | LESSLOCALCONSTJUMP |
+		JUMPIFFALSEREG | JUMPUNLESSREG | JUMPUNLESSREGCONST => {
+			let jump = i16::from_be_bytes([code[start], code[start + 1]]);
+			Some((jump as i64, start + 2 - offset))
+		},
+		JUMPLONG | JUMPIFFALSELONG | JUMPIFNILLONG | JUMPIFNOTNILLONG | JUMPIFPASSEDLONG | JUMPIFFALSEPOPLONG |
+		LESSLOCALSJUMPLONG | LESSLOCALCONSTJUMPLONG | JUMPIFFALSEREGLONG | JUMPUNLESSREGLONG | JUMPUNLESSREGCONSTLONG => {
+			let jump = i32::from_be_bytes([code[start], code[start + 1], code[start + 2], code[start + 3]]);
+			Some((jump as i64, start + 4 - offset))
+		},
+		_ => None,
+	}
+}
+
+/// The amount of operands a jump has before its offset
+pub fn jump_operands(op: OpCode) -> usize {
+	match op {
+		LESSLOCALSJUMP | LESSLOCALSJUMPLONG | LESSLOCALCONSTJUMP | LESSLOCALCONSTJUMPLONG => 2,
+		JUMPIFFALSEREG | JUMPIFFALSEREGLONG => 1,
+		JUMPUNLESSREG | JUMPUNLESSREGLONG | JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG => 3,
+		_ => 0,
+	}
+}
+
+/// The length of the instruction at an offset, with its operands
+pub fn length(chunk: &Chunk, offset: usize) -> usize {
+	let code = &chunk.code;
+	let op = code[offset];
+	if let Some((_, length)) = read_jump(chunk, offset) {
+		return length;
+	}
+	match op {
+		CONSTANT | DEFGLOBAL | GETGLOBAL | SETGLOBAL | GETLOCAL | SETLOCAL |
+		LEAVE | CALL | TAILCALL | GETUPVALUE | SETUPVALUE | BUILDLIST | BUILDMAP |
+		GETPROPERTY | UNPACKVARIANT => 2,
+		CONSTANTLONG | DEFGLOBALLONG | GETGLOBALLONG | SETGLOBALLONG |
+		GETLOCALLONG | SETLOCALLONG | GETPROPERTYLONG | UNPACKLIST |
+		INCRLOCAL | ADDLOCALCONST | SUBTRACTLOCALCONST | MOVEREG | LOADREG => 3,
+		ADDREG | ADDREGCONST | SUBTRACTREG | SUBTRACTREGCONST |
+		MULTIPLYREG | MULTIPLYREGCONST | DIVIDEREG | DIVIDEREGCONST => 4,
+		RETURNREG => 3,
+		UNARYREG => 5,
+		BINARYREG => 6,
+		CALLNAMED => 3 + code[offset + 2] as usize,
+		CALLNAMEDLONG => 3 + code[offset + 2] as usize * 2,
+		UNPACKMAP => 2 + code[offset + 1] as usize,
+		UNPACKMAPLONG => 2 + code[offset + 1] as usize * 2,
+		CLOSURE | CLOSURELONG => {
+			let mut end = offset;
+			let index = read_index(chunk, &mut end, op == CLOSURELONG);
+			let upvalues = match &chunk.constants[index] {
+				Value::FUNCTION(function) => function.upvalue_count,
+				_ => 0,
+			};
+			end - offset + 1 + upvalues * 3
+		},
+		_ => 1,
+	}
+}
+
+/// Read the index operand of an op, this is two bytes for the long ops
+pub fn read_index(chunk: &Chunk, offset: &mut usize, long: bool) -> usize {
+	if long {
+		*offset += 2;
+		(chunk.code[*offset - 1] as usize) << 8 | chunk.code[*offset] as usize
+	} else {
+		*offset += 1;
+		chunk.code[*offset] as usize
+	}
+}
+
+/// The constant indexes of the names an op reads,
+/// the name of a property, the keys of UNPACKMAP or the names of named arguments
+pub fn read_names(chunk: &Chunk, offset: usize) -> Vec<usize> {
+	let code = &chunk.code;
+	let op = code[offset];
+	// The indexes start after the operand with their amount
+	let (mut position, count) = match op {
+		GETPROPERTY | GETPROPERTYLONG => (offset, 1),
+		UNPACKMAP | UNPACKMAPLONG => (offset + 1, code[offset + 1] as usize),
+		CALLNAMED | CALLNAMEDLONG => (offset + 2, code[offset + 2] as usize),
+		_ => return vec![],
+	};
+	let long = matches!(op, GETPROPERTYLONG | UNPACKMAPLONG | CALLNAMEDLONG);
+	(0..count).map(|_| read_index(chunk, &mut position, long)).collect()
+}
+
+/// The amount of sources of a register op with kinds
+pub fn sources(op: OpCode) -> usize {
+	match op {
+		BINARYREG => 2,
+		UNARYREG | RETURNREG => 1,
+		_ => 0,
+	}
+}
+
+/// If a source of a register op is a constant, by the position of the source
+pub fn is_constant(kinds: u8, source: usize) -> bool {
+	kinds >> source & 1 == 1
+}
+
+/// A source of a register op as it is shown in the disassembly,
+/// like `r0001` for a register or `k0002 (3)` for a constant
+pub fn describe_source(chunk: &Chunk, kinds: u8, source: usize, index: u8) -> String {
+	if is_constant(kinds, source) {
+		format!("k{:04} ({})", index, describe(&chunk.constants[index as usize]))
+	} else {
+		format!("r{:04}", index)
+	}
+}
+
+/// A constant as it is shown next to its index.
+/// Strings are escaped so an instruction stays on one line.
+pub fn describe(value: &Value) -> String {
+	value.to_string().escape_debug().to_string()
+}
+
 /// Disassemble an instruction in a chunk
 pub fn disassemble(chunk: &Chunk, offset: usize) -> (String, usize) {
 	let op_offset = offset;
@@ -119,74 +598,197 @@ pub fn disassemble(chunk: &Chunk, offset: usize) -> (String, usize) {
 	};*/	
 
 	let name = match op {
-		CONSTANT => {
-			offset+=1;
-			let index = chunk.code[offset];
-			let value = &chunk.constants[index as usize];
-			format!("{} {:04} ({})", "CONSTANT", index, value.to_string())
+		CONSTANT | CONSTANTLONG => {
+			let index = read_index(chunk, &mut offset, op == CONSTANTLONG);
+			let name = if op == CONSTANT {"CONSTANT"} else {"CONSTANTLONG"};
+			format!("{} {:04} ({})", name, index, describe(&chunk.constants[index]))
 		},
-		DEFGLOBAL => {
-			offset+=1;
-			let index = chunk.code[offset];
-			format!("{} {:04}", "DEFGLOBAL", index)
+		DEFGLOBAL | DEFGLOBALLONG => {
+			let index = read_index(chunk, &mut offset, op == DEFGLOBALLONG);
+			let name = if op == DEFGLOBAL {"DEFGLOBAL"} else {"DEFGLOBALLONG"};
+			format!("{} {:04}", name, index)
 		},
-		GETGLOBAL => {
-			offset+=1;
-			let index = chunk.code[offset];
-			format!("{} {:04}", "GETGLOBAL", index)
+		GETGLOBAL | GETGLOBALLONG => {
+			let index = read_index(chunk, &mut offset, op == GETGLOBALLONG);
+			let name = if op == GETGLOBAL {"GETGLOBAL"} else {"GETGLOBALLONG"};
+			format!("{} {:04}", name, index)
 		},
-		SETGLOBAL => {
-			offset+=1;
-			let index = chunk.code[offset];
-			format!("{} {:04}", "SETGLOBAL", index)
+		SETGLOBAL | SETGLOBALLONG => {
+			let index = read_index(chunk, &mut offset, op == SETGLOBALLONG);
+			let name = if op == SETGLOBAL {"SETGLOBAL"} else {"SETGLOBALLONG"};
+			format!("{} {:04}", name, index)
+		},
+		GETLOCAL | GETLOCALLONG => {
+			let index = read_index(chunk, &mut offset, op == GETLOCALLONG);
+			let name = if op == GETLOCAL {"GETLOCAL"} else {"GETLOCALLONG"};
+			format!("{} {:04}", name, index)
+		},
+		SETLOCAL | SETLOCALLONG => {
+			let index = read_index(chunk, &mut offset, op == SETLOCALLONG);
+			let name = if op == SETLOCAL {"SETLOCAL"} else {"SETLOCALLONG"};
+			format!("{} {:04}", name, index)
 		},
-		GETLOCAL => {
+		GETPROPERTY | GETPROPERTYLONG => {
+			let index = read_index(chunk, &mut offset, op == GETPROPERTYLONG);
+			format!("{} {:04} ({})", name(op).unwrap(), index, describe(&chunk.constants[index]))
+		},
+		JUMP | JUMPIFFALSE | JUMPIFNIL | JUMPIFNOTNIL | JUMPIFPASSED | JUMPIFFALSEPOP |
+		JUMPLONG | JUMPIFFALSELONG | JUMPIFNILLONG | JUMPIFNOTNILLONG | JUMPIFPASSEDLONG | JUMPIFFALSEPOPLONG => {
+			let (jump, length) = read_jump(chunk, op_offset).unwrap();
+			offset = op_offset + length - 1;
+			let index = (op_offset + length) as i64 + jump;
+			format!("{} {} ({:04})", name(op).unwrap(), jump, index)
+		},
+		MOVEREG => {
+			offset += 2;
+			format!("{} {:04} {:04}", "MOVEREG", chunk.code[offset - 1], chunk.code[offset])
+		},
+		ADDREG | SUBTRACTREG | MULTIPLYREG | DIVIDEREG => {
+			offset += 3;
+			let (target, a, b) = (chunk.code[offset - 2], chunk.code[offset - 1], chunk.code[offset]);
+			format!("{} {:04} {:04} {:04}", name(op).unwrap(), target, a, b)
+		},
+		ADDREGCONST | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST => {
+			offset += 3;
+			let (target, local, index) = (chunk.code[offset - 2], chunk.code[offset - 1], chunk.code[offset]);
+			format!("{} {:04} {:04} {:04} ({})", name(op).unwrap(), target, local, index, describe(&chunk.constants[index as usize]))
+		},
+		INCRLOCAL | ADDLOCALCONST | SUBTRACTLOCALCONST | LOADREG => {
+			let local = chunk.code[offset + 1];
+			let index = chunk.code[offset + 2];
+			offset += 2;
+			format!("{} {:04} {:04} ({})", name(op).unwrap(), local, index, describe(&chunk.constants[index as usize]))
+		},
+		LESSLOCALSJUMP | LESSLOCALSJUMPLONG => {
+			let (jump, length) = read_jump(chunk, op_offset).unwrap();
+			let (a, b) = (chunk.code[offset + 1], chunk.code[offset + 2]);
+			offset = op_offset + length - 1;
+			let index = (op_offset + length) as i64 + jump;
+			format!("{} {:04} {:04} {} ({:04})", name(op).unwrap(), a, b, jump, index)
+		},
+		LESSLOCALCONSTJUMP | LESSLOCALCONSTJUMPLONG => {
+			let (jump, length) = read_jump(chunk, op_offset).unwrap();
+			let (local, index) = (chunk.code[offset + 1], chunk.code[offset + 2]);
+			offset = op_offset + length - 1;
+			let target = (op_offset + length) as i64 + jump;
+			format!("{} {:04} {:04} ({}) {} ({:04})", name(op).unwrap(), local, index, describe(&chunk.constants[index as usize]), jump, target)
+		},
+		BINARYREG | UNARYREG | RETURNREG => {
+			offset = op_offset + length(chunk, op_offset) - 1;
+			let operands = &chunk.code[op_offset + 1..=offset];
+			let mut str = name(op).unwrap().to_owned();
+			// The operator and the register it stores in
+			if op != RETURNREG {
+				str.push_str(&format!(" {} {:04}", name(operands[0]).unwrap_or("unknown"), operands[1]));
+			}
+			let (kinds, sources) = operands[operands.len() - sources(op) - 1..].split_first().unwrap();
+			for (i, source) in sources.iter().enumerate() {
+				str.push(' ');
+				str.push_str(&describe_source(chunk, *kinds, i, *source));
+			}
+			str
+		},
+		JUMPIFFALSEREG | JUMPIFFALSEREGLONG => {
+			let (jump, length) = read_jump(chunk, op_offset).unwrap();
+			let register = chunk.code[offset + 1];
+			offset = op_offset + length - 1;
+			let index = (op_offset + length) as i64 + jump;
+			format!("{} {:04} {} ({:04})", name(op).unwrap(), register, jump, index)
+		},
+		JUMPUNLESSREG | JUMPUNLESSREGLONG | JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG => {
+			let (jump, length) = read_jump(chunk, op_offset).unwrap();
+			let (comparison, a, b) = (chunk.code[offset + 1], chunk.code[offset + 2], chunk.code[offset + 3]);
+			offset = op_offset + length - 1;
+			let target = (op_offset + length) as i64 + jump;
+			let mut str = format!("{} {} {:04} {:04}", name(op).unwrap(), name(comparison).unwrap_or("unknown"), a, b);
+			if let JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG = op {
+				str.push_str(&format!(" ({})", describe(&chunk.constants[b as usize])));
+			}
+			format!("{} {} ({:04})", str, jump, target)
+		},
+		CALLNAMED | CALLNAMEDLONG => {
+			let argc = chunk.code[offset+1];
+			offset = op_offset + length(chunk, op_offset) - 1;
+			let mut str = format!("{} {}", name(op).unwrap(), argc);
+			let mut names = vec![];
+			for index in read_names(chunk, op_offset) {
+				str.push_str(&format!(" {:04}", index));
+				names.push(describe(&chunk.constants[index]));
+			}
+			format!("{} ({})", str, names.join(", "))
+		},
+		BUILDLIST => {
 			offset+=1;
-			let index = chunk.code[offset];
-			format!("{} {:04}", "GETLOCAL", index)
+			let n = chunk.code[offset];
+			format!("{} {}", "BUILDLIST", n)
 		},
-		SETLOCAL => {
+		BUILDMAP => {
 			offset+=1;
-			let index = chunk.code[offset];
-			format!("{} {:04}", "SETLOCAL", index)
+			let n = chunk.code[offset];
+			format!("{} {}", "BUILDMAP", n)
 		},
-		JUMP => {
+		UNPACKLIST => {
 			offset+=2;
-			let offset = (chunk.code[offset -1] as i16) << 8 | chunk.code[offset] as i16;
-			let index = op_offset as i16 + offset + 3;
-			format!("{} {} ({:04})", "JUMP", offset, index)
+			let n = chunk.code[offset-1];
+			let rest = if chunk.code[offset] == 1 {" rest"} else {""};
+			format!("{} {}{}", "UNPACKLIST", n, rest)
 		},
-		JUMPIFFALSE => {
-			offset+=2;
-			let offset = (chunk.code[offset -1] as i16) << 8 | chunk.code[offset] as i16;
-			let index = op_offset as i16 + offset + 3;
-			format!("{} {} ({:04})", "JUMPIFFALSE", offset, index)
+		UNPACKMAP | UNPACKMAPLONG => {
+			offset = op_offset + length(chunk, op_offset) - 1;
+			let mut str = name(op).unwrap().to_owned();
+			let mut keys = vec![];
+			for index in read_names(chunk, op_offset) {
+				str.push_str(&format!(" {:04}", index));
+				keys.push(describe(&chunk.constants[index]));
+			}
+			format!("{} ({})", str, keys.join(", "))
 		},
 		LEAVE => {
 			offset+=1;
 			let n = chunk.code[offset];
 			format!("{} {}", "LEAVE", n)
 		},
-		_ => {
-			match op {
-				RETURN => "RETURN",
-				NEGATE => "NEGATE",
-				ADD => "ADD",
-				SUBTRACT => "SUBTRACT",
-				MULTIPLY => "MULTIPLY",
-				DIVIDE => "DIVIDE",
-				NIL => "NIL",
-				TRUE => "TRUE",
-				FALSE => "FALSE",
-				NOT => "NOT",
-				EQUAL => "EQUAL",
-				GREATER => "GREATER",
-				LESS => "LESS",
-				PRINT => "PRINT",
-				POP => "POP",
-				_ => "unknown",
-			}.to_owned()
-		}
+		UNPACKVARIANT => {
+			offset+=1;
+			let n = chunk.code[offset];
+			format!("{} {}", "UNPACKVARIANT", n)
+		},
+		CALL => {
+			offset+=1;
+			let argc = chunk.code[offset];
+			format!("{} {}", "CALL", argc)
+		},
+		TAILCALL => {
+			offset+=1;
+			let argc = chunk.code[offset];
+			format!("{} {}", "TAILCALL", argc)
+		},
+		GETUPVALUE => {
+			offset+=1;
+			let index = chunk.code[offset];
+			format!("{} {:04}", "GETUPVALUE", index)
+		},
+		SETUPVALUE => {
+			offset+=1;
+			let index = chunk.code[offset];
+			format!("{} {:04}", "SETUPVALUE", index)
+		},
+		CLOSURE | CLOSURELONG => {
+			let index = read_index(chunk, &mut offset, op == CLOSURELONG);
+			let value = &chunk.constants[index];
+			let name = if op == CLOSURE {"CLOSURE"} else {"CLOSURELONG"};
+			let mut str = format!("{} {:04} ({})", name, index, describe(value));
+			if let Value::FUNCTION(function) = value {
+				for _ in 0..function.upvalue_count {
+					let kind = if chunk.code[offset+1] == 1 {"local"} else {"upvalue"};
+					offset+=1;
+					let index = read_index(chunk, &mut offset, true);
+					str.push_str(&format!(" {} {}", kind, index));
+				}
+			}
+			str
+		},
+		_ => name(op).unwrap_or("unknown").to_owned(),
 	};
 
 	let line = format!("{} {:04} {}\n",line_n_str, op_offset, name);
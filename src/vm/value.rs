@@ -5,6 +5,13 @@ So using an enum I get pretty much the same behavior, but I am using a safe type
 Memory is exactly the same, both are a union and a single byte identifier
 */
 
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::vm::function::{Function, Closure, Generator};
+use crate::vm::enums::{Enum, EnumValue};
+use crate::vm::native::Native;
+
 #[allow(non_camel_case_types)]
 pub type number = f64;
 
@@ -19,9 +26,28 @@ pub enum Value {
 	NUMBER(number),
 	STRING(String),
 	CHAR(char),
+	FUNCTION(Rc<Function>),
+	CLOSURE(Rc<Closure>),
+	GENERATOR(Rc<RefCell<Generator>>),
+	LIST(Rc<RefCell<Vec<Value>>>),
+	MAP(Rc<RefCell<HashMap<String, Value>>>),
+	ENUM(Rc<Enum>),
+	VARIANT(Rc<EnumValue>),
+	NATIVE(&'static Native),
+	/// An argument that wasn't passed, this is only in the slot of its parameter
+	/// until the function replaces it with the default value
+	MISSING,
 	NIL
 }
 
+/// What the VM keeps on its stack and in variables.
+/// With the nan-boxing feature this is a value packed in 8 bytes,
+/// otherwise it is just a Value.
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+#[cfg(feature = "nan-boxing")]
+pub use crate::vm::nan_boxing::Slot;
+
 impl From<bool> for Value {
 	fn from(value: bool) -> Value {
 		Value::BOOL(value)
@@ -46,6 +72,18 @@ impl From<char> for Value {
 	}
 }
 
+impl From<Vec<Value>> for Value {
+	fn from(value: Vec<Value>) -> Value {
+		Value::LIST(Rc::new(RefCell::new(value)))
+	}
+}
+
+impl From<HashMap<String, Value>> for Value {
+	fn from(value: HashMap<String, Value>) -> Value {
+		Value::MAP(Rc::new(RefCell::new(value)))
+	}
+}
+
 impl From<Value> for number {
 	fn from(value: Value) -> number {
 		match value {
@@ -91,6 +129,28 @@ impl std::fmt::Display for Value {
 			Value::BOOL(bool) => bool.to_string(),
 			Value::STRING(string) => string.clone(),
 			Value::CHAR(char) => char.to_string(),
+			Value::FUNCTION(function) => function.to_string(),
+			Value::CLOSURE(closure) => closure.function.to_string(),
+			Value::GENERATOR(generator) => match &generator.borrow().closure.function.name {
+				Some(name) => format!("<generator {}>", name),
+				None => "<generator>".to_owned(),
+			},
+			Value::LIST(list) => {
+				let items: Vec<String> = list.borrow().iter().map(|item| item.to_string()).collect();
+				format!("[{}]", items.join(", "))
+			},
+			Value::MAP(map) => {
+				// Sorted so the output doesn't depend on the hashing
+				let map = map.borrow();
+				let mut keys: Vec<&String> = map.keys().collect();
+				keys.sort();
+				let items: Vec<String> = keys.iter().map(|key| format!("{}: {}", key, map[*key])).collect();
+				format!("{{{}}}", items.join(", "))
+			},
+			Value::ENUM(enumeration) => format!("<enum {}>", enumeration.name),
+			Value::VARIANT(variant) => variant.to_string(),
+			Value::NATIVE(_) => "<native fn>".to_owned(),
+			Value::MISSING => "<missing>".to_owned(),
 			Value::NIL => "nil".to_owned()
 		})
 	}
@@ -111,6 +171,32 @@ impl Value {
 	pub fn equal(&self, second: Value) -> bool {
 		self == &second
 	}
+
+	// These are also implemented by the NaN-boxed Slot,
+	// so the VM can check a value without unpacking it
+
+	pub fn is_nil(&self) -> bool {
+		matches!(self, Value::NIL)
+	}
+
+	pub fn is_missing(&self) -> bool {
+		matches!(self, Value::MISSING)
+	}
+
+	pub fn is_string(&self) -> bool {
+		matches!(self, Value::STRING(_))
+	}
+
+	pub fn as_number(&self) -> Option<number> {
+		match self {
+			Value::NUMBER(number) => Some(*number),
+			_ => None
+		}
+	}
+
+	pub fn to_value(&self) -> Value {
+		self.clone()
+	}
 }
 
 /* UNION IMPLEMENTATION
@@ -0,0 +1,463 @@
+use std::fmt;
+use crate::vm::chunk::Chunk;
+use crate::vm::op_codes::*;
+use crate::vm::value::Value;
+
+/*
+The VM reads the code and constants without bounds checks.
+That is only sound for code the compiler could have produced,
+so every chunk is checked once before it runs.
+
+First every instruction is decoded, which checks the ops and their operands.
+Then the code is followed along all of its paths to find how many values are on the stack
+before each instruction. Every path to an instruction has to agree on that,
+so an op never pops a value that isn't there and never reads a local that was popped.
+The script is also checked for reading globals before they are defined,
+which can happen when a goto jumps over a declaration.
+Functions can read globals that are defined after them, so they aren't checked for that.
+*/
+
+/// Why a chunk can't be run
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+	UnknownOpcode {offset: usize, op: OpCode},
+	/// The operands go past the end of the code
+	CutOff {offset: usize, op: OpCode},
+	MissingConstant {offset: usize, op: OpCode, index: usize},
+	/// A closure is made of a constant that isn't a function
+	NotAFunction {offset: usize, op: OpCode, index: usize},
+	/// A name operand isn't a string constant
+	NotAName {offset: usize, op: OpCode, index: usize},
+	MissingUpvalue {offset: usize, op: OpCode, index: usize},
+	/// The operator or kinds of a register op aren't ones it can have
+	InvalidOperand {offset: usize, op: OpCode},
+	InvalidCapture {offset: usize},
+	MissingReturn,
+	InvalidJump {offset: usize},
+	/// Only a generator can yield, as the VM resumes it through its caller
+	YieldOutsideGenerator {offset: usize},
+	/// An op pops more values than there are on the stack
+	StackUnderflow {offset: usize, op: OpCode, depth: usize},
+	/// Two paths reach an instruction with a different amount of values on the stack
+	StackMismatch {offset: usize, depth: usize, other: usize},
+	MissingLocal {offset: usize, op: OpCode, index: usize},
+	/// The name is only known when the chunk has the names of its globals
+	UndefinedGlobal {line: u32, index: usize, name: Option<String>},
+	/// An error in a function in the constants
+	InFunction {name: String, error: Box<VerifyError>},
+}
+
+impl fmt::Display for VerifyError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// Only an unknown op has no name
+		let op_name = |op: &OpCode| name(*op).unwrap_or("unknown");
+		match self {
+			VerifyError::UnknownOpcode {offset, op} => write!(f, "unknown opcode {:#x} at {:04}", op, offset),
+			VerifyError::CutOff {offset, op} => write!(f, "operands of {} at {:04} are cut off", op_name(op), offset),
+			VerifyError::MissingConstant {offset, op, index} => {
+				write!(f, "constant {} of {} at {:04} doesn't exist", index, op_name(op), offset)
+			},
+			VerifyError::NotAFunction {offset, op, index} => {
+				write!(f, "constant {} of {} at {:04} isn't a function", index, op_name(op), offset)
+			},
+			VerifyError::NotAName {offset, op, index} => {
+				write!(f, "name {} of {} at {:04} isn't a string constant", index, op_name(op), offset)
+			},
+			VerifyError::MissingUpvalue {offset, op, index} => {
+				write!(f, "upvalue {} of {} at {:04} doesn't exist", index, op_name(op), offset)
+			},
+			VerifyError::InvalidOperand {offset, op} => write!(f, "{} at {:04} has an invalid operator or kinds", op_name(op), offset),
+			VerifyError::InvalidCapture {offset} => write!(f, "closure at {:04} captures an upvalue that doesn't exist", offset),
+			VerifyError::MissingReturn => write!(f, "code doesn't end with a return or jump"),
+			VerifyError::InvalidJump {offset} => write!(f, "jump at {:04} doesn't land on an instruction", offset),
+			VerifyError::YieldOutsideGenerator {offset} => write!(f, "yield at {:04} isn't in a generator", offset),
+			VerifyError::StackUnderflow {offset, op, depth} => {
+				write!(f, "{} at {:04} pops more than the {} values on the stack", op_name(op), offset, depth)
+			},
+			VerifyError::StackMismatch {offset, depth, other} => {
+				write!(f, "paths reach {:04} with {} and {} values on the stack", offset, depth, other)
+			},
+			VerifyError::MissingLocal {offset, op, index} => {
+				write!(f, "local {} of {} at {:04} isn't on the stack", index, op_name(op), offset)
+			},
+			VerifyError::UndefinedGlobal {line, name: Some(name), ..} => {
+				write!(f, "global '{}' on line {} can be used before it is defined", name, line)
+			},
+			VerifyError::UndefinedGlobal {line, index, name: None} => {
+				write!(f, "global {} on line {} can be used before it is defined", index, line)
+			},
+			VerifyError::InFunction {name, error} => write!(f, "{} in {}", error, name),
+		}
+	}
+}
+
+/// Check that the script, and the functions in it, can be run without reading out of bounds
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+	verify_chunk(chunk, Frame {upvalue_count: 0, params: 0, script: true, generator: false}, true)
+}
+
+/// Only check that the code can be split into instructions, without following its paths.
+/// This is enough to look at code that would go wrong when it runs.
+pub fn verify_code(chunk: &Chunk) -> Result<(), VerifyError> {
+	verify_chunk(chunk, Frame {upvalue_count: 0, params: 0, script: true, generator: false}, false)
+}
+
+/// What a chunk can use of the function it is in
+#[derive(Clone, Copy)]
+struct Frame {
+	upvalue_count: usize,
+	/// The values on the stack when the function starts, the parameters
+	params: usize,
+	script: bool,
+	generator: bool,
+}
+
+fn verify_chunk(chunk: &Chunk, frame: Frame, follow_paths: bool) -> Result<(), VerifyError> {
+	let code = &chunk.code;
+	let mut boundaries = vec![false; code.len()];
+	let mut jumps = vec![];
+	let mut offset = 0;
+	let mut last = None;
+
+	while offset < code.len() {
+		let op = code[offset];
+		if name(op).is_none() {
+			return Err(VerifyError::UnknownOpcode {offset, op});
+		}
+		let length = instruction_length(chunk, offset)?;
+		boundaries[offset] = true;
+
+		if let Some(operand) = constant_operand(op) {
+			let index = read_index(chunk, &mut (offset + operand - 1), op == CONSTANTLONG);
+			if index >= chunk.constants.len() {
+				return Err(VerifyError::MissingConstant {offset, op, index});
+			}
+		}
+		match op {
+			CLOSURE | CLOSURELONG => check_closure(chunk, offset, frame.upvalue_count)?,
+			GETPROPERTY | GETPROPERTYLONG | UNPACKMAP | UNPACKMAPLONG | CALLNAMED | CALLNAMEDLONG => {
+				for index in read_names(chunk, offset) {
+					check_name(chunk, index, offset)?;
+				}
+			},
+			GETUPVALUE | SETUPVALUE if code[offset + 1] as usize >= frame.upvalue_count => {
+				return Err(VerifyError::MissingUpvalue {offset, op, index: code[offset + 1] as usize});
+			},
+			YIELD if !frame.generator => return Err(VerifyError::YieldOutsideGenerator {offset}),
+			BINARYREG | UNARYREG | RETURNREG => check_sources(chunk, offset)?,
+			JUMPUNLESSREG | JUMPUNLESSREGLONG | JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG if !is_comparison(code[offset + 1]) => {
+				return Err(VerifyError::InvalidOperand {offset, op});
+			},
+			_ => {}
+		}
+
+		if let Some((jump, length)) = read_jump(chunk, offset) {
+			jumps.push((offset, offset as i64 + length as i64 + jump));
+		}
+		last = Some(op);
+		offset += length;
+	}
+
+	// Running past the end would read outside of the code
+	if !matches!(last, Some(RETURN | RETURNREG | JUMP | JUMPLONG)) {
+		return Err(VerifyError::MissingReturn);
+	}
+	for (offset, target) in jumps {
+		if target < 0 || target as usize >= code.len() || !boundaries[target as usize] {
+			return Err(VerifyError::InvalidJump {offset});
+		}
+	}
+
+	if follow_paths {
+		check_paths(chunk, frame)?;
+	}
+
+	for constant in &chunk.constants {
+		if let Value::FUNCTION(function) = constant {
+			let frame = Frame {
+				upvalue_count: function.upvalue_count,
+				params: function.arity as usize + function.rest.is_some() as usize,
+				script: false,
+				generator: function.generator,
+			};
+			verify_chunk(&function.chunk, frame, follow_paths).map_err(|error| VerifyError::InFunction {
+				name: function.to_string(),
+				error: Box::new(error),
+			})?;
+		}
+	}
+	Ok(())
+}
+
+/// The length of an instruction, checking that it fits in the code
+fn instruction_length(chunk: &Chunk, offset: usize) -> Result<usize, VerifyError> {
+	let code = &chunk.code;
+	let op = code[offset];
+	// Bytes that have to be there to know the length
+	let header = match op {
+		_ if long_jump(op) != op => 3 + jump_operands(op),
+		_ if short_jump(op) != op => 5 + jump_operands(op),
+		CALLNAMED | CALLNAMEDLONG | CLOSURELONG | UNPACKLIST => 3,
+		CLOSURE | UNPACKMAP | UNPACKMAPLONG => 2,
+		_ => 1,
+	};
+	if offset + header > code.len() {
+		return Err(VerifyError::CutOff {offset, op});
+	}
+	if let CLOSURE | CLOSURELONG = op {
+		let index = read_index(chunk, &mut offset.clone(), op == CLOSURELONG);
+		match chunk.constants.get(index) {
+			Some(Value::FUNCTION(_)) => {},
+			Some(_) => return Err(VerifyError::NotAFunction {offset, op, index}),
+			None => return Err(VerifyError::MissingConstant {offset, op, index}),
+		}
+	}
+	let length = length(chunk, offset);
+	if offset + length > code.len() {
+		return Err(VerifyError::CutOff {offset, op});
+	}
+	Ok(length)
+}
+
+/// The upvalues a closure captures have to exist in the function creating it
+fn check_closure(chunk: &Chunk, offset: usize, upvalue_count: usize) -> Result<(), VerifyError> {
+	let code = &chunk.code;
+	let mut entry = offset;
+	let index = read_index(chunk, &mut entry, code[offset] == CLOSURELONG);
+	let Value::FUNCTION(function) = &chunk.constants[index] else {
+		unreachable!()
+	};
+	for _ in 0..function.upvalue_count {
+		let is_local = code[entry + 1];
+		let captured = (code[entry + 2] as usize) << 8 | code[entry + 3] as usize;
+		if is_local > 1 || is_local == 0 && captured >= upvalue_count {
+			return Err(VerifyError::InvalidCapture {offset});
+		}
+		entry += 3;
+	}
+	Ok(())
+}
+
+/// Where the constant index of an op is, counting from the op
+fn constant_operand(op: OpCode) -> Option<usize> {
+	match op {
+		CONSTANT | CONSTANTLONG => Some(1),
+		INCRLOCAL | ADDLOCALCONST | SUBTRACTLOCALCONST | LESSLOCALCONSTJUMP | LESSLOCALCONSTJUMPLONG | LOADREG => Some(2),
+		ADDREGCONST | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST | JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG => Some(3),
+		_ => None,
+	}
+}
+
+/// The operator of a register op has to be one it can do, and its constant sources have to exist
+fn check_sources(chunk: &Chunk, offset: usize) -> Result<(), VerifyError> {
+	let code = &chunk.code;
+	let op = code[offset];
+	let operator = code[offset + 1];
+	let valid = match op {
+		BINARYREG => is_comparison(operator) || matches!(operator, ADD | SUBTRACT | MULTIPLY | DIVIDE),
+		UNARYREG => matches!(operator, NEGATE | NOT),
+		_ => true,
+	};
+	let end = offset + length(chunk, offset);
+	let (kinds, sources) = code[end - sources(op) - 1..end].split_first().unwrap();
+	if !valid || kinds >> sources.len() != 0 {
+		return Err(VerifyError::InvalidOperand {offset, op});
+	}
+	for (i, index) in sources.iter().enumerate() {
+		if is_constant(*kinds, i) && *index as usize >= chunk.constants.len() {
+			return Err(VerifyError::MissingConstant {offset, op, index: *index as usize});
+		}
+	}
+	Ok(())
+}
+
+/// The operators a register op can compare with
+fn is_comparison(operator: OpCode) -> bool {
+	matches!(operator, GREATER | LESS | GREATEREQUAL | LESSEQUAL | EQUAL | NOTEQUAL)
+}
+
+/// Names are read as strings from the constants
+fn check_name(chunk: &Chunk, index: usize, offset: usize) -> Result<(), VerifyError> {
+	match chunk.constants.get(index) {
+		Some(Value::STRING(_)) => Ok(()),
+		_ => Err(VerifyError::NotAName {offset, op: chunk.code[offset], index}),
+	}
+}
+
+/// What is known before an instruction on every path that reaches it
+#[derive(Clone, PartialEq)]
+struct State {
+	depth: usize,
+	/// The globals that are defined, only kept for the script
+	globals: Vec<bool>,
+}
+
+/// Follow every path through the code, checking the stack and globals before each instruction
+fn check_paths(chunk: &Chunk, frame: Frame) -> Result<(), VerifyError> {
+	let code = &chunk.code;
+	let mut states: Vec<Option<State>> = vec![None; code.len()];
+	states[0] = Some(State {depth: frame.params, globals: vec![]});
+	let mut pending = vec![0];
+
+	while let Some(offset) = pending.pop() {
+		let mut state = states[offset].clone().unwrap();
+		let op = code[offset];
+
+		// A function can capture itself, in the slot the closure is pushed to
+		let slots = if let CLOSURE | CLOSURELONG = op {state.depth + 1} else {state.depth};
+		for index in locals(chunk, offset) {
+			if index >= slots {
+				return Err(VerifyError::MissingLocal {offset, op, index});
+			}
+		}
+		// A register op can store in the register above the others, which pushes its result
+		if let BINARYREG | UNARYREG = op {
+			let register = code[offset + 2] as usize;
+			if register > state.depth {
+				return Err(VerifyError::MissingLocal {offset, op, index: register});
+			}
+		}
+		if frame.script {
+			match op {
+				GETGLOBAL | GETGLOBALLONG => {
+					let index = read_index(chunk, &mut offset.clone(), op == GETGLOBALLONG);
+					if !state.globals.get(index).copied().unwrap_or(false) {
+						return Err(VerifyError::UndefinedGlobal {
+							line: chunk.line_at(offset).unwrap_or(0),
+							index,
+							name: chunk.globals.get(index).cloned(),
+						});
+					}
+				},
+				// Assigning a global defines it as well
+				DEFGLOBAL | DEFGLOBALLONG | SETGLOBAL | SETGLOBALLONG => {
+					let index = read_index(chunk, &mut offset.clone(), op == DEFGLOBALLONG || op == SETGLOBALLONG);
+					if state.globals.len() <= index {
+						state.globals.resize(index + 1, false);
+					}
+					state.globals[index] = true;
+				},
+				_ => {}
+			}
+		}
+
+		let operands = &code[offset + 1..offset + length(chunk, offset)];
+		let (pops, pushes) = stack_effect(op, operands, state.depth, frame.script);
+		if pops > state.depth {
+			return Err(VerifyError::StackUnderflow {offset, op, depth: state.depth});
+		}
+		state.depth = state.depth - pops + pushes;
+
+		for next in successors(chunk, offset) {
+			match &mut states[next] {
+				Some(known) if known.depth != state.depth => {
+					return Err(VerifyError::StackMismatch {offset: next, depth: known.depth, other: state.depth});
+				},
+				Some(known) => {
+					// A global is only defined if it is on every path
+					let globals: Vec<bool> = known.globals.iter()
+						.enumerate()
+						.map(|(index, defined)| *defined && state.globals.get(index).copied().unwrap_or(false))
+						.collect();
+					if globals != known.globals {
+						known.globals = globals;
+						pending.push(next);
+					}
+				},
+				None => {
+					states[next] = Some(state.clone());
+					pending.push(next);
+				},
+			}
+		}
+	}
+	Ok(())
+}
+
+/// The instructions that can run after an instruction
+fn successors(chunk: &Chunk, offset: usize) -> Vec<usize> {
+	let op = chunk.code[offset];
+	let next = offset + length(chunk, offset);
+	let mut successors = vec![];
+	if !matches!(op, RETURN | RETURNREG | JUMP | JUMPLONG | NOMATCH) {
+		successors.push(next);
+	}
+	if let Some((jump, length)) = read_jump(chunk, offset) {
+		successors.push((offset as i64 + length as i64 + jump) as usize);
+	}
+	successors
+}
+
+/// The amount of values an instruction pops and pushes, with the depth of the stack before it.
+/// The operands are the bytes after the op.
+pub fn stack_effect(op: OpCode, operands: &[u8], depth: usize, script: bool) -> (usize, usize) {
+	let operand = |n: usize| operands[n - 1] as usize;
+	match op {
+		// The script stops without looking at the stack
+		RETURN if script => (0, 0),
+		RETURN | POP | PRINT | DEFGLOBAL | DEFGLOBALLONG | JUMPIFFALSEPOP | JUMPIFFALSEPOPLONG | NOMATCH => (1, 0),
+		CONSTANT | CONSTANTLONG | NIL | TRUE | FALSE | GETGLOBAL | GETGLOBALLONG | GETLOCAL | GETLOCALLONG |
+		GETUPVALUE | CLOSURE | CLOSURELONG | ADDLOCALCONST | SUBTRACTLOCALCONST => (0, 1),
+		// The value sent when the generator is resumed replaces the one it yields
+		NEGATE | NOT | GETPROPERTY | GETPROPERTYLONG | YIELD | SETGLOBAL | SETGLOBALLONG | SETLOCAL | SETLOCALLONG | SETUPVALUE |
+		JUMPIFFALSE | JUMPIFFALSELONG | JUMPIFNIL | JUMPIFNILLONG | JUMPIFNOTNIL | JUMPIFNOTNILLONG |
+		JUMPIFPASSED | JUMPIFPASSEDLONG => (1, 1),
+		DUP => (1, 2),
+		ADD | SUBTRACT | MULTIPLY | DIVIDE | EQUAL | NOTEQUAL | GREATER | LESS |
+		GREATEREQUAL | LESSEQUAL | MATCH => (2, 1),
+		LEAVE => (operand(1), 0),
+		// The callee and arguments are replaced with the result
+		CALL | TAILCALL => (operand(1) + 1, 1),
+		CALLNAMED | CALLNAMEDLONG => (operand(1) + operand(2) + 1, 1),
+		BUILDLIST => (operand(1), 1),
+		BUILDMAP => (operand(1) * 2, 1),
+		UNPACKLIST => (1, operand(1) + operand(2)),
+		UNPACKMAP | UNPACKMAPLONG | UNPACKVARIANT => (1, operand(1)),
+		// Everything from the register that is stored in is replaced
+		BINARYREG | UNARYREG => (depth.saturating_sub(operand(2)), 1),
+		// Jumps, superinstructions and register ops that only work on locals
+		_ => (0, 0),
+	}
+}
+
+/// The locals an instruction reads or writes
+fn locals(chunk: &Chunk, offset: usize) -> Vec<usize> {
+	let code = &chunk.code;
+	let op = code[offset];
+	let operand = |n: usize| code[offset + n] as usize;
+	match op {
+		GETLOCAL | SETLOCAL | INCRLOCAL | ADDLOCALCONST | SUBTRACTLOCALCONST | LOADREG |
+		LESSLOCALCONSTJUMP | LESSLOCALCONSTJUMPLONG => vec![operand(1)],
+		GETLOCALLONG | SETLOCALLONG => vec![operand(1) << 8 | operand(2)],
+		MOVEREG | LESSLOCALSJUMP | LESSLOCALSJUMPLONG |
+		ADDREGCONST | SUBTRACTREGCONST | MULTIPLYREGCONST | DIVIDEREGCONST => vec![operand(1), operand(2)],
+		ADDREG | SUBTRACTREG | MULTIPLYREG | DIVIDEREG => vec![operand(1), operand(2), operand(3)],
+		JUMPIFFALSEREG | JUMPIFFALSEREGLONG => vec![operand(1)],
+		JUMPUNLESSREGCONST | JUMPUNLESSREGCONSTLONG => vec![operand(2)],
+		JUMPUNLESSREG | JUMPUNLESSREGLONG => vec![operand(2), operand(3)],
+		BINARYREG | UNARYREG | RETURNREG => {
+			let end = offset + length(chunk, offset);
+			let (kinds, sources) = code[end - sources(op) - 1..end].split_first().unwrap();
+			sources.iter().enumerate()
+				.filter(|(i, _)| !is_constant(*kinds, *i))
+				.map(|(_, register)| *register as usize)
+				.collect()
+		},
+		CLOSURE | CLOSURELONG => {
+			let mut entry = offset;
+			let index = read_index(chunk, &mut entry, op == CLOSURELONG);
+			let Value::FUNCTION(function) = &chunk.constants[index] else {
+				unreachable!()
+			};
+			let mut captured = vec![];
+			for _ in 0..function.upvalue_count {
+				if code[entry + 1] == 1 {
+					captured.push((code[entry + 2] as usize) << 8 | code[entry + 3] as usize);
+				}
+				entry += 3;
+			}
+			captured
+		},
+		_ => vec![],
+	}
+}
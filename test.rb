@@ -1,7 +1,16 @@
 #!/usr/bin/env ruby
 require 'open3'
+require 'tmpdir'
 TEST_DIR  = "./test"
 BINARY = "./target/release/loxidation"
+# Extra options for the interpreter, like FLAGS=--registers
+FLAGS = (ENV["FLAGS"] || "").split
+# Compile the tests to C and run those instead, with TRANSPILE=1
+TRANSPILE = ENV["TRANSPILE"]
+# Save the tests as bytecode and run the .loxc files, with BYTECODE=1
+BYTECODE = ENV["BYTECODE"]
+# Disassemble the tests and run the assembly, with ASSEMBLY=1
+ASSEMBLY = ENV["ASSEMBLY"]
 
 puts "Building crate"
 # Build release to suppress debug messages
@@ -15,7 +24,7 @@ test_paths = test_paths.reject {|e| e == File.join(TEST_DIR, "..") || e == File.
 new_paths = []
 test_paths = test_paths.select {|path|
     if File.directory? path then
-        entries = Dir.glob(File.join path, "*.lox")
+        entries = Dir.glob(File.join path, "*.{lox,loxasm}")
         new_paths << entries
         next false
     end
@@ -83,6 +92,66 @@ def compare_errors file, output
     return expect == new_out
 end
 
+# The tests in test/cfg also compare the graph --emit-cfg writes with the .dot file next to them
+def compare_cfg path
+    dot = File.join(Dir.tmpdir, "loxidation_test.dot")
+    File.delete dot if File.exist? dot
+    # Run from the folder of the test so the graph is labelled with the file name only
+    Open3.capture3 File.expand_path(BINARY), "--emit-cfg", File.basename(path), "-o", dot, chdir: File.dirname(path)
+    return File.exist?(dot) && File.read(dot) == File.read(path.sub(/\.lox$/, ".dot"))
+end
+
+# Options a test needs itself, from a `// flags: --no-tail-calls` comment
+def test_flags path
+    flags = []
+    for line in File.read(path).lines
+        if line.include? "//"
+            comment = line.split("//").last.strip
+            flags.concat comment["flags: ".length..].split if comment.start_with? "flags: "
+        end
+    end
+    return flags
+end
+
+def run_test path
+    # Bytecode that can't be made from source, like a broken file, is run from the .loxc next to the test
+    loxc = path.sub(/\.lox$/, ".loxc")
+    return Open3.capture3 BINARY, loxc if loxc != path && File.exist?(loxc)
+    flags = FLAGS + test_flags(path)
+    if TRANSPILE
+        c = File.join(Dir.tmpdir, "loxidation_test.c")
+        executable = File.join(Dir.tmpdir, "loxidation_test")
+        File.delete c if File.exist? c
+        out, err = Open3.capture3 BINARY, "build", *flags, path, "-o", c
+        # Compile errors are shown by the build
+        return [out, err] if !File.exist? c
+        system("cc", "-O1", "-w", "-o", executable, c) or return ["", "cc failed"]
+        out, err = Open3.capture3 executable
+        return [out, err]
+    end
+    if BYTECODE
+        loxc = File.join(Dir.tmpdir, "loxidation_test.loxc")
+        File.delete loxc if File.exist? loxc
+        out, err = Open3.capture3 BINARY, "--emit-bytecode", *flags, path, "-o", loxc
+        return [out, err] if !File.exist? loxc
+        return Open3.capture3 BINARY, loxc
+    end
+    if ASSEMBLY
+        loxasm = File.join(Dir.tmpdir, "loxidation_test.loxasm")
+        File.delete loxasm if File.exist? loxasm
+        out, err = Open3.capture3 BINARY, "--emit-assembly", *flags, path, "-o", loxasm
+        return [out, err] if !File.exist? loxasm
+        return Open3.capture3 BINARY, loxasm
+    end
+    stdin, stdout, stderr, wait_thr = Open3.popen3 BINARY, *flags, path
+    err = stderr.gets(nil)
+    out = stdout.gets(nil)
+    stdin.close
+    stdout.close
+    stderr.close
+    return [out, err]
+end
+
 for category in tests
     category_name = category[0]
     category_tests = category[1]
@@ -98,14 +167,10 @@ for category in tests
         name = test_[0]
         test_ = test_[1]
         print "Running test #{name}: "
-        stdin, stdout, stderr, wait_thr = Open3.popen3 BINARY,  test_[:path]
-        err = stderr.gets(nil)
-        out = stdout.gets(nil)
-        stdin.close
-        stdout.close
-        stderr.close
+        out, err = run_test test_[:path]
         #exit_status = wait_thr.value
         suc = compare_output(test_[:path], out) && compare_errors(test_[:path], err);
+        suc &&= compare_cfg(test_[:path]) if category_name == "cfg"
         test_[:passed] = suc
         puts '✔' if suc
         puts '⨯' if !suc